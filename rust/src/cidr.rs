@@ -0,0 +1,118 @@
+//! IPv4/IPv6 networks for `remove --cidr`.
+//!
+//! IP literals are not a stored type of their own: they end up in the
+//! `domain` column as text. Validated imports accept dotted-quad IPv4;
+//! IPv6 needs `--no-validate`. Containment
+//! is checked client-side, with the same rules as PostgreSQL's
+//! `inet <<= cidr` (the address families must match).
+
+use anyhow::{bail, Result};
+use std::fmt;
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// `10.0.0.0/8`, `2001:db8::/32`, or a bare address for just that host.
+    /// Host bits below the prefix must be zero, as for PostgreSQL `cidr`.
+    pub fn parse(input: &str) -> Result<Self> {
+        let input = input.trim();
+        let (addr, prefix) = match input.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (input, None),
+        };
+        let Ok(network) = addr.parse::<IpAddr>() else {
+            bail!("Invalid CIDR '{}': '{}' is not an IPv4 or IPv6 address", input, addr);
+        };
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            None => max,
+            Some(p) => match p.parse::<u8>() {
+                Ok(p) if p <= max => p,
+                _ => bail!("Invalid CIDR '{}': the prefix length must be 0-{}", input, max),
+            },
+        };
+        if bits(network) & !mask(network, prefix) != 0 {
+            let base = Self { network: masked(network, prefix), prefix };
+            bail!("Invalid CIDR '{}': it has bits set to the right of the mask; did you mean {}?", input, base);
+        }
+        Ok(Self { network, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        if ip.is_ipv4() != self.network.is_ipv4() {
+            return false;
+        }
+        let mask = mask(ip, self.prefix);
+        bits(ip) & mask == bits(self.network) & mask
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// The address as an integer, IPv4 in the low 32 bits
+fn bits(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(v4) => u32::from(v4) as u128,
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+/// Network mask of `prefix` bits for the family of `ip`
+fn mask(ip: IpAddr, prefix: u8) -> u128 {
+    let width = if ip.is_ipv4() { 32 } else { 128 };
+    if prefix == 0 {
+        return 0;
+    }
+    let ones = u128::MAX << (128 - prefix as u32);
+    ones >> (128 - width)
+}
+
+fn masked(ip: IpAddr, prefix: u8) -> IpAddr {
+    let network = bits(ip) & mask(ip, prefix);
+    match ip {
+        IpAddr::V4(_) => IpAddr::V4((network as u32).into()),
+        IpAddr::V6(_) => IpAddr::V6(network.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let net = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(net.contains(ip("10.255.1.2")));
+        assert!(!net.contains(ip("11.0.0.1")));
+        assert!(!net.contains(ip("::ffff:10.0.0.1")));
+
+        let host = Cidr::parse("192.168.1.7").unwrap();
+        assert!(host.contains(ip("192.168.1.7")));
+        assert!(!host.contains(ip("192.168.1.8")));
+
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("8.8.8.8")));
+        let v6 = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(v6.contains(ip("2001:db8:ffff::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+        assert!(!v6.contains(ip("10.0.0.1")));
+
+        assert!(Cidr::parse("10.0.0.1/8").unwrap_err().to_string().contains("did you mean 10.0.0.0/8?"));
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("2001:db8::/129").is_err());
+        assert!(Cidr::parse("example.com/8").is_err());
+        assert!(Cidr::parse("10.0.0.0/x").is_err());
+    }
+}
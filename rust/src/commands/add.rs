@@ -1,90 +1,1001 @@
-use anyhow::Result;
-use deadpool_postgres::Pool;
-use std::io::{self, BufRead, BufReader};
-use std::path::PathBuf;
-use std::fs::File;
-use std::time::Instant;
-use tokio_postgres::types::ToSql;
-
-use crate::domain::is_valid_domain;
-
-const BATCH_SIZE: usize = 10_000;
-const COPY_CHUNK_SIZE: usize = 5_000_000;
+use anyhow::{bail, Context, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_postgres::types::Type;
+use tracing::{debug, debug_span, info, warn};
+
+use crate::db::{self, Pool};
+use crate::domain::{
+    dedup_case_insensitive, fold_www, has_control_chars, is_valid_domain, normalize_host,
+    strip_comment, strip_www, to_idna_ascii, www_counterparts, TldFilter,
+};
+use crate::human;
+use crate::input;
+use crate::progress;
+
+/// Default domains per INSERT batch (`--batch-size`, `tuning.batch_size`)
+pub const BATCH_SIZE: usize = 10_000;
+/// Defaults for the COPY chunk tuner flags (and their `tuning` settings)
+pub const TARGET_FLUSH_MS: u64 = 1500;
+pub const MIN_COPY_CHUNK: usize = 10_000;
+pub const MAX_COPY_CHUNK: usize = 5_000_000;
+/// First COPY flush size, before any throughput has been measured
+const COPY_START_CHUNK: usize = 100_000;
+/// Largest factor one COPY flush may grow or shrink the next chunk by
+const COPY_MAX_STEP: f64 = 4.0;
 // Threshold: use COPY+rebuild for large imports, INSERT for small ones
 const BULK_THRESHOLD: usize = 100_000;
 
+/// Progress of a `--resume` import, persisted after every commit
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    /// Byte offset just past the last committed line
+    offset: u64,
+    /// Input lines consumed up to `offset`
+    lines: u64,
+    /// Input file size when the checkpoint was written
+    file_size: u64,
+}
+
+/// Passes stdin through while appending every byte read to the `--spool`
+/// file, so the raw input survives a crash before it reaches the database
+struct Tee<R> {
+    inner: R,
+    spool: File,
+}
+
+impl<R: Read> Read for Tee<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.spool.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+/// What one import did, recorded by `--compare-previous` and reported by
+/// `--webhook`
+pub struct ImportSummary {
+    pub processed: u64,
+    pub new: u64,
+    pub duplicates: u64,
+    /// Entries rejected as invalid domains
+    pub invalid: u64,
+    /// Time from reading the input to the last commit
+    pub elapsed: Duration,
+    /// Row id in the `imports` table, when `--compare-previous` recorded one
+    pub import_id: Option<i64>,
+}
+
+/// A drop in new domains versus the previous run at least this large (in
+/// percent) is flagged as suspicious
+const SUSPICIOUS_DROP_PCT: f64 = 50.0;
+
+/// `+1200 new vs +3400 last run (-64.7%)`, and whether the drop is large
+/// enough to flag
+fn describe_delta(new: u64, previous: u64) -> (String, bool) {
+    let mut line = format!("+{} new vs +{} last run", human::count(new), human::count(previous));
+    if previous == 0 {
+        return (line, false);
+    }
+    let change = (new as f64 - previous as f64) / previous as f64 * 100.0;
+    line.push_str(&format!(" ({:+.1}%)", change));
+    (line, -change >= SUSPICIOUS_DROP_PCT)
+}
+
+/// Store this import's counts in the `imports` table and report the change
+/// in new domains against the previously recorded run. Returns the new row's id.
+async fn record_and_compare(pool: &Pool, summary: &ImportSummary, silent: bool) -> Result<i64> {
+    let client = pool.get().await?;
+    client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS imports (\
+                id BIGSERIAL PRIMARY KEY, \
+                finished_at TIMESTAMPTZ NOT NULL DEFAULT now(), \
+                processed BIGINT NOT NULL, \
+                new BIGINT NOT NULL, \
+                duplicates BIGINT NOT NULL)",
+        )
+        .await?;
+    db::pin(&client).await?;
+    let previous = db::query(
+        &client,
+        "SELECT new, to_char(finished_at, 'YYYY-MM-DD HH24:MI') FROM imports ORDER BY id DESC LIMIT 1",
+        &[],
+    )
+    .await?;
+    let (processed, new, duplicates) = (summary.processed as i64, summary.new as i64, summary.duplicates as i64);
+    let inserted = db::query(
+        &client,
+        "INSERT INTO imports (processed, new, duplicates) VALUES ($1, $2, $3) RETURNING id",
+        &[(&processed, Type::INT8), (&new, Type::INT8), (&duplicates, Type::INT8)],
+    )
+    .await?;
+    db::unpin(&client).await?;
+    let id: i64 = inserted[0].get(0);
+
+    if silent {
+        return Ok(id);
+    }
+    let Some(row) = previous.first() else {
+        info!("No previous import recorded; this run is the baseline for --compare-previous");
+        return Ok(id);
+    };
+    let previous_new: i64 = row.get(0);
+    let finished_at: String = row.get(1);
+    let (line, suspicious) = describe_delta(summary.new, previous_new.max(0) as u64);
+    info!("{} ({})", line, finished_at);
+    if suspicious {
+        warn!("new domains dropped sharply since the last run; check the upstream tools");
+    }
+    Ok(id)
+}
+
+/// Picks the lines `--validate-sample` validates
+struct Sampler {
+    rng: StdRng,
+    rate: f64,
+}
+
+impl Sampler {
+    fn new(rate: f64) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0x9E37_79B9_7F4A_7C15);
+        Self { rng: StdRng::seed_from_u64(seed), rate: rate.clamp(0.0, 1.0) }
+    }
+
+    fn pick(&mut self) -> bool {
+        self.rng.random_bool(self.rate)
+    }
+}
+
+/// Metadata stamped on every imported row by `--program`/`--source`/`--tag`
+#[derive(Default)]
+pub struct Stamp {
+    pub program: Option<String>,
+    pub source: Option<String>,
+    pub tags: Vec<String>,
+}
+
+impl Stamp {
+    pub fn is_empty(&self) -> bool {
+        self.program.is_none() && self.source.is_none() && self.tags.is_empty()
+    }
+}
+
+/// Sizes bulk COPY chunks from measured throughput so each flush takes
+/// about `target`, within `min..=max` rows
+pub struct ChunkTuner {
+    target: Duration,
+    min: usize,
+    max: usize,
+    size: usize,
+}
+
+impl ChunkTuner {
+    pub fn new(target_flush_ms: u64, min: usize, max: usize) -> Result<Self> {
+        if target_flush_ms == 0 {
+            bail!("--target-flush-ms must be positive");
+        }
+        if min == 0 || min > max {
+            bail!("--min-copy-chunk must be positive and at most --max-copy-chunk");
+        }
+        Ok(Self {
+            target: Duration::from_millis(target_flush_ms),
+            min,
+            max,
+            size: COPY_START_CHUNK.clamp(min, max),
+        })
+    }
+
+    /// Rows to send in the next flush
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Move toward the size that would have taken `target` at the rate just
+    /// measured, by at most `COPY_MAX_STEP` per flush to damp noisy samples
+    fn record(&mut self, rows: usize, elapsed: Duration) {
+        let ratio = if elapsed.is_zero() {
+            COPY_MAX_STEP
+        } else {
+            (self.target.as_secs_f64() / elapsed.as_secs_f64()).clamp(1.0 / COPY_MAX_STEP, COPY_MAX_STEP)
+        };
+        self.size = ((rows as f64 * ratio) as usize).clamp(self.min, self.max);
+    }
+}
+
+/// How `add` resolves duplicates (`--dedup-strategy`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DedupStrategy {
+    /// `sql-rebuild` for 100K domains or more, else `on-conflict`
+    #[default]
+    Auto,
+    /// Drop repeats client-side, then insert like `on-conflict`
+    Memory,
+    /// COPY everything, then deduplicate while rebuilding the primary key
+    SqlRebuild,
+    /// `INSERT ... ON CONFLICT DO NOTHING` in batches
+    OnConflict,
+}
+
+/// Which of `www.X` and `X` `--fold-www` keeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum WwwForm {
+    Bare,
+    Www,
+}
+
+impl WwwForm {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Bare => "bare",
+            Self::Www => "www",
+        }
+    }
+}
+
+/// How `add` validates, filters and writes domains; everything except
+/// where the input comes from
+pub struct AddOptions {
+    /// Reject entries that are not valid domain names
+    pub validate: bool,
+    /// Skip `#`/`//` comment lines and strip inline annotations
+    pub allow_comments: bool,
+    /// Keep the first-seen casing and enforce case-insensitive uniqueness
+    pub preserve_case: bool,
+    /// Reduce URLs and host:port entries to the lowercased host
+    pub normalize: bool,
+    /// Convert internationalized domains to punycode
+    pub idn: bool,
+    /// Commit after every N batches instead of all at once
+    pub batch_commit_every: Option<usize>,
+    pub dedup_strategy: DedupStrategy,
+    /// Validate only this share of the entries
+    pub validate_sample: Option<f64>,
+    /// Abort when the sampled invalid rate exceeds this percentage
+    pub abort_if_invalid_over: Option<f64>,
+    /// Stop reading after this many valid domains
+    pub max_domains: Option<u64>,
+    pub tld_filter: TldFilter,
+    /// List newly stored domains on stdout
+    pub print_new: bool,
+    /// List domains that were already stored on stdout
+    pub print_duplicates: bool,
+    pub stamp: Stamp,
+    /// Record the import and compare it with the previous one
+    pub compare_previous: bool,
+    /// Domains per INSERT batch
+    pub batch_size: usize,
+    pub tuner: ChunkTuner,
+    /// Store `www.example.com` as `example.com`
+    pub strip_www_prefix: bool,
+    /// Fold www/bare pairs into this form
+    pub fold_www_into: Option<WwwForm>,
+    /// Only report errors
+    pub silent: bool,
+}
+
+impl Default for AddOptions {
+    fn default() -> Self {
+        Self {
+            validate: true,
+            allow_comments: false,
+            preserve_case: false,
+            normalize: false,
+            idn: false,
+            batch_commit_every: None,
+            dedup_strategy: DedupStrategy::Auto,
+            validate_sample: None,
+            abort_if_invalid_over: None,
+            max_domains: None,
+            tld_filter: TldFilter::default(),
+            print_new: false,
+            print_duplicates: false,
+            stamp: Stamp::default(),
+            compare_previous: false,
+            batch_size: BATCH_SIZE,
+            tuner: ChunkTuner::new(TARGET_FLUSH_MS, MIN_COPY_CHUNK, MAX_COPY_CHUNK).expect("default tuning is valid"),
+            strip_www_prefix: false,
+            fold_www_into: None,
+            silent: false,
+        }
+    }
+}
+
+impl AddOptions {
+    fn check(&self) -> Result<()> {
+        if self.batch_size == 0 {
+            bail!("--batch-size must be positive");
+        }
+        if self.batch_commit_every == Some(0) {
+            bail!("--batch-commit-every must be positive");
+        }
+        if let Some(rate) = self.validate_sample {
+            if !(rate > 0.0 && rate <= 1.0) {
+                bail!("--validate-sample must be in (0, 1], got {}", rate);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `add`: read `file` (or stdin, spooled to `spool`) and import it
 pub async fn run(
     pool: &Pool,
     file: Option<PathBuf>,
-    validate: bool,
-    silent: bool,
-) -> Result<()> {
+    input_format: input::Format,
+    resume: bool,
+    spool: Option<PathBuf>,
+    opts: AddOptions,
+) -> Result<ImportSummary> {
     let start = Instant::now();
+    progress::install_signal_handler();
+    let silent = opts.silent;
 
-    // First, read and validate all domains into memory
-    let reader: Box<dyn BufRead> = match &file {
-        Some(path) => Box::new(BufReader::with_capacity(1024 * 1024, File::open(path)?)),
-        None => Box::new(BufReader::with_capacity(1024 * 1024, io::stdin().lock())),
+    if resume {
+        opts.check()?;
+        if !input_format.is_text() {
+            bail!("--resume only works with text input, not --input-format json/jsonl");
+        }
+        let (path, commit_every) = match (file, opts.batch_commit_every) {
+            (Some(p), Some(n)) => (p, n),
+            (None, _) => bail!("--resume only works with a seekable file input (-f), not stdin"),
+            (_, None) => bail!("--resume requires --batch-commit-every"),
+        };
+        if opts.preserve_case {
+            ensure_case_insensitive_index(pool).await?;
+        }
+        let case_insensitive = opts.preserve_case || has_case_insensitive_index(pool).await?;
+        prepare_stamp(pool, &opts.stamp).await?;
+        let import_lock = db::lock_import(pool).await?;
+        let mut summary = run_resumable(
+            pool, &path, opts.validate, opts.allow_comments, opts.normalize, opts.idn, commit_every, opts.batch_size,
+            opts.max_domains, &opts.tld_filter, &opts.stamp, case_insensitive, silent,
+        ).await?;
+        db::unlock_import(import_lock).await?;
+        summary.elapsed = start.elapsed();
+        if opts.compare_previous {
+            summary.import_id = Some(record_and_compare(pool, &summary, silent).await?);
+        }
+        if !silent {
+            let elapsed = start.elapsed().as_secs_f64();
+            info!(elapsed_s = elapsed, "Completed in {:.1}s", elapsed);
+        }
+        return Ok(summary);
+    }
+
+    // First, read and validate all domains into memory; compressed input
+    // is spooled as it arrives, still compressed
+    let reader: Box<dyn BufRead> = match (&file, &spool) {
+        (Some(path), _) => input::open(Some(path), 1024 * 1024)?,
+        (None, Some(path)) => {
+            // Never overwrite the spool of an earlier import that died
+            let spool = File::options()
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .with_context(|| {
+                    format!("Cannot create spool file {:?}; if it is left over from a crashed import, add it with -f", path)
+                })?;
+            input::read(Tee { inner: io::stdin(), spool }, 1024 * 1024)?
+        }
+        (None, None) => input::open(None, 1024 * 1024)?,
     };
+    let reader = input::domains(reader, &input_format)?;
+    let summary = import(pool, reader, opts).await?;
+
+    // Everything is committed, so the spooled input is no longer needed
+    if let Some(path) = &spool {
+        fs::remove_file(path)?;
+        if progress::steps(silent) {
+            info!("Removed spool file {:?}", path);
+        }
+    }
+
+    if !silent {
+        let elapsed = start.elapsed().as_secs_f64();
+        info!(elapsed_s = elapsed, "Completed in {:.1}s", elapsed);
+    }
+
+    Ok(summary)
+}
+
+/// Validate, filter and store the domains read from `reader`, one per line
+pub async fn import(pool: &Pool, reader: Box<dyn BufRead>, opts: AddOptions) -> Result<ImportSummary> {
+    let start = Instant::now();
+    opts.check()?;
+    let AddOptions {
+        validate, allow_comments, preserve_case, normalize, idn, batch_commit_every, dedup_strategy, validate_sample,
+        abort_if_invalid_over, max_domains, tld_filter, print_new, print_duplicates, stamp, compare_previous,
+        batch_size, tuner, strip_www_prefix, fold_www_into, silent,
+    } = opts;
 
     let mut domains: Vec<String> = Vec::new();
     let mut total = 0u64;
     let mut invalid = 0u64;
+    let mut comments = 0u64;
 
+    // --validate-sample: only a random share of lines is validated
+    let mut sampler = validate_sample.map(Sampler::new);
+    let mut sampled = 0u64;
+    let mut sampled_invalid = 0u64;
+    let mut cap_hit = false;
+    let mut tld_rejected = 0u64;
+    let mut control_rejected = 0u64;
+    let mut normalized = 0u64;
+    let mut converted = 0u64;
+    let mut stripped = 0u64;
+
+    progress::set_phase("reading");
+    let ticker = progress::ticker(silent);
     for line in reader.lines() {
         let line = line?;
-        let domain = line.trim();
+        progress::add_lines(1);
+        let domain = if allow_comments {
+            match strip_comment(&line) {
+                Some(d) => d,
+                None => {
+                    comments += 1;
+                    continue;
+                }
+            }
+        } else {
+            line.trim()
+        };
         if domain.is_empty() {
             continue;
         }
 
         total += 1;
 
-        if validate && !is_valid_domain(domain) {
+        // Always checked: --no-validate relaxes the domain shape, not this
+        if has_control_chars(domain) {
+            control_rejected += 1;
+            continue;
+        }
+
+        let host = if normalize { normalize_host(domain) } else { Cow::Borrowed(domain) };
+        if host != domain {
+            normalized += 1;
+        }
+        let domain = host.as_ref();
+        if domain.is_empty() {
+            invalid += 1;
+            continue;
+        }
+        let ascii = match to_punycode(domain, idn) {
+            Some(ascii) => ascii,
+            None => {
+                invalid += 1;
+                continue;
+            }
+        };
+        if ascii != domain {
+            converted += 1;
+        }
+        let domain = ascii.as_ref();
+
+        if let Some(s) = sampler.as_mut() {
+            if s.pick() {
+                sampled += 1;
+                if !is_valid_domain(domain) {
+                    sampled_invalid += 1;
+                    invalid += 1;
+                    continue;
+                }
+            }
+        } else if validate && !is_valid_domain(domain) {
             invalid += 1;
             continue;
         }
 
+        if !tld_filter.permits(domain) {
+            tld_rejected += 1;
+            continue;
+        }
+
+        // --max-domains: stop reading once the cap of valid domains is met
+        if max_domains.is_some_and(|max| domains.len() as u64 >= max) {
+            total -= 1;
+            cap_hit = true;
+            break;
+        }
+
+        let domain = match strip_www_prefix.then(|| strip_www(domain)).flatten() {
+            Some(bare) => {
+                stripped += 1;
+                bare
+            }
+            _ => domain,
+        };
         domains.push(domain.to_string());
     }
+    drop(ticker);
+
+    if cap_hit {
+        report_cap_hit(max_domains, silent);
+    }
+    if !silent && !tld_filter.is_empty() {
+        report_tld_rejected(tld_rejected);
+    }
+    if !silent && control_rejected > 0 {
+        report_control_rejected(control_rejected);
+    }
+    if !silent && normalize {
+        report_normalized(normalized);
+    }
+    if !silent && idn {
+        report_converted(converted);
+    }
+    if !silent && strip_www_prefix {
+        info!("Stripped www. from {} domains", human::count(stripped));
+    }
 
-    // Choose strategy based on batch size
-    if domains.len() >= BULK_THRESHOLD {
+    if validate_sample.is_some() {
+        let rate = if sampled > 0 {
+            (sampled_invalid as f64 / sampled as f64) * 100.0
+        } else {
+            0.0
+        };
         if !silent {
-            eprintln!("Adding {} domains (bulk COPY mode)...", domains.len());
+            info!(
+                "Validated a sample of {} of {} domains: {} invalid ({:.2}%)",
+                human::count(sampled),
+                human::count(total),
+                human::count(sampled_invalid),
+                rate
+            );
         }
-        run_bulk_copy(pool, domains, total, invalid, silent).await?;
+        if let Some(max) = abort_if_invalid_over {
+            if rate > max {
+                bail!(
+                    "Sampled invalid rate {:.2}% exceeds --abort-if-invalid-over {}%; nothing was written",
+                    rate, max
+                );
+            }
+        }
+    }
+
+    // Case-insensitive uniqueness: the first-seen casing wins, both within
+    // the input and against rows already stored. Once the functional index
+    // exists every later add honours it, with or without the flag.
+    if preserve_case {
+        ensure_case_insensitive_index(pool).await?;
+    }
+    let case_insensitive = preserve_case || has_case_insensitive_index(pool).await?;
+    if case_insensitive {
+        domains = dedup_case_insensitive(domains);
+    }
+    if let Some(form) = fold_www_into {
+        let prefer_www = form == WwwForm::Www;
+        let candidates = www_counterparts(&domains, prefer_www);
+        let stored = stored_among(pool, &candidates, batch_size, case_insensitive).await?;
+        let (kept, folded) = fold_www(domains, prefer_www, &stored);
+        domains = kept;
+        if !silent {
+            info!("Folded {} www/bare pairs into the {} form", human::count(folded), form.as_str());
+        }
+    }
+    prepare_stamp(pool, &stamp).await?;
+
+    // Incremental commits are only possible on the INSERT path, since COPY
+    // mode drops the indexes, and the COPY dedup step cannot tell which
+    // casing was seen first.
+    // Listing new/duplicate domains relies on INSERT ... RETURNING, and the
+    // index rebuild's DDL and SET LOCALs can't span a transaction pooler
+    let insert_only = batch_commit_every.is_some()
+        || case_insensitive
+        || print_new
+        || print_duplicates
+        || !stamp.is_empty()
+        || pool.transaction_pooling();
+    let strategy = match dedup_strategy {
+        DedupStrategy::Auto if domains.len() >= BULK_THRESHOLD && !insert_only => DedupStrategy::SqlRebuild,
+        DedupStrategy::Auto => DedupStrategy::OnConflict,
+        DedupStrategy::SqlRebuild if insert_only => bail!(
+            "--dedup-strategy sql-rebuild cannot be combined with --batch-commit-every, \
+             --print-new/--print-duplicates, --program/--source/--tag, --pgbouncer \
+             or a case-insensitive index"
+        ),
+        other => other,
+    };
+
+    if strategy == DedupStrategy::Memory || print_new || print_duplicates {
+        // Drop exact repeats client-side so the database only sees each
+        // domain once; the INSERT still resolves conflicts with stored rows.
+        // Listing needs this too, or a repeat would be reported twice
+        let mut seen: HashSet<String> = HashSet::with_capacity(domains.len());
+        domains.retain(|d| seen.insert(d.clone()));
+    }
+
+    let import_lock = db::lock_import(pool).await?;
+    let mut summary = if strategy == DedupStrategy::SqlRebuild {
+        if progress::steps(silent) {
+            info!("Adding {} domains (bulk COPY mode)...", human::count(domains.len() as u64));
+        }
+        run_bulk_copy(pool, domains, total - tld_rejected - control_rejected, invalid, tuner, silent).await?
     } else {
-        if !silent && domains.len() > 0 {
-            eprintln!("Adding {} domains...", domains.len());
+        if progress::steps(silent) && !domains.is_empty() {
+            info!("Adding {} domains...", human::count(domains.len() as u64));
         }
-        run_insert(pool, domains, total, invalid, silent).await?;
+        run_insert(
+            pool, domains, total - tld_rejected - control_rejected, invalid, batch_commit_every, batch_size,
+            print_new, print_duplicates, &stamp, case_insensitive, silent,
+        ).await?
+    };
+    db::unlock_import(import_lock).await?;
+    summary.elapsed = start.elapsed();
+    if compare_previous {
+        summary.import_id = Some(record_and_compare(pool, &summary, silent).await?);
+    }
+
+    if !silent && comments > 0 {
+        info!("Skipped {} comment lines", human::count(comments));
+    }
+
+    Ok(summary)
+}
+
+/// Enforce uniqueness on `lower(domain)` so differently-cased inserts conflict
+async fn ensure_case_insensitive_index(pool: &Pool) -> Result<()> {
+    let client = pool.get().await?;
+    client
+        .batch_execute("CREATE UNIQUE INDEX IF NOT EXISTS idx_domains_lower ON domains (lower(domain))")
+        .await
+        .context("Failed to create case-insensitive unique index (table already contains domains differing only by case)")?;
+    Ok(())
+}
+
+/// Add the metadata columns (schema version 3) on first use of
+/// `--program`/`--source`/`--tag`. Checked first, since `ADD COLUMN IF NOT
+/// EXISTS` still takes an exclusive lock that would block concurrent readers.
+async fn prepare_stamp(pool: &Pool, stamp: &Stamp) -> Result<()> {
+    if stamp.is_empty() {
+        return Ok(());
     }
 
+    let client = pool.get().await?;
+    db::pin(&client).await?;
+    let row = client
+        .query_one(
+            "SELECT COUNT(*) FROM information_schema.columns \
+             WHERE table_schema = current_schema() AND table_name = 'domains' \
+             AND column_name IN ('program', 'source', 'tags')",
+            &[],
+        )
+        .await?;
+    db::unpin(&client).await?;
+    let present: i64 = row.get(0);
+    if present < 3 {
+        client
+            .batch_execute(
+                "ALTER TABLE domains ADD COLUMN IF NOT EXISTS program TEXT, \
+                 ADD COLUMN IF NOT EXISTS source TEXT, ADD COLUMN IF NOT EXISTS tags TEXT[]",
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+async fn has_case_insensitive_index(pool: &Pool) -> Result<bool> {
+    let client = pool.get().await?;
+    db::pin(&client).await?;
+    let row = client
+        .query_one("SELECT to_regclass('idx_domains_lower') IS NOT NULL", &[])
+        .await?;
+    db::unpin(&client).await?;
+    Ok(row.get(0))
+}
+
+fn report_cap_hit(max_domains: Option<u64>, silent: bool) {
+    progress::set_phase("cap reached");
     if !silent {
-        eprintln!("Completed in {:.1}s", start.elapsed().as_secs_f64());
+        info!(
+            "Reached --max-domains cap of {}; remaining input was not imported",
+            human::count(max_domains.unwrap_or(0))
+        );
     }
+}
+
+fn report_tld_rejected(rejected: u64) {
+    info!("Rejected {} domains outside the allowed TLDs", human::count(rejected));
+}
 
+fn report_control_rejected(rejected: u64) {
+    info!("Rejected {} lines containing control characters", human::count(rejected));
+}
+
+fn report_normalized(normalized: u64) {
+    info!("Normalized {} entries to their lowercased host", human::count(normalized));
+}
+
+fn report_converted(converted: u64) {
+    info!("Converted {} internationalized domains to punycode", human::count(converted));
+}
+
+/// With `--idn`, the punycode form of an internationalized domain; `None`
+/// when it cannot be converted. Other entries are passed through.
+fn to_punycode(domain: &str, idn: bool) -> Option<Cow<'_, str>> {
+    if idn && !domain.is_ascii() {
+        to_idna_ascii(domain).map(Cow::Owned)
+    } else {
+        Some(Cow::Borrowed(domain))
+    }
+}
+
+fn checkpoint_path(input: &Path) -> PathBuf {
+    let mut name = input.as_os_str().to_owned();
+    name.push(".checkpoint");
+    PathBuf::from(name)
+}
+
+fn save_checkpoint(path: &Path, checkpoint: &Checkpoint) -> Result<()> {
+    // Write-then-rename so a crash never leaves a truncated state file
+    let tmp = path.with_extension("checkpoint.tmp");
+    fs::write(&tmp, serde_json::to_vec(checkpoint)?)?;
+    fs::rename(&tmp, path)?;
     Ok(())
 }
 
+/// Streaming INSERT path for `--resume`: commits every `commit_every`
+/// batches and records the input offset reached, so a rerun of the same
+/// command seeks past everything already committed.
+#[allow(clippy::too_many_arguments)]
+async fn run_resumable(
+    pool: &Pool,
+    path: &Path,
+    validate: bool,
+    allow_comments: bool,
+    normalize: bool,
+    idn: bool,
+    commit_every: usize,
+    batch_size: usize,
+    max_domains: Option<u64>,
+    tld_filter: &TldFilter,
+    stamp: &Stamp,
+    case_insensitive: bool,
+    silent: bool,
+) -> Result<ImportSummary> {
+    let state_path = checkpoint_path(path);
+    if let Some(compression) = input::compression(path)? {
+        bail!("--resume needs an uncompressed file; {:?} is {}-compressed, so decompress it first", path, compression);
+    }
+    let mut file = File::open(path)?;
+    let file_size = file.metadata()?.len();
+
+    let mut checkpoint = match fs::read(&state_path) {
+        Ok(data) => {
+            let cp: Checkpoint = serde_json::from_slice(&data)
+                .with_context(|| format!("Failed to parse checkpoint file: {:?}", state_path))?;
+            if cp.file_size != file_size || cp.offset > file_size {
+                bail!(
+                    "Checkpoint {:?} does not match {:?} (file changed); delete it to start over",
+                    state_path, path
+                );
+            }
+            if progress::steps(silent) {
+                info!("Resuming from line {} (byte {})", cp.lines, cp.offset);
+            }
+            cp
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Checkpoint { offset: 0, lines: 0, file_size },
+        Err(e) => return Err(e.into()),
+    };
+
+    file.seek(SeekFrom::Start(checkpoint.offset))?;
+    let mut reader = BufReader::with_capacity(1024 * 1024, file);
+    let client = pool.get().await?;
+
+    let mut offset = checkpoint.offset;
+    let mut lines = checkpoint.lines;
+    let mut total = 0u64;
+    let mut invalid = 0u64;
+    let mut comments = 0u64;
+    let mut new_count = 0u64;
+    let mut batches = 0usize;
+    let mut accepted = 0u64;
+    let mut cap_hit = false;
+    let mut tld_rejected = 0u64;
+    let mut control_rejected = 0u64;
+    let mut normalized = 0u64;
+    let mut converted = 0u64;
+    let mut batch: Vec<String> = Vec::with_capacity(batch_size);
+    let mut line = String::new();
+
+    progress::set_phase("inserting");
+    let ticker = progress::ticker(silent);
+    client.batch_execute("BEGIN").await?;
+    loop {
+        line.clear();
+        let n = reader.read_line(&mut line)?;
+        let mut done = n == 0;
+        if !done {
+            offset += n as u64;
+            lines += 1;
+            progress::add_lines(1);
+
+            let domain = if allow_comments {
+                strip_comment(&line)
+            } else {
+                Some(line.trim())
+            };
+            match domain {
+                None => comments += 1,
+                Some("") => {}
+                Some(raw) => {
+                    total += 1;
+                    // Control characters are checked on the raw line, never normalized away
+                    let control = has_control_chars(raw);
+                    let host = if normalize && !control { normalize_host(raw) } else { Cow::Borrowed(raw) };
+                    if host != raw {
+                        normalized += 1;
+                    }
+                    let ascii = if control { None } else { to_punycode(&host, idn) };
+                    if ascii.as_ref().is_some_and(|a| a != &host) {
+                        converted += 1;
+                    }
+                    let d = ascii.as_deref().unwrap_or("");
+                    if control {
+                        control_rejected += 1;
+                    } else if d.is_empty() || (validate && !is_valid_domain(d)) {
+                        invalid += 1;
+                    } else if !tld_filter.permits(d) {
+                        tld_rejected += 1;
+                    } else if max_domains.is_some_and(|max| accepted >= max) {
+                        // Leave this line unconsumed so a rerun picks it up
+                        offset -= n as u64;
+                        lines -= 1;
+                        total -= 1;
+                        cap_hit = true;
+                        done = true;
+                    } else {
+                        accepted += 1;
+                        batch.push(d.to_string());
+                    }
+                }
+            }
+        }
+
+        if batch.len() >= batch_size || (done && !batch.is_empty()) {
+            new_count += insert_batch(&client, &batch, stamp, case_insensitive).await?;
+            batch.clear();
+            batches += 1;
+
+            if batches.is_multiple_of(commit_every) {
+                client.batch_execute("COMMIT").await?;
+                checkpoint.offset = offset;
+                checkpoint.lines = lines;
+                save_checkpoint(&state_path, &checkpoint)?;
+                client.batch_execute("BEGIN").await?;
+            }
+        }
+
+        if done {
+            break;
+        }
+    }
+    client.batch_execute("COMMIT").await?;
+    drop(ticker);
+
+    if cap_hit {
+        // Keep the checkpoint so a rerun continues after the cap
+        checkpoint.offset = offset;
+        checkpoint.lines = lines;
+        save_checkpoint(&state_path, &checkpoint)?;
+        report_cap_hit(max_domains, silent);
+    } else if let Err(e) = fs::remove_file(&state_path) {
+        // Finished cleanly: nothing left to resume
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e.into());
+        }
+    }
+
+    let valid_count = total - invalid - tld_rejected - control_rejected;
+    if !silent {
+        info!(
+            processed = total,
+            new = new_count,
+            duplicates = valid_count - new_count,
+            "Processed {} domains: {} new, {} duplicates",
+            human::count(total),
+            human::count(new_count),
+            human::count(valid_count - new_count)
+        );
+        if invalid > 0 {
+            info!("Skipped {} invalid domains", human::count(invalid));
+        }
+        if comments > 0 {
+            info!("Skipped {} comment lines", human::count(comments));
+        }
+        if !tld_filter.is_empty() {
+            report_tld_rejected(tld_rejected);
+        }
+        if control_rejected > 0 {
+            report_control_rejected(control_rejected);
+        }
+        if normalize {
+            report_normalized(normalized);
+        }
+        if idn {
+            report_converted(converted);
+        }
+    }
+
+    Ok(ImportSummary {
+        processed: total,
+        new: new_count,
+        duplicates: valid_count - new_count,
+        invalid,
+        elapsed: Duration::ZERO,
+        import_id: None,
+    })
+}
+
 /// Fast INSERT with ON CONFLICT for small batches (< 100K domains)
+///
+/// All batches run in a single transaction unless `commit_every` is set,
+/// in which case a commit is issued after every N batches.
+///
+/// With `print_new`/`print_duplicates`, the matching input domains are
+/// written to stdout once the batches containing them are committed.
+#[allow(clippy::too_many_arguments)]
 async fn run_insert(
     pool: &Pool,
     domains: Vec<String>,
     total: u64,
     invalid: u64,
+    commit_every: Option<usize>,
+    batch_size: usize,
+    print_new: bool,
+    print_duplicates: bool,
+    stamp: &Stamp,
+    case_insensitive: bool,
     silent: bool,
-) -> Result<()> {
+) -> Result<ImportSummary> {
     let client = pool.get().await?;
     let start = Instant::now();
 
     let mut new_count = 0u64;
+    let mut batches = 0usize;
+    let mut pending: Vec<&str> = Vec::new();
+    let listing = print_new || print_duplicates;
 
-    // Process in batches
-    for chunk in domains.chunks(BATCH_SIZE) {
-        new_count += insert_batch(&client, chunk).await?;
+    // Process in batches; listed domains go to stdout, so no ticker then
+    progress::set_phase("inserting");
+    let ticker = if listing { None } else { progress::ticker(silent) };
+    client.batch_execute("BEGIN").await?;
+    for chunk in domains.chunks(batch_size) {
+        if listing {
+            let inserted: HashSet<String> = insert_batch_returning(&client, chunk, stamp, case_insensitive).await?;
+            new_count += inserted.len() as u64;
+            pending.extend(
+                chunk
+                    .iter()
+                    .filter(|d| inserted.contains(*d) == print_new)
+                    .map(String::as_str),
+            );
+        } else {
+            new_count += insert_batch(&client, chunk, stamp, case_insensitive).await?;
+        }
+        batches += 1;
+
+        if commit_every.is_some_and(|n| batches.is_multiple_of(n)) {
+            client.batch_execute("COMMIT; BEGIN").await?;
+            flush_listing(&mut pending)?;
+        }
     }
+    client.batch_execute("COMMIT").await?;
+    drop(ticker);
+    flush_listing(&mut pending)?;
 
     let valid_count = total - invalid;
     let duplicate_count = valid_count - new_count;
@@ -95,35 +1006,57 @@ async fn run_insert(
         } else {
             0.0
         };
-        eprintln!(
+        info!(
+            processed = total,
+            new = new_count,
+            duplicates = duplicate_count,
             "Processed {} domains: {} new, {} duplicates ({:.2}%) in {:.1}s",
-            total, new_count, duplicate_count, pct, start.elapsed().as_secs_f64()
+            human::count(total),
+            human::count(new_count),
+            human::count(duplicate_count),
+            pct,
+            start.elapsed().as_secs_f64()
         );
         if invalid > 0 {
-            eprintln!("Skipped {} invalid domains", invalid);
+            info!("Skipped {} invalid domains", human::count(invalid));
         }
     }
 
-    Ok(())
+    Ok(ImportSummary {
+        processed: total,
+        new: new_count,
+        duplicates: duplicate_count,
+        invalid,
+        elapsed: Duration::ZERO,
+        import_id: None,
+    })
 }
 
 /// Bulk COPY with index rebuild for large imports (>= 100K domains)
+///
+/// Everything from the index drop to the rebuild is one transaction: DDL is
+/// transactional in PostgreSQL, so an error or a killed process rolls back
+/// to the table as it was, primary key included. Readers wait on the
+/// table lock until it commits.
 async fn run_bulk_copy(
     pool: &Pool,
     domains: Vec<String>,
     total: u64,
     invalid: u64,
+    mut tuner: ChunkTuner,
     silent: bool,
-) -> Result<()> {
+) -> Result<ImportSummary> {
     let client = pool.get().await?;
     let start = Instant::now();
-    
+
+    client.batch_execute("BEGIN").await?;
+
     // Get initial count
     let row = client.query_one("SELECT COUNT(*) FROM domains", &[]).await?;
     let before_count: i64 = row.get(0);
 
-    if !silent {
-        eprintln!("Processing domains with COPY (streaming)...");
+    if progress::steps(silent) {
+        info!("Processing domains with COPY (streaming)...");
     }
 
     // Drop indexes for fast insert
@@ -135,30 +1068,64 @@ async fn run_bulk_copy(
     client.execute("SET LOCAL work_mem = '256MB'", &[]).await?;
     client.execute("SET LOCAL maintenance_work_mem = '512MB'", &[]).await?;
 
-    // Insert in chunks
-    for chunk in domains.chunks(COPY_CHUNK_SIZE) {
+    // Insert in chunks sized by the measured flush time
+    progress::set_phase("copying");
+    let phase = debug_span!("copying");
+    let ticker = progress::ticker(silent);
+    let mut rest = &domains[..];
+    while !rest.is_empty() {
+        let (chunk, tail) = rest.split_at(tuner.size().min(rest.len()));
+        let flush = Instant::now();
         copy_domains(&client, chunk).await?;
+        tuner.record(chunk.len(), flush.elapsed());
+        rest = tail;
+    }
+    drop(ticker);
+    drop(phase);
+    if !silent {
+        debug!(
+            chunk_rows = tuner.size(),
+            "COPY chunk size converged to {} rows (target {}ms per flush)",
+            human::count(tuner.size() as u64),
+            tuner.target.as_millis()
+        );
     }
 
     // Deduplicate
-    if !silent {
-        eprintln!("Deduplicating...");
+    progress::set_phase("deduplicating");
+    let phase = debug_span!("deduplicating");
+    if progress::steps(silent) {
+        info!("Deduplicating...");
     }
+    // Every stored domain the input repeated was just seen again; of each
+    // group, keep the row seen first (pre-upgrade rows, with no first_seen,
+    // count as oldest)
     client.execute(
-        "DELETE FROM domains a USING domains b WHERE a.ctid < b.ctid AND a.domain = b.domain",
+        "UPDATE domains SET last_seen = now() \
+         WHERE domain IN (SELECT domain FROM domains GROUP BY domain HAVING COUNT(*) > 1)",
+        &[],
+    ).await?;
+    client.execute(
+        "DELETE FROM domains a USING domains b WHERE a.domain = b.domain \
+         AND (COALESCE(a.first_seen, '-infinity'), a.ctid) > (COALESCE(b.first_seen, '-infinity'), b.ctid)",
         &[],
     ).await?;
 
     // Rebuild indexes
-    if !silent {
-        eprintln!("Rebuilding indexes...");
+    drop(phase);
+    progress::set_phase("rebuilding indexes");
+    let phase = debug_span!("rebuilding_indexes");
+    if progress::steps(silent) {
+        info!("Rebuilding indexes...");
     }
     client.execute("ALTER TABLE domains ADD PRIMARY KEY (domain)", &[]).await?;
     client.execute("CREATE INDEX idx_domains_domain ON domains (domain text_pattern_ops)", &[]).await?;
+    drop(phase);
 
     // Get final count
     let row = client.query_one("SELECT COUNT(*) FROM domains", &[]).await?;
     let after_count: i64 = row.get(0);
+    client.batch_execute("COMMIT").await?;
     let new_count = after_count - before_count;
     let valid_count = total - invalid;
     let duplicate_count = valid_count as i64 - new_count;
@@ -169,19 +1136,33 @@ async fn run_bulk_copy(
         } else {
             0.0
         };
-        eprintln!(
+        info!(
+            processed = total,
+            new = new_count.max(0),
+            duplicates = duplicate_count.max(0),
             "Processed {} domains: {} new, {} duplicates ({:.2}%) in {:.1}s",
-            total, new_count, duplicate_count, pct, start.elapsed().as_secs_f64()
+            human::count(total),
+            human::count(new_count.max(0) as u64),
+            human::count(duplicate_count.max(0) as u64),
+            pct,
+            start.elapsed().as_secs_f64()
         );
         if invalid > 0 {
-            eprintln!("Skipped {} invalid domains", invalid);
+            info!("Skipped {} invalid domains", human::count(invalid));
         }
     }
 
-    Ok(())
+    Ok(ImportSummary {
+        processed: total,
+        new: new_count.max(0) as u64,
+        duplicates: duplicate_count.max(0) as u64,
+        invalid,
+        elapsed: Duration::ZERO,
+        import_id: None,
+    })
 }
 
-async fn copy_domains(client: &deadpool_postgres::Client, domains: &[String]) -> Result<()> {
+async fn copy_domains(client: &db::Client, domains: &[String]) -> Result<()> {
     // Use text-based COPY (more compatible than binary)
     let sink = client
         .copy_in("COPY domains (domain) FROM STDIN WITH (FORMAT text)")
@@ -190,36 +1171,363 @@ async fn copy_domains(client: &deadpool_postgres::Client, domains: &[String]) ->
     // Build text data
     let mut data = String::with_capacity(domains.len() * 50);
     for domain in domains {
-        data.push_str(domain);
-        data.push('\n');
+        push_copy_line(&mut data, domain);
     }
     
     use futures_util::SinkExt;
     let mut sink = std::pin::pin!(sink);
     sink.send(bytes::Bytes::from(data)).await?;
     sink.close().await?;
+    progress::add_written(domains.len() as u64);
     
     Ok(())
 }
 
-async fn insert_batch(client: &deadpool_postgres::Client, domains: &[String]) -> Result<u64> {
+/// Append `domain` as one line of text-format COPY data. Backslash, tab,
+/// CR and LF are special there, so they are escaped. Input lines with
+/// control characters are already rejected, but a backslash can still
+/// arrive with `--no-validate`.
+fn push_copy_line(data: &mut String, domain: &str) {
+    if !domain.contains(['\\', '\t', '\n', '\r']) {
+        data.push_str(domain);
+    } else {
+        for c in domain.chars() {
+            match c {
+                '\\' => data.push_str("\\\\"),
+                '\t' => data.push_str("\\t"),
+                '\n' => data.push_str("\\n"),
+                '\r' => data.push_str("\\r"),
+                c => data.push(c),
+            }
+        }
+    }
+    data.push('\n');
+}
+
+/// The subset of `candidates` already in the table
+async fn stored_among(
+    pool: &Pool,
+    candidates: &[String],
+    batch_size: usize,
+    case_insensitive: bool,
+) -> Result<HashSet<String>> {
+    // Mixed-case rows are only found through lower(domain), which
+    // idx_domains_lower indexes
+    let sql = if case_insensitive {
+        "SELECT lower(domain) FROM domains WHERE lower(domain) = ANY($1)"
+    } else {
+        "SELECT domain FROM domains WHERE domain = ANY($1)"
+    };
+    let client = pool.get().await?;
+    let mut stored = HashSet::new();
+    db::pin(&client).await?;
+    for chunk in candidates.chunks(batch_size) {
+        let rows = db::query(
+            &client,
+            sql,
+            &[(&chunk, Type::TEXT_ARRAY)],
+        )
+        .await?;
+        stored.extend(rows.iter().map(|r| r.get::<_, String>(0)));
+    }
+    db::unpin(&client).await?;
+    Ok(stored)
+}
+
+fn flush_listing(pending: &mut Vec<&str>) -> Result<()> {
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+    for domain in pending.drain(..) {
+        writeln!(handle, "{}", domain)?;
+    }
+    Ok(())
+}
+
+/// Like [`insert_batch`], but returns the domains that were actually new
+async fn insert_batch_returning(
+    client: &db::Client,
+    domains: &[String],
+    stamp: &Stamp,
+    case_insensitive: bool,
+) -> Result<HashSet<String>> {
+    let rows = upsert(client, domains, stamp, case_insensitive).await?;
+    Ok(rows.into_iter().filter(|(_, new)| *new).map(|(d, _)| d).collect())
+}
+
+/// Insert a batch, returning how many domains were new
+async fn insert_batch(
+    client: &db::Client,
+    domains: &[String],
+    stamp: &Stamp,
+    case_insensitive: bool,
+) -> Result<u64> {
+    let rows = upsert(client, domains, stamp, case_insensitive).await?;
+    Ok(rows.iter().filter(|(_, new)| *new).count() as u64)
+}
+
+/// New rows take `first_seen`/`last_seen` from their defaults; stored rows
+/// get `last_seen` bumped. The input is made distinct first because
+/// `DO UPDATE` cannot touch a row twice in one statement.
+const UPSERT: &str = "INSERT INTO domains (domain) SELECT DISTINCT unnest($1::text[]) \
+     ON CONFLICT (domain) DO UPDATE SET last_seen = now() \
+     RETURNING domain, xmax = 0";
+
+/// [`UPSERT`] against the case-insensitive index: the first-seen casing of
+/// each domain is inserted, and a stored domain matches in any case
+const UPSERT_CASE_INSENSITIVE: &str = "INSERT INTO domains (domain) \
+     SELECT DISTINCT ON (lower(d)) d FROM unnest($1::text[]) WITH ORDINALITY AS t(d, n) ORDER BY lower(d), n \
+     ON CONFLICT ((lower(domain))) DO UPDATE SET last_seen = now() \
+     RETURNING domain, xmax = 0";
+
+/// Each domain with whether it was newly inserted
+async fn upsert(
+    client: &db::Client,
+    domains: &[String],
+    stamp: &Stamp,
+    case_insensitive: bool,
+) -> Result<Vec<(String, bool)>> {
     if domains.is_empty() {
-        return Ok(0);
+        return Ok(Vec::new());
+    }
+    if !stamp.is_empty() {
+        return upsert_stamped(client, domains, stamp, case_insensitive).await;
+    }
+    let query = if case_insensitive { UPSERT_CASE_INSENSITIVE } else { UPSERT };
+    let rows = db::query(client, query, &[(&domains, Type::TEXT_ARRAY)]).await?;
+    progress::add_written(domains.len() as u64);
+    Ok(rows.iter().map(|r| (r.get(0), r.get(1))).collect())
+}
+
+/// `DO UPDATE` of a stamped upsert: existing rows get provided attributes
+/// overwritten (tags are merged), keep the ones that were omitted and have
+/// `last_seen` bumped
+macro_rules! stamp_update {
+    () => {
+        "DO UPDATE SET last_seen = now(), \
+         program = COALESCE(EXCLUDED.program, domains.program), \
+         source = COALESCE(EXCLUDED.source, domains.source), \
+         tags = CASE WHEN EXCLUDED.tags IS NULL THEN domains.tags ELSE ARRAY( \
+             SELECT DISTINCT t FROM unnest(COALESCE(domains.tags, '{}') || EXCLUDED.tags) AS t ORDER BY t) END \
+         RETURNING domain, xmax = 0"
+    };
+}
+
+/// Upsert stamping metadata in one statement: new rows get every provided
+/// attribute, existing rows are updated by `stamp_update!`
+const STAMPED_UPSERT: &str = concat!(
+    "INSERT INTO domains (domain, program, source, tags) \
+     SELECT DISTINCT d, $2::text, $3::text, $4::text[] FROM unnest($1::text[]) AS d \
+     ON CONFLICT (domain) ",
+    stamp_update!()
+);
+
+/// [`STAMPED_UPSERT`] against the case-insensitive index, inserting the
+/// first-seen casing like [`UPSERT_CASE_INSENSITIVE`]
+const STAMPED_UPSERT_CASE_INSENSITIVE: &str = concat!(
+    "INSERT INTO domains (domain, program, source, tags) \
+     SELECT DISTINCT ON (lower(d)) d, $2::text, $3::text, $4::text[] \
+     FROM unnest($1::text[]) WITH ORDINALITY AS t(d, n) ORDER BY lower(d), n \
+     ON CONFLICT ((lower(domain))) ",
+    stamp_update!()
+);
+
+/// Each domain with whether it was newly inserted
+async fn upsert_stamped(
+    client: &db::Client,
+    domains: &[String],
+    stamp: &Stamp,
+    case_insensitive: bool,
+) -> Result<Vec<(String, bool)>> {
+    let tags = (!stamp.tags.is_empty()).then_some(&stamp.tags);
+    let query = if case_insensitive { STAMPED_UPSERT_CASE_INSENSITIVE } else { STAMPED_UPSERT };
+    let rows = db::query(
+        client,
+        query,
+        &[
+            (&domains, Type::TEXT_ARRAY),
+            (&stamp.program, Type::TEXT),
+            (&stamp.source, Type::TEXT),
+            (&tags, Type::TEXT_ARRAY),
+        ],
+    )
+    .await?;
+    progress::add_written(domains.len() as u64);
+    Ok(rows.iter().map(|r| (r.get(0), r.get(1))).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::scratch::Scratch;
+
+    #[test]
+    fn test_chunk_tuner_moves_toward_target() {
+        let mut tuner = ChunkTuner::new(1000, 10_000, 1_000_000).unwrap();
+        assert_eq!(tuner.size(), 100_000);
+        // Half the target: double the chunk
+        tuner.record(100_000, Duration::from_millis(500));
+        assert_eq!(tuner.size(), 200_000);
+        // Far too fast: growth is capped per flush, then by the maximum
+        tuner.record(200_000, Duration::from_millis(1));
+        assert_eq!(tuner.size(), 800_000);
+        tuner.record(800_000, Duration::from_millis(1));
+        assert_eq!(tuner.size(), 1_000_000);
+        // Far too slow: shrink is capped per flush, then by the minimum
+        tuner.record(1_000_000, Duration::from_secs(60));
+        assert_eq!(tuner.size(), 250_000);
+        tuner.record(20_000, Duration::from_secs(60));
+        assert_eq!(tuner.size(), 10_000);
+        assert!(ChunkTuner::new(1000, 10, 5).is_err());
     }
 
-    // Build parameterized query
-    let mut query = String::from("INSERT INTO domains (domain) VALUES ");
-    let mut params: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(domains.len());
+    /// Inputs that would break unescaped text COPY: delimiters, line breaks,
+    /// escapes, the `\N` null marker and the `\.` end-of-data marker
+    const ADVERSARIAL: &[&str] = &[
+        "tab\there", "back\\slash", "\\N", "\\.", "cr\rlf\n", "trailing\\", "plain",
+    ];
 
-    for (i, domain) in domains.iter().enumerate() {
-        if i > 0 {
-            query.push_str(", ");
+    #[test]
+    fn test_push_copy_line_escapes_specials() {
+        let mut data = String::new();
+        for domain in ADVERSARIAL {
+            push_copy_line(&mut data, domain);
         }
-        query.push_str(&format!("(${})", i + 1));
-        params.push(domain);
+        assert_eq!(
+            data,
+            "tab\\there\nback\\\\slash\n\\\\N\n\\\\.\ncr\\rlf\\n\ntrailing\\\\\nplain\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_copy_round_trips_adversarial_domains() {
+        let scratch = Scratch::initialized("copy_escape").await;
+        let client = scratch.pool.get().await.unwrap();
+        let domains: Vec<String> = ADVERSARIAL.iter().map(|d| format!("{}.copy-escape.bountycatch.test", d)).collect();
+
+        copy_domains(&client, &domains).await.unwrap();
+        let rows = client
+            .query("SELECT domain FROM domains WHERE domain LIKE '%.copy-escape.bountycatch.test'", &[])
+            .await
+            .unwrap();
+        let mut stored: Vec<String> = rows.iter().map(|r| r.get(0)).collect();
+        let mut expected = domains.clone();
+        stored.sort();
+        expected.sort();
+        assert_eq!(stored, expected);
+
+        drop(client);
+        scratch.drop().await;
     }
-    query.push_str(" ON CONFLICT DO NOTHING");
 
-    let result = client.execute(&query, &params).await?;
-    Ok(result)
+    #[tokio::test]
+    async fn test_fold_www_keeps_preserved_case() {
+        let scratch = Scratch::initialized("fold_preserve").await;
+        let client = scratch.pool.get().await.unwrap();
+        client.batch_execute("INSERT INTO domains (domain) VALUES ('Shop.Example.net')").await.unwrap();
+        let opts = AddOptions {
+            preserve_case: true,
+            fold_www_into: Some(WwwForm::Bare),
+            silent: true,
+            ..Default::default()
+        };
+        let input = "API.Example.com\nWWW.Example.org\nexample.ORG\nwww.shop.example.NET\n";
+        let summary = import(&scratch.pool, Box::new(io::Cursor::new(input)), opts).await.unwrap();
+        assert_eq!(summary.new, 2);
+
+        let rows = client.query("SELECT domain FROM domains ORDER BY domain", &[]).await.unwrap();
+        let stored: Vec<String> = rows.iter().map(|r| r.get(0)).collect();
+        assert_eq!(stored, ["API.Example.com", "Shop.Example.net", "example.ORG"]);
+
+        drop(client);
+        scratch.drop().await;
+    }
+
+    #[test]
+    fn test_describe_delta() {
+        assert_eq!(describe_delta(1200, 3400), ("+1200 new vs +3400 last run (-64.7%)".to_string(), true));
+        assert_eq!(describe_delta(900, 1000), ("+900 new vs +1000 last run (-10.0%)".to_string(), false));
+        assert_eq!(describe_delta(5, 0), ("+5 new vs +0 last run".to_string(), false));
+    }
+
+    type Metadata = (Option<String>, Option<String>, Option<Vec<String>>);
+
+    async fn metadata(client: &db::Client, domain: &str) -> Metadata {
+        let row = client
+            .query_one("SELECT program, source, tags FROM domains WHERE domain = $1", &[&domain])
+            .await
+            .unwrap();
+        (row.get(0), row.get(1), row.get(2))
+    }
+
+    fn stamp(program: Option<&str>, source: Option<&str>, tags: &[&str]) -> Stamp {
+        Stamp {
+            program: program.map(String::from),
+            source: source.map(String::from),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stamp_new_rows_get_all_attributes() {
+        let scratch = Scratch::initialized("stamp_new").await;
+        let full = stamp(Some("acme"), Some("subfinder"), &["recon"]);
+        prepare_stamp(&scratch.pool, &full).await.unwrap();
+        let client = scratch.pool.get().await.unwrap();
+        let domain = "stamp-new.bountycatch.test".to_string();
+
+        let new = insert_batch(&client, &[domain.clone(), domain.clone()], &full, false).await.unwrap();
+        assert_eq!(new, 1);
+        assert_eq!(
+            metadata(&client, &domain).await,
+            (Some("acme".into()), Some("subfinder".into()), Some(vec!["recon".into()]))
+        );
+
+        drop(client);
+        scratch.drop().await;
+    }
+
+    #[tokio::test]
+    async fn test_stamp_conflict_updates_provided_and_keeps_omitted() {
+        let scratch = Scratch::initialized("stamp_conflict").await;
+        let first = stamp(Some("acme"), Some("subfinder"), &["recon"]);
+        prepare_stamp(&scratch.pool, &first).await.unwrap();
+        let client = scratch.pool.get().await.unwrap();
+        let domain = "stamp-conflict.bountycatch.test".to_string();
+
+        insert_batch(&client, std::slice::from_ref(&domain), &first, false).await.unwrap();
+        let second = stamp(None, Some("amass"), &["web", "recon"]);
+        let new = insert_batch(&client, std::slice::from_ref(&domain), &second, false).await.unwrap();
+        assert_eq!(new, 0);
+        assert_eq!(
+            metadata(&client, &domain).await,
+            (Some("acme".into()), Some("amass".into()), Some(vec!["recon".into(), "web".into()]))
+        );
+
+        drop(client);
+        scratch.drop().await;
+    }
+
+    #[tokio::test]
+    async fn test_stamp_with_case_insensitive_index() {
+        let scratch = Scratch::initialized("stamp_case").await;
+        ensure_case_insensitive_index(&scratch.pool).await.unwrap();
+        let first = stamp(Some("acme"), None, &["recon"]);
+        prepare_stamp(&scratch.pool, &first).await.unwrap();
+        let client = scratch.pool.get().await.unwrap();
+        let domain = "Stamp-Case.bountycatch.test".to_string();
+
+        let new = insert_batch(&client, &[domain.clone(), domain.to_lowercase()], &first, true).await.unwrap();
+        assert_eq!(new, 1);
+        let second = stamp(None, Some("amass"), &["web"]);
+        let new = insert_batch(&client, &[domain.to_uppercase()], &second, true).await.unwrap();
+        assert_eq!(new, 0);
+        // The first-seen casing is kept and stamped by both imports
+        assert_eq!(
+            metadata(&client, &domain).await,
+            (Some("acme".into()), Some("amass".into()), Some(vec!["recon".into(), "web".into()]))
+        );
+
+        drop(client);
+        scratch.drop().await;
+    }
 }
@@ -0,0 +1,63 @@
+use anyhow::Result;
+use futures_util::StreamExt;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::pin::pin;
+use tracing::info;
+
+use crate::db::{self, Pool};
+use crate::human;
+use crate::output::{OutputSink, StdoutSink};
+use crate::psl::SuffixList;
+
+/// The unique registrable domains of the stored domains, sorted, on stdout;
+/// with `counts`, each followed by how many stored domains fall under it
+pub async fn run(pool: &Pool, psl: Option<PathBuf>, counts: bool, silent: bool) -> Result<()> {
+    let list = match &psl {
+        Some(path) => SuffixList::load(path)?,
+        None => SuffixList::builtin(),
+    };
+
+    let client = pool.get().await?;
+    db::pin(&client).await?;
+    let rows = db::query_stream(&client, "SELECT domain FROM domains", &[]).await?;
+    let mut rows = pin!(rows);
+    let mut apexes: BTreeMap<String, u64> = BTreeMap::new();
+    let (mut total, mut skipped) = (0u64, 0u64);
+    while let Some(row) = rows.next().await {
+        let row = row?;
+        let domain: &str = row.get(0);
+        total += 1;
+        match list.split_apex(domain) {
+            Some((host, start)) => *apexes.entry(host[start..].to_string()).or_insert(0) += 1,
+            None => skipped += 1,
+        }
+    }
+    if total == 0 {
+        db::hint_if_empty(&client, silent).await?;
+    }
+    db::unpin(&client).await?;
+
+    let mut out = StdoutSink::new((None, None));
+    for (apex, count) in &apexes {
+        if counts {
+            out.line(&format!("{}\t{}", apex, count))?;
+        } else {
+            out.line(apex)?;
+        }
+    }
+    out.finish()?;
+
+    if !silent {
+        info!(
+            apexes = apexes.len(),
+            "{} apex domains from {} stored domains",
+            human::count(apexes.len() as u64),
+            human::count(total)
+        );
+        if skipped > 0 {
+            info!("Skipped {} IP addresses and bare public suffixes", human::count(skipped));
+        }
+    }
+    Ok(())
+}
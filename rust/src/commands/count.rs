@@ -1,51 +1,163 @@
 use anyhow::Result;
-use deadpool_postgres::Pool;
-use regex::Regex;
+use futures_util::StreamExt;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::pin::pin;
+
+use crate::db::{self, check_row_cap, Pool};
+use crate::domain::subdomain_labels;
+use crate::filter::DomainFilter;
+
+#[derive(Serialize)]
+pub struct LabelCount {
+    pub label: String,
+    pub count: i64,
+}
+
+/// The filter flags of a `count`, echoed back in the json and kv output
+/// (see [`crate::output::count_report`])
+pub struct CountFilter {
+    pub match_filter: Vec<String>,
+    pub regex_filter: Vec<String>,
+    pub filter_logic: Option<String>,
+    pub ignore_case: bool,
+    pub invert: bool,
+    pub labels: Vec<String>,
+    pub any_label: bool,
+    pub since: Option<String>,
+}
+
+impl CountFilter {
+    pub fn is_empty(&self) -> bool {
+        self.match_filter.is_empty() && self.regex_filter.is_empty() && self.labels.is_empty() && self.since.is_none()
+    }
+}
+
+/// What `count` found, before formatting
+pub enum Counted {
+    /// Matching domains, with the whole corpus for `--matched-percent`
+    Domains { count: i64, total: Option<i64> },
+    /// `--label-histogram`, most frequent first
+    Labels(Vec<LabelCount>),
+}
 
 pub async fn run(
     pool: &Pool,
-    match_filter: Option<String>,
-    regex_filter: Option<String>,
+    flags: &CountFilter,
+    matched_percent: bool,
+    histogram: Option<(usize, u64)>,
+    row_cap: Option<u64>,
+    wait: bool,
     silent: bool,
-) -> Result<()> {
+) -> Result<Counted> {
     let client = pool.get().await?;
-    let _ = silent; // suppress unused warning
+    db::pin(&client).await?;
+    db::check_import(&client, wait, silent).await?;
+
+    let mut filter = DomainFilter::from_flags(
+        &flags.match_filter,
+        &flags.regex_filter,
+        flags.filter_logic.as_deref(),
+        flags.ignore_case,
+        flags.invert,
+    )?;
+    filter.push_labels(&flags.labels, flags.any_label);
+    if let Some(since) = &flags.since {
+        filter.push_since(since)?;
+    }
+
+    if let Some((top, min_count)) = histogram {
+        let counts = label_histogram(&client, &filter, top, min_count, row_cap).await?;
+        if counts.is_empty() {
+            db::hint_if_empty(&client, silent).await?;
+        }
+        db::unpin(&client).await?;
+        return Ok(Counted::Labels(counts));
+    }
+
+    let count = matching(&client, &filter, row_cap).await?;
 
-    let count: i64 = if match_filter.is_some() || regex_filter.is_some() {
-        let regex = if let Some(pattern) = &regex_filter {
-            Some(Regex::new(pattern)?)
+    // Share of the whole corpus, only meaningful when a filter is applied
+    let total: Option<i64> = if matched_percent {
+        if !filter.is_empty() {
+            let row = client.query_one("SELECT COUNT(*) FROM domains", &[]).await?;
+            Some(row.get(0))
         } else {
-            None
-        };
+            Some(count)
+        }
+    } else {
+        None
+    };
 
-        let rows = client.query("SELECT domain FROM domains", &[]).await?;
-        let mut count = 0i64;
+    if count == 0 {
+        db::hint_if_empty(&client, silent).await?;
+    }
+    db::unpin(&client).await?;
 
-        for row in rows {
-            let domain: &str = row.get(0);
+    Ok(Counted::Domains { count, total })
+}
 
-            if let Some(ref m) = match_filter {
-                if !domain.contains(m.as_str()) {
-                    continue;
-                }
-            }
+/// Domains passing `filter`; only a filter with client-side parts streams
+/// rows, and so is subject to `row_cap`
+pub async fn matching(client: &db::Client, filter: &DomainFilter, row_cap: Option<u64>) -> Result<i64> {
+    if filter.has_residual() {
+        check_row_cap(client, filter, row_cap).await?;
 
-            if let Some(ref re) = regex {
-                if !re.is_match(domain) {
-                    continue;
-                }
+        let rows = db::query_stream(client, &filter.select(false), &filter.params()).await?;
+        let mut rows = pin!(rows);
+        let mut count = 0i64;
+        while let Some(row) = rows.next().await {
+            if filter.matches(row?.get(0)) {
+                count += 1;
             }
-
-            count += 1;
         }
-        count
+        Ok(count)
+    } else if !filter.is_empty() {
+        // Every condition is in SQL: count server-side without streaming rows
+        let query = format!("SELECT COUNT(*) FROM domains{}", filter.where_clause());
+        let rows = db::query(client, &query, &filter.params()).await?;
+        Ok(rows[0].get(0))
     } else {
         // Fast direct COUNT(*) when no filters
         let row = client.query_one("SELECT COUNT(*) FROM domains", &[]).await?;
-        row.get(0)
-    };
+        Ok(row.get(0))
+    }
+}
 
-    println!("{}", count);
+/// The `top` most frequent subdomain labels (lowercased, left of the apex)
+/// seen at least `min_count` times across the matching domains, most
+/// frequent first. The apex lookup runs client-side, so every matching
+/// row is streamed.
+async fn label_histogram(
+    client: &db::Client,
+    filter: &DomainFilter,
+    top: usize,
+    min_count: u64,
+    row_cap: Option<u64>,
+) -> Result<Vec<LabelCount>> {
+    check_row_cap(client, filter, row_cap).await?;
+    let rows = db::query_stream(client, &filter.select(false), &filter.params()).await?;
+    let mut rows = pin!(rows);
+    let mut tally: HashMap<String, i64> = HashMap::new();
+    while let Some(row) = rows.next().await {
+        let row = row?;
+        let domain: &str = row.get(0);
+        if filter.matches(domain) {
+            for label in subdomain_labels(domain) {
+                *tally.entry(label.to_ascii_lowercase()).or_insert(0) += 1;
+            }
+        }
+    }
+    Ok(top_labels(tally, top, min_count))
+}
 
-    Ok(())
+fn top_labels(tally: HashMap<String, i64>, top: usize, min_count: u64) -> Vec<LabelCount> {
+    let mut counts: Vec<LabelCount> = tally
+        .into_iter()
+        .filter(|&(_, count)| count as u64 >= min_count)
+        .map(|(label, count)| LabelCount { label, count })
+        .collect();
+    counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.label.cmp(&b.label)));
+    counts.truncate(top);
+    counts
 }
@@ -0,0 +1,224 @@
+use anyhow::Result;
+use std::collections::HashSet;
+use tracing::info;
+
+use crate::db::{self, Pool};
+use crate::domain::{normalize_host, strip_trailing_dot, to_idna_ascii};
+use crate::human;
+use crate::progress;
+
+const BATCH_SIZE: i64 = 10_000;
+
+/// A single opt-in normalization applied by `dedupe`
+#[derive(Clone, Copy)]
+enum Normalization {
+    /// The ingest normalizer (`add --normalize`)
+    Host,
+    Lowercase,
+    TrailingDot,
+    Idna,
+}
+
+impl Normalization {
+    fn name(self) -> &'static str {
+        match self {
+            Normalization::Host => "host",
+            Normalization::Lowercase => "lowercase",
+            Normalization::TrailingDot => "trailing-dot",
+            Normalization::Idna => "idna",
+        }
+    }
+
+    /// The normalized form, or `None` when the domain is already normal
+    fn apply(self, domain: &str) -> Option<String> {
+        let normalized = match self {
+            // An entry with no host left (`https://`) is left alone
+            Normalization::Host => Some(normalize_host(domain).into_owned()).filter(|h| !h.is_empty())?,
+            Normalization::Lowercase => domain.to_lowercase(),
+            Normalization::TrailingDot => strip_trailing_dot(domain).to_string(),
+            Normalization::Idna => to_idna_ascii(domain)?,
+        };
+        (normalized != domain).then_some(normalized)
+    }
+}
+
+/// Apply the requested normalizations in key order, one transaction per
+/// batch, merging rows that collide
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    pool: &Pool,
+    host: bool,
+    lowercase: bool,
+    trailing_dot: bool,
+    idna: bool,
+    dry_run: bool,
+    silent: bool,
+) -> Result<()> {
+    let mut client = pool.get().await?;
+
+    let steps: Vec<Normalization> = [
+        (host, Normalization::Host),
+        (idna, Normalization::Idna),
+        (lowercase, Normalization::Lowercase),
+        (trailing_dot, Normalization::TrailingDot),
+    ]
+    .into_iter()
+    .filter_map(|(enabled, n)| enabled.then_some(n))
+    .collect();
+
+    // No normalization requested: only report exact duplicates, which can
+    // exist if an interrupted bulk COPY left the primary key dropped
+    if steps.is_empty() {
+        db::pin(&client).await?;
+        let row = client
+            .query_one("SELECT COUNT(*) - COUNT(DISTINCT domain) FROM domains", &[])
+            .await?;
+        db::unpin(&client).await?;
+        let duplicates: i64 = row.get(0);
+        println!("{} exact duplicate rows", duplicates);
+        return Ok(());
+    }
+
+    progress::install_signal_handler();
+    progress::set_phase("deduplicating");
+    let row = client.query_one("SELECT COUNT(*) FROM domains", &[]).await?;
+    let before: i64 = row.get(0);
+
+    // Every step applies to each row in turn, so a rewritten row is already
+    // normal when the scan reaches it again and an interrupted run can
+    // simply be rerun
+    let mut cursor = String::new();
+    let mut rewritten = vec![0u64; steps.len()];
+    let mut merged = 0u64;
+    // Dry run only: targets produced so far, to count merges between rewrites
+    let mut produced: HashSet<String> = HashSet::new();
+
+    loop {
+        let tx = client.transaction().await?;
+        let rows = tx
+            .query(
+                "SELECT domain FROM domains WHERE domain > $1 ORDER BY domain LIMIT $2",
+                &[&cursor, &BATCH_SIZE],
+            )
+            .await?;
+        let Some(last) = rows.last() else {
+            break;
+        };
+        cursor = last.get(0);
+        progress::add_lines(rows.len() as u64);
+
+        let mut old: Vec<String> = Vec::new();
+        let mut new: Vec<String> = Vec::new();
+        for row in &rows {
+            let domain: String = row.get(0);
+            let mut normalized = domain.clone();
+            for (i, step) in steps.iter().enumerate() {
+                if let Some(n) = step.apply(&normalized) {
+                    normalized = n;
+                    rewritten[i] += 1;
+                }
+            }
+            if normalized != domain {
+                old.push(domain);
+                new.push(normalized);
+            }
+        }
+
+        if dry_run {
+            let existing = tx
+                .query("SELECT domain FROM domains WHERE domain = ANY($1)", &[&new])
+                .await?;
+            let existing: HashSet<String> = existing.iter().map(|r| r.get(0)).collect();
+            for n in new {
+                if existing.contains(&n) || !produced.insert(n) {
+                    merged += 1;
+                }
+            }
+            continue;
+        }
+
+        merged += db::rename_domains(&tx, &old, &new).await?;
+        tx.commit().await?;
+        progress::add_written(old.len() as u64);
+    }
+
+    if !silent {
+        let verb = if dry_run { "would be rewritten" } else { "rewritten" };
+        for (step, rewritten) in steps.iter().zip(rewritten) {
+            info!("{}: {} rows {}", step.name(), rewritten, verb);
+        }
+        let remaining = human::count(before as u64 - merged);
+        if dry_run {
+            info!("Dry run: would remove {} duplicate rows, leaving {}", human::count(merged), remaining);
+        } else {
+            info!("Removed {} duplicate rows; {} domains after normalization", human::count(merged), remaining);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::scratch::Scratch;
+
+    #[test]
+    fn test_apply() {
+        assert_eq!(Normalization::Lowercase.apply("API.Example.COM").as_deref(), Some("api.example.com"));
+        assert_eq!(Normalization::Lowercase.apply("api.example.com"), None);
+        assert_eq!(Normalization::TrailingDot.apply("Example.com.").as_deref(), Some("Example.com"));
+        assert_eq!(Normalization::TrailingDot.apply("example.com"), None);
+        assert_eq!(Normalization::Idna.apply("bücher.example").as_deref(), Some("xn--bcher-kva.example"));
+        assert_eq!(Normalization::Idna.apply("example.com"), None);
+        assert_eq!(Normalization::Host.apply("https://API.example.com:8443/x").as_deref(), Some("api.example.com"));
+        assert_eq!(Normalization::Host.apply("https://"), None);
+    }
+
+    #[tokio::test]
+    async fn test_lowercase_stores_lowercased() {
+        let scratch = Scratch::initialized("dedupe_lowercase").await;
+        let client = scratch.pool.get().await.unwrap();
+        client
+            .batch_execute(
+                "INSERT INTO domains (domain) VALUES ('API.Example.com'), ('api.example.com'), \
+                 ('WWW.EXAMPLE.COM'), ('Keep.example.com.')",
+            )
+            .await
+            .unwrap();
+
+        run(&scratch.pool, false, true, false, false, false, true).await.unwrap();
+        let rows = client.query("SELECT domain FROM domains ORDER BY domain", &[]).await.unwrap();
+        let stored: Vec<String> = rows.iter().map(|r| r.get(0)).collect();
+        // Only the case changes; the trailing dot needs --trailing-dot
+        assert_eq!(stored, ["api.example.com", "keep.example.com.", "www.example.com"]);
+
+        drop(client);
+        scratch.drop().await;
+    }
+
+    #[tokio::test]
+    async fn test_steps_merge_in_one_pass() {
+        let scratch = Scratch::initialized("dedupe_steps").await;
+        let client = scratch.pool.get().await.unwrap();
+        client
+            .batch_execute(
+                "INSERT INTO domains (domain) VALUES ('Shop.Example.com.'), ('shop.example.com'), \
+                 ('Bücher.example'), ('xn--bcher-kva.example'), ('other.example')",
+            )
+            .await
+            .unwrap();
+
+        run(&scratch.pool, false, true, true, true, true, true).await.unwrap();
+        let row = client.query_one("SELECT COUNT(*) FROM domains", &[]).await.unwrap();
+        assert_eq!(row.get::<_, i64>(0), 5);
+
+        run(&scratch.pool, false, true, true, true, false, true).await.unwrap();
+        let rows = client.query("SELECT domain FROM domains ORDER BY domain", &[]).await.unwrap();
+        let stored: Vec<String> = rows.iter().map(|r| r.get(0)).collect();
+        assert_eq!(stored, ["other.example", "shop.example.com", "xn--bcher-kva.example"]);
+
+        drop(client);
+        scratch.drop().await;
+    }
+}
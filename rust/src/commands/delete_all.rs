@@ -1,40 +1,43 @@
 use anyhow::Result;
-use deadpool_postgres::Pool;
-use std::io::{self, Write};
+use tracing::info;
 
-pub async fn run(pool: &Pool, confirm: bool, silent: bool) -> Result<()> {
-    if !confirm {
-        print!("Are you sure you want to delete ALL domains from the database? (y/N): ");
-        io::stdout().flush()?;
+use crate::db::{self, Pool};
+use crate::human;
+use crate::prompt;
 
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
+pub async fn run(pool: &Pool, confirm: bool, dry_run: bool, silent: bool) -> Result<()> {
+    if dry_run {
+        let client = pool.get().await?;
+        let row = client.query_one("SELECT COUNT(*) FROM domains", &[]).await?;
+        let count: i64 = row.get(0);
+        if !silent {
+            info!("Dry run: would delete all {} domains", human::count(count as u64));
+        }
+        return Ok(());
+    }
 
-        if !input.trim().eq_ignore_ascii_case("y") && !input.trim().eq_ignore_ascii_case("yes") {
-            if !silent {
-                eprintln!("Delete operation cancelled");
-            }
-            return Ok(());
+    if !confirm && !prompt::confirm("Are you sure you want to delete ALL domains from the database?")? {
+        if !silent {
+            info!("Delete operation cancelled");
         }
+        return Ok(());
     }
 
     let client = pool.get().await?;
+    db::pin(&client).await?;
 
-    // Check if table has data
-    let row = client
-        .query_one("SELECT EXISTS(SELECT 1 FROM domains LIMIT 1)", &[])
-        .await?;
-    let has_data: bool = row.get(0);
-
-    if !has_data {
+    if db::is_empty(&client).await? {
         if !silent {
-            eprintln!("No domains existed in database");
+            info!("No domains existed in database");
         }
         return Ok(());
     }
 
     client.execute("TRUNCATE TABLE domains", &[]).await?;
-    println!("All domains deleted successfully");
+    db::unpin(&client).await?;
+    if !silent {
+        info!("All domains deleted successfully");
+    }
 
     Ok(())
 }
@@ -0,0 +1,134 @@
+use anyhow::Result;
+use futures_util::StreamExt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::pin::pin;
+use std::time::Instant;
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::types::{ToSql, Type};
+use tracing::info;
+
+use crate::db::Pool;
+use crate::domain::strip_comment;
+use crate::human;
+use crate::progress;
+
+/// Compare an input list with the stored domains, writing `+ domain` for
+/// input-only domains, `- domain` for stored-only ones and, with `common`,
+/// `  domain` for both, in domain order. `only_new` writes just the new
+/// domains, bare. Returns whether any difference was found.
+pub async fn run(
+    pool: &Pool,
+    file: Option<PathBuf>,
+    allow_comments: bool,
+    only_new: bool,
+    common: bool,
+    silent: bool,
+) -> Result<bool> {
+    let client = pool.get().await?;
+    let start = Instant::now();
+    progress::install_signal_handler();
+
+    // The temp table lives for the transaction, like remove's fast path
+    client.batch_execute("BEGIN").await?;
+    client
+        .batch_execute("CREATE TEMP TABLE temp_diff (domain TEXT) ON COMMIT DROP")
+        .await?;
+
+    let reader: Box<dyn BufRead> = match file {
+        Some(path) => Box::new(BufReader::with_capacity(512 * 1024, File::open(path)?)),
+        None => Box::new(BufReader::with_capacity(512 * 1024, io::stdin().lock())),
+    };
+
+    // Stream the input straight into the temp table
+    let sink = client
+        .copy_in("COPY temp_diff (domain) FROM STDIN (FORMAT binary)")
+        .await?;
+    let mut writer = pin!(BinaryCopyInWriter::new(sink, &[Type::TEXT]));
+    let mut comments = 0u64;
+    progress::set_phase("copying");
+    for line in reader.lines() {
+        let line = line?;
+        progress::add_lines(1);
+        let domain = if allow_comments {
+            match strip_comment(&line) {
+                Some(d) => d,
+                None => {
+                    comments += 1;
+                    continue;
+                }
+            }
+        } else {
+            line.trim()
+        };
+        if !domain.is_empty() {
+            writer.as_mut().write(&[&domain]).await?;
+        }
+    }
+    let copied = writer.finish().await?;
+    progress::add_written(copied);
+    // Temp tables are never auto-analyzed; the join plan needs the row count
+    client.batch_execute("ANALYZE temp_diff").await?;
+
+    if !silent && comments > 0 {
+        info!("Skipped {} comment lines", human::count(comments));
+    }
+
+    let filter = if only_new {
+        " WHERE d.domain IS NULL"
+    } else if common {
+        ""
+    } else {
+        " WHERE d.domain IS NULL OR t.domain IS NULL"
+    };
+    let query = format!(
+        "SELECT COALESCE(t.domain, d.domain), d.domain IS NULL, t.domain IS NULL \
+         FROM (SELECT DISTINCT domain FROM temp_diff) t FULL JOIN domains d ON d.domain = t.domain{} \
+         ORDER BY 1",
+        filter
+    );
+
+    progress::set_phase("comparing");
+    let stream = client
+        .query_raw(&query, std::iter::empty::<&(dyn ToSql + Sync)>())
+        .await?;
+    let mut stream = pin!(stream);
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let (mut added, mut removed, mut shared) = (0u64, 0u64, 0u64);
+    while let Some(row) = stream.next().await {
+        let row = row?;
+        let domain: &str = row.get(0);
+        let (new, gone): (bool, bool) = (row.get(1), row.get(2));
+        if only_new {
+            writeln!(out, "{}", domain)?;
+        } else if new {
+            writeln!(out, "+ {}", domain)?;
+        } else if gone {
+            writeln!(out, "- {}", domain)?;
+        } else {
+            writeln!(out, "  {}", domain)?;
+        }
+        match (new, gone) {
+            (true, _) => added += 1,
+            (_, true) => removed += 1,
+            _ => shared += 1,
+        }
+    }
+    out.flush()?;
+    client.batch_execute("COMMIT").await?;
+
+    if !silent {
+        let mut summary = format!("{} new", human::count(added));
+        if !only_new {
+            summary.push_str(&format!(", {} removed", human::count(removed)));
+        }
+        if common {
+            summary.push_str(&format!(", {} in both", human::count(shared)));
+        }
+        info!("{} ({:.1}s)", summary, start.elapsed().as_secs_f64());
+    }
+
+    Ok(added + removed > 0)
+}
@@ -0,0 +1,506 @@
+//! `bountycatch doctor`: guided troubleshooting over config, connectivity,
+//! privileges and schema. Every check runs; checks whose prerequisite failed
+//! are reported as skipped instead of stopping at the first problem.
+
+use anyhow::{bail, Result};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::db;
+
+/// How long to wait for the server before reporting it unreachable
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+    Skip,
+}
+
+#[derive(Default)]
+struct Report {
+    passed: usize,
+    warned: usize,
+    failed: usize,
+}
+
+impl Report {
+    fn record(&mut self, status: Status, check: &str, detail: &str, hint: Option<&str>) {
+        let label = match status {
+            Status::Pass => {
+                self.passed += 1;
+                "PASS"
+            }
+            Status::Warn => {
+                self.warned += 1;
+                "WARN"
+            }
+            Status::Fail => {
+                self.failed += 1;
+                "FAIL"
+            }
+            Status::Skip => "SKIP",
+        };
+        println!("[{}] {:<12} {}", label, check, detail);
+        if let (Some(hint), true) = (hint, status != Status::Pass) {
+            println!("       {:<12} hint: {}", "", hint);
+        }
+    }
+
+    fn skip(&mut self, checks: &[&str], reason: &str) {
+        for check in checks {
+            self.record(Status::Skip, check, reason, None);
+        }
+    }
+}
+
+/// Checks that need a working connection, in report order
+const DB_CHECKS: &[&str] = &["connection", "table", "privileges", "ownership", "schema", "index"];
+
+/// Remediation for a failed connection, keyed on the server's message
+fn connection_hint(error: &str) -> &'static str {
+    let error = error.to_ascii_lowercase();
+    if error.contains("password authentication failed") || error.contains("authentication") {
+        "check user/password in the config file or set PGPASSWORD"
+    } else if error.contains("database") && error.contains("does not exist") {
+        "create the database (sudo ./setup_postgres.sh) or fix `database` in the config"
+    } else if error.contains("role") && error.contains("does not exist") {
+        "create the role (sudo ./setup_postgres.sh) or fix `user` in the config"
+    } else if error.contains("refused") || error.contains("timed out") {
+        "start PostgreSQL (sudo systemctl start postgresql) and check host/port"
+    } else {
+        "check host, port and credentials; `bountycatch -v info` shows the target"
+    }
+}
+
+/// Whether the domains table exists, or can be created, in the project's
+/// schema. Returns the connected user when it exists.
+async fn check_table(client: &db::Client, project_schema: Option<&str>, report: &mut Report) -> Result<Option<String>> {
+    let row = client
+        .query_one("SELECT current_user::text, current_database()::text, current_schema()::text", &[])
+        .await?;
+    let user: String = row.get(0);
+    let database: String = row.get(1);
+    // NULL when no schema on the search_path exists
+    let schema: Option<String> = row.get(2);
+
+    if let Some(project_schema) = project_schema {
+        // Project names are limited to [a-z0-9_], so the literal is safe
+        let row = client
+            .query_one(
+                &format!(
+                    "SELECT to_regnamespace('{}') IS NOT NULL, \
+                     COALESCE(has_database_privilege(current_database(), 'CREATE'), false)",
+                    project_schema
+                ),
+                &[],
+            )
+            .await?;
+        let (exists, can_create): (bool, bool) = (row.get(0), row.get(1));
+        if !exists {
+            if can_create {
+                report.record(
+                    Status::Warn,
+                    "table",
+                    &format!("project schema {} does not exist yet", project_schema),
+                    Some("the project is created by its first `add`; `bountycatch projects` lists the existing ones"),
+                );
+            } else {
+                let hint = format!("GRANT CREATE ON DATABASE {} TO {};", database, user);
+                report.record(
+                    Status::Fail,
+                    "table",
+                    &format!("project schema {} does not exist and cannot be created", project_schema),
+                    Some(&hint),
+                );
+            }
+            return Ok(None);
+        }
+    }
+
+    let Some(schema) = schema else {
+        report.record(
+            Status::Fail,
+            "table",
+            "no schema on the search_path exists",
+            Some("create the schema or fix search_path for this role"),
+        );
+        return Ok(None);
+    };
+    let row = client
+        .query_one(
+            "SELECT to_regclass('domains') IS NOT NULL, \
+             COALESCE(has_schema_privilege(current_schema(), 'CREATE'), false)",
+            &[],
+        )
+        .await?;
+    let (exists, can_create): (bool, bool) = (row.get(0), row.get(1));
+    if exists {
+        report.record(Status::Pass, "table", "domains exists", None);
+        return Ok(Some(user));
+    }
+    if can_create {
+        report.record(
+            Status::Warn,
+            "table",
+            "domains does not exist yet",
+            Some("it is created on the first other command, e.g. `bountycatch count`"),
+        );
+    } else {
+        let hint = format!("GRANT CREATE ON SCHEMA {} TO {};", schema, user);
+        report.record(Status::Fail, "table", "domains does not exist and cannot be created", Some(&hint));
+    }
+    Ok(None)
+}
+
+/// With `fix`, a missing primary key and a missing or invalid prefix index
+/// are rebuilt instead of only reported
+pub async fn run(
+    config_path: Option<&Path>,
+    pgbouncer: bool,
+    no_prepare: bool,
+    project: Option<&str>,
+    fix: bool,
+) -> Result<()> {
+    let mut report = Report::default();
+
+    // Config
+    let source = Config::config_file(config_path).ok().flatten();
+    let mut config = match Config::load(config_path) {
+        Ok(config) => {
+            let detail = match &source {
+                Some(path) => format!("loaded {:?}", path),
+                None => "no config file found; using defaults and PG* variables".to_string(),
+            };
+            report.record(Status::Pass, "config", &detail, None);
+            Some(config)
+        }
+        Err(e) => {
+            report.record(
+                Status::Fail,
+                "config",
+                &format!("{:#}", e),
+                Some("check the file syntax and password_source, or point --config at a valid file"),
+            );
+            None
+        }
+    };
+
+    // Target and pool options
+    let pool = match config.as_mut() {
+        Some(config) => {
+            if pgbouncer {
+                config.postgresql.pooler_mode = "transaction".to_string();
+            }
+            if no_prepare {
+                config.postgresql.prepared_statements = false;
+            }
+            if let Some(project) = project {
+                config.postgresql.project = project.to_string();
+            }
+            match db::create_pool(&config.postgresql).await {
+                Ok(pool) => {
+                    let mut target = config.postgresql.redacted_target();
+                    if config.postgresql.project != db::DEFAULT_PROJECT {
+                        target.push_str(&format!(" (project {})", config.postgresql.project));
+                    }
+                    report.record(Status::Pass, "target", &target, None);
+                    Some(pool)
+                }
+                Err(e) => {
+                    report.record(
+                        Status::Fail,
+                        "target",
+                        &format!("{:#}", e),
+                        Some("check pooler_mode (session or transaction), sslmode and project in the config"),
+                    );
+                    None
+                }
+            }
+        }
+        None => {
+            report.skip(&["target"], "no usable config");
+            None
+        }
+    };
+
+    let Some(pool) = pool else {
+        report.skip(DB_CHECKS, "no connection settings");
+        return finish(report);
+    };
+
+    // Connection
+    let client = match tokio::time::timeout(CONNECT_TIMEOUT, pool.get()).await {
+        Ok(Ok(client)) => client,
+        Ok(Err(e)) => {
+            let error = anyhow::Error::from(e).root_cause().to_string();
+            report.record(Status::Fail, "connection", &error, Some(connection_hint(&error)));
+            report.skip(&DB_CHECKS[1..], "not connected");
+            return finish(report);
+        }
+        Err(_) => {
+            let error = format!("timed out after {}s", CONNECT_TIMEOUT.as_secs());
+            report.record(Status::Fail, "connection", &error, Some(connection_hint(&error)));
+            report.skip(&DB_CHECKS[1..], "not connected");
+            return finish(report);
+        }
+    };
+    db::pin(&client).await?;
+    let row = client.query_one("SHOW server_version", &[]).await?;
+    let version: String = row.get(0);
+    report.record(Status::Pass, "connection", &format!("PostgreSQL {}", version), None);
+
+    // Table
+    let Some(user) = check_table(&client, pool.settings().schema.as_deref(), &mut report).await? else {
+        report.skip(&DB_CHECKS[2..], "no domains table");
+        db::unpin(&client).await?;
+        return finish(report);
+    };
+
+    // Privileges the everyday commands need
+    let row = client
+        .query_one(
+            "SELECT has_table_privilege('domains', 'SELECT'), \
+             has_table_privilege('domains', 'INSERT'), \
+             has_table_privilege('domains', 'DELETE'), \
+             pg_has_role((SELECT relowner FROM pg_class WHERE oid = 'domains'::regclass), 'USAGE')",
+            &[],
+        )
+        .await?;
+    let missing: Vec<&str> = ["SELECT", "INSERT", "DELETE"]
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !row.get::<_, bool>(*i))
+        .map(|(_, p)| *p)
+        .collect();
+    if missing.is_empty() {
+        report.record(Status::Pass, "privileges", "SELECT, INSERT, DELETE on domains", None);
+    } else {
+        let hint = format!("GRANT {} ON domains TO {};", missing.join(", "), user);
+        report.record(Status::Fail, "privileges", &format!("missing {}", missing.join(", ")), Some(&hint));
+    }
+    let owner: bool = row.get(3);
+    if owner {
+        report.record(Status::Pass, "ownership", &format!("{} owns domains", user), None);
+    } else {
+        let hint = format!("ALTER TABLE domains OWNER TO {};", user);
+        report.record(
+            Status::Warn,
+            "ownership",
+            "not the table owner; sql-rebuild imports, table-mode and metadata stamping need it",
+            Some(&hint),
+        );
+    }
+
+    check_schema(&client, fix, &mut report).await?;
+    db::unpin(&client).await?;
+
+    finish(report)
+}
+
+/// Column type, primary key and prefix index of the domains table. With
+/// `fix`, a missing primary key or index is rebuilt unless an import is
+/// running, since a bulk import drops both and restores them at the end.
+async fn check_schema(client: &db::Client, fix: bool, report: &mut Report) -> Result<()> {
+    // Schema
+    let row = client
+        .query_opt(
+            "SELECT data_type::text FROM information_schema.columns \
+             WHERE table_schema = current_schema() AND table_name = 'domains' AND column_name = 'domain'",
+            &[],
+        )
+        .await?;
+    let data_type: Option<String> = row.map(|r| r.get(0));
+    let mut has_pkey = has_primary_key(client).await?;
+    let mut repair = None;
+    if fix && !has_pkey && data_type.as_deref() == Some("text") {
+        // Left alone while an import is running, so check again after
+        repair = Some(match db::check_primary_key(client, true, true).await {
+            Ok(()) => {
+                has_pkey = has_primary_key(client).await?;
+                None
+            }
+            Err(e) => Some(format!("{:#}", e)),
+        });
+    }
+    match (data_type.as_deref(), repair) {
+        (Some("text"), Some(None)) if has_pkey => {
+            report.record(Status::Pass, "schema", "domain text primary key (rebuilt)", None)
+        }
+        (Some("text"), _) if has_pkey => report.record(Status::Pass, "schema", "domain text primary key", None),
+        (Some("text"), Some(Some(error))) => report.record(
+            Status::Fail,
+            "schema",
+            &format!("primary key on domain is missing and could not be rebuilt: {}", error),
+            Some("remove the duplicate rows `bountycatch dedupe` reports, then rerun with --fix"),
+        ),
+        (Some("text"), _) => report.record(
+            Status::Warn,
+            "schema",
+            "primary key on domain is missing (bulk import running or interrupted)",
+            Some("if no import is running, rerun with --fix (or any command with --repair) to rebuild it"),
+        ),
+        (Some(other), _) => report.record(
+            Status::Fail,
+            "schema",
+            &format!("domain column is {}, expected text", other),
+            Some("ALTER TABLE domains ALTER COLUMN domain TYPE text;"),
+        ),
+        (None, _) => report.record(
+            Status::Fail,
+            "schema",
+            "domains has no domain column",
+            Some("the table was not created by bountycatch; rename it and let bountycatch create its own"),
+        ),
+    }
+
+    // Prefix index
+    let row = client
+        .query_opt(
+            "SELECT indisvalid FROM pg_index WHERE indexrelid = to_regclass('idx_domains_domain')",
+            &[],
+        )
+        .await?;
+    let create_index = "CREATE INDEX idx_domains_domain ON domains (domain text_pattern_ops);";
+    let valid = row.map(|r| r.get::<_, bool>(0));
+    // A bulk import drops the index and rebuilds it at the end
+    let importing = fix && valid != Some(true) && db::import_running(client).await?;
+    if fix && valid != Some(true) && data_type.is_some() && !importing {
+        let rebuild = format!("DROP INDEX IF EXISTS idx_domains_domain; {}", create_index);
+        match client.batch_execute(&rebuild).await {
+            Ok(()) => report.record(Status::Pass, "index", "idx_domains_domain present (rebuilt)", None),
+            Err(e) => report.record(
+                Status::Fail,
+                "index",
+                &format!("could not rebuild idx_domains_domain: {}", anyhow::Error::from(e).root_cause()),
+                Some("rebuilding the index needs table ownership; see the ownership check"),
+            ),
+        }
+        return Ok(());
+    }
+    match valid {
+        Some(true) => report.record(Status::Pass, "index", "idx_domains_domain present", None),
+        _ if importing => report.record(
+            Status::Warn,
+            "index",
+            "idx_domains_domain is missing while a bulk import rebuilds it; left alone",
+            Some("rerun with --fix if it is still missing after the import"),
+        ),
+        Some(false) => report.record(
+            Status::Warn,
+            "index",
+            "idx_domains_domain is invalid (interrupted build)",
+            Some(&format!("DROP INDEX idx_domains_domain; {}", create_index)),
+        ),
+        None => report.record(
+            Status::Warn,
+            "index",
+            "idx_domains_domain is missing; prefix lookups scan the table",
+            Some(create_index),
+        ),
+    }
+    Ok(())
+}
+
+async fn has_primary_key(client: &db::Client) -> Result<bool> {
+    let row = client
+        .query_one("SELECT to_regclass('domains_pkey') IS NOT NULL", &[])
+        .await?;
+    Ok(row.get(0))
+}
+
+fn finish(report: Report) -> Result<()> {
+    println!();
+    println!("{} passed, {} warnings, {} failed", report.passed, report.warned, report.failed);
+    if report.failed > 0 {
+        bail!("{} check(s) failed", report.failed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::scratch::Scratch;
+
+    /// A domains table that lost its primary key and prefix index
+    async fn stripped(name: &str) -> Scratch {
+        let scratch = Scratch::empty(name).await;
+        let client = scratch.pool.get().await.unwrap();
+        client
+            .batch_execute("CREATE TABLE domains (domain TEXT); INSERT INTO domains VALUES ('a.example.com')")
+            .await
+            .unwrap();
+        scratch
+    }
+
+    async fn has_index(client: &db::Client) -> bool {
+        let row = client.query_one("SELECT to_regclass('idx_domains_domain') IS NOT NULL", &[]).await.unwrap();
+        row.get(0)
+    }
+
+    #[test]
+    fn test_connection_hint() {
+        assert!(connection_hint("password authentication failed for user \"x\"").contains("PGPASSWORD"));
+        assert!(connection_hint("database \"bc\" does not exist").contains("setup_postgres"));
+        assert!(connection_hint("Connection refused (os error 111)").contains("systemctl"));
+        assert!(connection_hint("something else").contains("host, port"));
+    }
+
+    #[tokio::test]
+    async fn test_missing_project_schema() {
+        let scratch = Scratch::empty("doctor_no_project").await;
+        let client = scratch.pool.get().await.unwrap();
+        client.batch_execute("DROP SCHEMA bc_test_doctor_no_project").await.unwrap();
+
+        let mut report = Report::default();
+        let user = check_table(&client, Some("bc_test_doctor_no_project"), &mut report).await.unwrap();
+        assert!(user.is_none());
+        assert_eq!((report.passed, report.warned, report.failed), (0, 1, 0));
+
+        // Without a project the search_path has no existing schema left
+        let mut report = Report::default();
+        assert!(check_table(&client, None, &mut report).await.unwrap().is_none());
+        assert_eq!((report.passed, report.warned, report.failed), (0, 0, 1));
+    }
+
+    #[tokio::test]
+    async fn test_fix_rebuilds_primary_key_and_index() {
+        let scratch = stripped("doctor_fix").await;
+        let client = scratch.pool.get().await.unwrap();
+
+        let mut report = Report::default();
+        check_schema(&client, false, &mut report).await.unwrap();
+        assert_eq!((report.passed, report.warned, report.failed), (0, 2, 0));
+        assert!(!has_primary_key(&client).await.unwrap());
+
+        let mut report = Report::default();
+        check_schema(&client, true, &mut report).await.unwrap();
+        assert_eq!((report.passed, report.warned, report.failed), (2, 0, 0));
+        assert!(has_primary_key(&client).await.unwrap());
+        assert!(has_index(&client).await);
+
+        drop(client);
+        scratch.drop().await;
+    }
+
+    #[tokio::test]
+    async fn test_fix_leaves_schema_alone_during_import() {
+        let scratch = stripped("doctor_importing").await;
+        let import = db::lock_import(&scratch.pool).await.unwrap();
+        let client = scratch.pool.get().await.unwrap();
+
+        let mut report = Report::default();
+        check_schema(&client, true, &mut report).await.unwrap();
+        assert_eq!((report.passed, report.warned, report.failed), (0, 2, 0));
+        assert!(!has_primary_key(&client).await.unwrap());
+        assert!(!has_index(&client).await);
+
+        db::unlock_import(import).await.unwrap();
+        drop(client);
+        scratch.drop().await;
+    }
+}
@@ -1,112 +1,688 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use chrono::Utc;
-use deadpool_postgres::Pool;
 use futures_util::StreamExt;
-use regex::Regex;
 use serde::Serialize;
-use std::fs::File;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
 use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::pin::pin;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_postgres::types::{ToSql, Type};
+use tracing::info;
+
+use crate::csv;
+use crate::db::{self, Pool};
+use crate::domain::{covered_by_wildcard, one_per_apex, strip_trailing_dot};
+use crate::exclude;
+use crate::filter::DomainFilter;
+use crate::human;
+use crate::output::{FileSink, OutputSink, PartFormat, PartSink, FILE_BUFFER};
+#[cfg(feature = "parquet")]
+use crate::parquet;
+use crate::progress;
+
+/// CSV columns after `domain`, rendered server-side as text; sighting
+/// times a row never recorded are left empty
+const CSV_SEEN_COLUMNS: &str = "\
+    COALESCE(to_char(first_seen AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"'), ''), \
+    COALESCE(to_char(last_seen AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"'), '')";
+const CSV_HEADER: &str = "domain,first_seen,last_seen";
+/// Parquet columns after `domain`: the sighting times in microseconds since
+/// the Unix epoch, NULL when not recorded
+const PARQUET_SEEN_COLUMNS: &str = "\
+    (extract(epoch FROM first_seen) * 1000000)::int8, (extract(epoch FROM last_seen) * 1000000)::int8";
+
+/// One line of a `jsonl` export
+#[derive(Serialize)]
+struct JsonlRecord<'a> {
+    domain: &'a str,
+}
 
 #[derive(Serialize)]
 struct ExportData {
     domain_count: usize,
     exported_at: String,
-    domains: Vec<String>,
+    /// `null` instead of `[]` when nothing matched and --emit-null-for-empty is set
+    domains: Option<Vec<String>>,
 }
 
+/// Part files for `--partition-size`: CSV parts each start with the header;
+/// JSON parts are each a complete document, so they are buffered
+fn part_format(format: &str) -> PartFormat {
+    match format {
+        "json" => PartFormat { ext: "json", header: None, document: Some(json_document) },
+        "csv" => PartFormat { ext: "csv", header: Some(CSV_HEADER), document: None },
+        "jsonl" => PartFormat { ext: "jsonl", header: None, document: None },
+        _ => PartFormat { ext: "txt", header: None, document: None },
+    }
+}
+
+/// The `json` export document for `domains`
+fn json_document(domains: Vec<String>) -> Result<Vec<u8>> {
+    let export_data = ExportData {
+        domain_count: domains.len(),
+        exported_at: Utc::now().to_rfc3339(),
+        domains: Some(domains),
+    };
+    Ok(serde_json::to_vec_pretty(&export_data)?)
+}
+
+/// How `export` selects, orders and formats domains; everything except
+/// where they are written
+pub struct FileExportOptions {
+    /// `text`, `json`, `jsonl`, `csv` or `parquet`
+    pub format: String,
+    pub match_filter: Vec<String>,
+    pub regex_filter: Vec<String>,
+    /// `and` or `or`
+    pub filter_logic: Option<String>,
+    pub ignore_case: bool,
+    /// Export the domains that do not match instead
+    pub invert: bool,
+    /// Order by domain instead of storage order
+    pub sort: bool,
+    /// Lowercased, byte-order sorted text for version control
+    pub canonical: bool,
+    /// Keep one host per apex, picked by this `--representative` rule
+    pub one_per_apex_rule: Option<String>,
+    /// In JSON, write `"domains": null` instead of `[]` when nothing matched
+    pub emit_null_for_empty: bool,
+    /// Drop hosts covered by an exported `*.apex` wildcard
+    pub minimize: bool,
+    /// Seeded random order; `seed` fixes it
+    pub shuffle: bool,
+    pub seed: Option<u64>,
+    /// Text around each domain (text format)
+    pub prefix: Option<String>,
+    pub suffix: Option<String>,
+    /// Leave out the domains listed in an earlier export
+    pub diff_against: Option<PathBuf>,
+    /// Leave out the domains listed in this file
+    pub exclude_file: Option<PathBuf>,
+    /// Only report errors
+    pub silent: bool,
+}
+
+impl Default for FileExportOptions {
+    fn default() -> Self {
+        Self {
+            format: "text".to_string(),
+            match_filter: Vec::new(),
+            regex_filter: Vec::new(),
+            filter_logic: None,
+            ignore_case: false,
+            invert: false,
+            sort: false,
+            canonical: false,
+            one_per_apex_rule: None,
+            emit_null_for_empty: false,
+            minimize: false,
+            shuffle: false,
+            seed: None,
+            prefix: None,
+            suffix: None,
+            diff_against: None,
+            exclude_file: None,
+            silent: false,
+        }
+    }
+}
+
+/// Export to `file`, or to numbered part files with `partition`
 pub async fn run(
     pool: &Pool,
-    file: PathBuf,
-    format: String,
-    match_filter: Option<String>,
-    regex_filter: Option<String>,
-    sort: bool,
-    silent: bool,
-) -> Result<()> {
+    file: Option<PathBuf>,
+    partition: Option<(usize, PathBuf)>,
+    opts: FileExportOptions) -> Result<()> {
+    let FileExportOptions {
+        format, match_filter, regex_filter, filter_logic, ignore_case, invert, sort, canonical, one_per_apex_rule,
+        emit_null_for_empty, minimize, shuffle, seed, prefix, suffix, diff_against, exclude_file, silent,
+    } = opts;
+    if format != "text" && (prefix.is_some() || suffix.is_some()) {
+        bail!("--prefix/--suffix only apply to the text format");
+    }
+    if format != "text" && canonical {
+        bail!("--canonical only applies to the text format");
+    }
+    if format == "parquet" && (partition.is_some() || one_per_apex_rule.is_some()) {
+        bail!("--format parquet writes one streamed file; it cannot be combined with --partition-size or --one-per-apex");
+    }
+    let csv = format == "csv";
+    let wrap = (prefix.as_deref(), suffix.as_deref());
     let client = pool.get().await?;
+    db::pin(&client).await?;
 
-    // Use fast COPY when no filters and text format
-    if match_filter.is_none() && regex_filter.is_none() && !sort && format != "json" {
-        let output = File::create(&file)?;
-        let mut writer = BufWriter::with_capacity(1024 * 1024, output);
-        
-        let reader = client
-            .copy_out("COPY domains (domain) TO STDOUT")
-            .await?;
+    let filter = DomainFilter::from_flags(&match_filter, &regex_filter, filter_logic.as_deref(), ignore_case, invert)?;
+    let seed = shuffle.then(|| seed.unwrap_or_else(random_seed).to_string());
+    if let (Some(seed), false) = (&seed, silent) {
+        info!("Shuffling with seed {} (pass --seed {} to reproduce)", seed, seed);
+    }
+    // --canonical sorts client-side, so the server's order is irrelevant
+    let (select, params) = ordered_select(&filter, sort && !canonical, seed.as_ref(), &format);
+    let wildcards = if minimize {
+        Some(load_wildcards(&client, &match_filter, &regex_filter, filter_logic.as_deref(), ignore_case, invert).await?)
+    } else {
+        None
+    };
+    // --diff-against and --exclude-file subtract a list the same way
+    let exclusion = diff_against.map(|path| (path, "--diff-against"))
+        .or(exclude_file.map(|path| (path, "--exclude-file")));
+    let previous = exclusion.as_ref().map(|(path, flag)| exclude::load(path, flag)).transpose()?;
+    let flag = exclusion.as_ref().map_or("", |(_, flag)| *flag);
+    if let (Some(previous), Some((path, _)), true) = (&previous, &exclusion, progress::steps(silent)) {
+        info!("Loaded {} domains from {:?}", human::count(previous.len() as u64), path);
+    }
+
+    if let Some((size, dir)) = partition {
+        run_partitioned(
+            &client, size, &dir, &format, wrap, &filter, (&select, &params), sort, one_per_apex_rule, wildcards.as_ref(),
+            previous.as_ref().map(|p| (p, flag)), silent,
+        )
+        .await?;
+        db::unpin(&client).await?;
+        return Ok(());
+    }
+    let Some(file) = file else {
+        bail!("--file is required unless --partition-size is given");
+    };
+
+    // Use fast COPY when no filters and text format; an exclusion list is
+    // anti-joined server-side
+    if filter.is_empty() && !sort && !canonical && !shuffle && one_per_apex_rule.is_none() && !minimize && format == "text" {
+        let mut out = FileSink::create(&file, wrap)?;
+
+        let copy = match &previous {
+            Some(previous) => {
+                exclude::load_table(&client, previous).await?;
+                exclude::COPY_OUT
+            }
+            None => "COPY domains (domain) TO STDOUT",
+        };
+        let reader = client.copy_out(copy).await?;
         
         let mut pinned = pin!(reader);
+        let mut exported = 0u64;
         while let Some(chunk) = pinned.next().await {
             let data = chunk?;
-            writer.write_all(&data)?;
+            exported += data.iter().filter(|&&b| b == b'\n').count() as u64;
+            out.raw(&data)?;
+        }
+        out.finish()?;
+        if previous.is_some() {
+            exclude::release(&client).await?;
         }
-        writer.flush()?;
 
         // Get count for logging
         let row = client.query_one("SELECT COUNT(*) FROM domains", &[]).await?;
         let count: i64 = row.get(0);
 
         if !silent {
-            eprintln!("Exported {} domains to {:?}", count, file);
+            info!(exported, "Exported {} domains to {:?}", human::count(exported), file);
+            if previous.is_some() {
+                info!(
+                    "Skipped {} domains listed in {}",
+                    human::count((count as u64).saturating_sub(exported)),
+                    flag
+                );
+            }
         }
+        if count == 0 {
+            db::hint_if_empty(&client, silent).await?;
+        }
+    } else if (format == "jsonl" || format == "parquet") && one_per_apex_rule.is_none() {
+        // Streamed from a cursor through the filters: memory stays flat on
+        // any corpus size
+        let mut out = BufWriter::with_capacity(FILE_BUFFER, File::create(&file)?);
+        let mut skip = Skip { wildcards: wildcards.as_ref(), previous: previous.as_ref(), ..Skip::default() };
+        let count = write_rows(&client, &mut out, &format, (&select, &params), &filter, &mut skip).await?;
+        let Skip { omitted, known, .. } = skip;
+        let known = previous.is_some().then_some((known, flag));
+        report_export(&client, &file, count, omitted, known, &format, minimize, silent).await?;
     } else {
-        let regex = if let Some(pattern) = &regex_filter {
-            Some(Regex::new(pattern)?)
-        } else {
-            None
-        };
-
-        let query = if sort {
-            "SELECT domain FROM domains ORDER BY domain"
-        } else {
-            "SELECT domain FROM domains"
-        };
-
-        let rows = client.query(query, &[]).await?;
+        let rows = db::query(&client, &select, &params).await?;
         let mut domains: Vec<String> = Vec::new();
+        // CSV: the sighting-time columns of each kept domain
+        let mut seen: HashMap<String, Seen> = HashMap::new();
+        let mut omitted = 0u64;
+        let mut known = 0u64;
 
         for row in rows {
             let domain: String = row.get(0);
-
-            if let Some(ref m) = match_filter {
-                if !domain.contains(m.as_str()) {
-                    continue;
-                }
+            if !filter.matches(&domain) {
+                continue;
             }
-
-            if let Some(ref re) = regex {
-                if !re.is_match(&domain) {
-                    continue;
+            if wildcards.as_ref().is_some_and(|w| covered_by_wildcard(&domain, w)) {
+                omitted += 1;
+            } else if previous.as_ref().is_some_and(|p| p.contains(&domain)) {
+                known += 1;
+            } else {
+                if csv {
+                    seen.insert(domain.clone(), (row.get(1), row.get(2)));
                 }
+                domains.push(domain);
             }
+        }
 
-            domains.push(domain);
+        if let Some(rule) = &one_per_apex_rule {
+            domains = one_per_apex(domains.iter().map(String::as_str), rule);
+            if sort {
+                domains.sort();
+            }
+        }
+        if canonical {
+            domains = canonical_order(domains);
         }
 
         let count = domains.len();
 
+        let mut out = FileSink::create(&file, wrap)?;
         if format == "json" {
             let export_data = ExportData {
                 domain_count: count,
                 exported_at: Utc::now().to_rfc3339(),
-                domains,
+                domains: if count == 0 && emit_null_for_empty { None } else { Some(domains) },
             };
-            let output = File::create(&file)?;
-            serde_json::to_writer_pretty(output, &export_data)?;
+            out.raw(&serde_json::to_vec_pretty(&export_data)?)?;
         } else {
-            let output = File::create(&file)?;
-            let mut writer = BufWriter::with_capacity(1024 * 1024, output);
+            if csv {
+                out.line(CSV_HEADER)?;
+            }
             for domain in &domains {
-                writeln!(writer, "{}", domain)?;
+                match format.as_str() {
+                    "csv" => out.line(&csv_line(domain, seen_columns(&seen, domain))?)?,
+                    "jsonl" => out.line(&jsonl_line(domain)?)?,
+                    _ => out.line(domain)?,
+                }
             }
-            writer.flush()?;
         }
+        out.finish()?;
 
-        if !silent {
-            eprintln!("Exported {} domains to {:?} ({} format)", count, file, format);
+        let known = previous.is_some().then_some((known, flag));
+        report_export(&client, &file, count as u64, omitted, known, &format, minimize, silent).await?;
+    }
+
+    db::unpin(&client).await?;
+
+    Ok(())
+}
+
+/// Stream the domains passing `filter` to `out` as `text`, `jsonl`, `csv`
+/// or `parquet` (in row groups), or buffered into one `json` document;
+/// returns how many were written
+pub async fn write_matching<W: Write>(
+    client: &db::Client,
+    out: &mut W,
+    format: &str,
+    filter: &DomainFilter,
+    sort: bool,
+) -> Result<u64> {
+    let (select, params) = ordered_select(filter, sort, None, format);
+    write_rows(client, out, format, (&select, &params), filter, &mut Skip::default()).await
+}
+
+/// Matching domains an export leaves out, counted as they are skipped:
+/// those under a stored wildcard (`--minimize`) and those already listed
+/// in the `--diff-against`/`--exclude-file` list
+#[derive(Default)]
+struct Skip<'a> {
+    wildcards: Option<&'a HashSet<String>>,
+    previous: Option<&'a HashSet<String>>,
+    omitted: u64,
+    known: u64,
+}
+
+impl Skip<'_> {
+    fn skips(&mut self, domain: &str) -> bool {
+        if self.wildcards.is_some_and(|w| covered_by_wildcard(domain, w)) {
+            self.omitted += 1;
+            true
+        } else if self.previous.is_some_and(|p| p.contains(domain)) {
+            self.known += 1;
+            true
+        } else {
+            false
         }
     }
+}
 
+/// Stream the rows of `select` through `filter` and `skip` into `out`,
+/// in the row order; returns how many were written
+async fn write_rows<W: Write>(
+    client: &db::Client,
+    out: &mut W,
+    format: &str,
+    (select, params): (&str, &[(&(dyn ToSql + Sync), Type)]),
+    filter: &DomainFilter,
+    skip: &mut Skip<'_>,
+) -> Result<u64> {
+    let stream = db::query_stream(client, select, params).await?;
+    let mut stream = pin!(stream);
+    if format == "csv" {
+        writeln!(out, "{}", CSV_HEADER)?;
+    }
+    let mut json = Vec::new();
+    #[cfg(feature = "parquet")]
+    let mut parquet = if format == "parquet" { Some(parquet::Writer::new()?) } else { None };
+    let mut count = 0u64;
+    while let Some(row) = stream.next().await {
+        let row = row?;
+        let domain: &str = row.get(0);
+        if !filter.matches(domain) || skip.skips(domain) {
+            continue;
+        }
+        match format {
+            "json" => json.push(domain.to_string()),
+            #[cfg(feature = "parquet")]
+            "parquet" => parquet.as_mut().expect("parquet writer").push(out, domain, row.get(1), row.get(2))?,
+            "csv" => writeln!(out, "{}", csv_line(domain, (row.get(1), row.get(2)))?)?,
+            "jsonl" => writeln!(out, "{}", jsonl_line(domain)?)?,
+            _ => writeln!(out, "{}", domain)?,
+        }
+        count += 1;
+    }
+    if format == "json" {
+        out.write_all(&json_document(json)?)?;
+        writeln!(out)?;
+    }
+    #[cfg(feature = "parquet")]
+    if let Some(parquet) = parquet {
+        parquet.finish(out)?;
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+/// Summary of a single-file export on stderr; `known` counts the domains
+/// skipped by `--diff-against`/`--exclude-file`, and names the flag
+#[allow(clippy::too_many_arguments)]
+async fn report_export(
+    client: &db::Client,
+    file: &Path,
+    count: u64,
+    omitted: u64,
+    known: Option<(u64, &str)>,
+    format: &str,
+    minimize: bool,
+    silent: bool,
+) -> Result<()> {
+    if !silent {
+        info!(exported = count, "Exported {} domains to {:?} ({} format)", human::count(count), file, format);
+        if minimize {
+            info!("Omitted {} domains covered by wildcards", human::count(omitted));
+        }
+        if let Some((known, flag)) = known {
+            info!("Skipped {} domains listed in {}", human::count(known), flag);
+        }
+    }
+    if count == 0 && omitted == 0 && known.map_or(0, |(known, _)| known) == 0 {
+        db::hint_if_empty(client, silent).await?;
+    }
+    Ok(())
+}
+
+/// Stream matching domains from a cursor into fixed-size numbered part files
+#[allow(clippy::too_many_arguments)]
+async fn run_partitioned(
+    client: &db::Client,
+    size: usize,
+    dir: &Path,
+    format: &str,
+    wrap: (Option<&str>, Option<&str>),
+    filter: &DomainFilter,
+    (select, params): (&str, &[(&(dyn ToSql + Sync), Type)]),
+    sort: bool,
+    one_per_apex_rule: Option<String>,
+    wildcards: Option<&HashSet<String>>,
+    previous: Option<(&HashSet<String>, &str)>,
+    silent: bool,
+) -> Result<()> {
+    if size == 0 {
+        bail!("--partition-size must be at least 1");
+    }
+    fs::create_dir_all(dir)?;
+
+    let csv = format == "csv";
+    let mut parts = PartSink::new(dir, size, part_format(format), wrap);
+    let mut count = 0u64;
+    let mut omitted = 0u64;
+    let mut known = 0u64;
+    let stream = db::query_stream(client, select, params).await?;
+    let mut stream = pin!(stream);
+
+    // One representative per apex needs the whole matching set first
+    let mut matched: Vec<String> = Vec::new();
+    let mut seen: HashMap<String, Seen> = HashMap::new();
+    while let Some(row) = stream.next().await {
+        let row = row?;
+        let domain: String = row.get(0);
+        if !filter.matches(&domain) {
+            continue;
+        }
+        if wildcards.is_some_and(|w| covered_by_wildcard(&domain, w)) {
+            omitted += 1;
+            continue;
+        }
+        if previous.is_some_and(|(p, _)| p.contains(&domain)) {
+            known += 1;
+            continue;
+        }
+        if one_per_apex_rule.is_some() {
+            if csv {
+                seen.insert(domain.clone(), (row.get(1), row.get(2)));
+            }
+            matched.push(domain);
+        } else {
+            let seen = if csv { (row.get(1), row.get(2)) } else { ("", "") };
+            parts.line(&part_line(format, &domain, seen)?)?;
+            count += 1;
+        }
+    }
+
+    if let Some(rule) = &one_per_apex_rule {
+        let mut reps = one_per_apex(matched.iter().map(String::as_str), rule);
+        if sort {
+            reps.sort();
+        }
+        for domain in reps {
+            parts.line(&part_line(format, &domain, seen_columns(&seen, &domain))?)?;
+            count += 1;
+        }
+    }
+    parts.finish()?;
+
+    if !silent {
+        info!(
+            exported = count,
+            parts = parts.parts(),
+            "Exported {} domains to {} parts in {:?} ({} format)",
+            human::count(count),
+            parts.parts(),
+            dir,
+            format
+        );
+        if wildcards.is_some() {
+            info!("Omitted {} domains covered by wildcards", human::count(omitted));
+        }
+        if let Some((_, flag)) = previous {
+            info!("Skipped {} domains listed in {}", human::count(known), flag);
+        }
+    }
+    if count == 0 && omitted == 0 && known == 0 {
+        db::hint_if_empty(client, silent).await?;
+    }
     Ok(())
 }
+
+/// What a part file gets for `domain`: the domain itself for text parts
+/// and buffered JSON, a rendered line for CSV and JSONL
+fn part_line(format: &str, domain: &str, seen: (&str, &str)) -> Result<String> {
+    Ok(match format {
+        "csv" => csv_line(domain, seen)?,
+        "jsonl" => jsonl_line(domain)?,
+        _ => domain.to_string(),
+    })
+}
+
+/// `--canonical`: every domain lowercased with its trailing root dot
+/// dropped, spellings that then coincide (`A.com`, `a.com.`) merged, and
+/// the result sorted by bytes, so the output depends on neither the
+/// server's collation nor the table's physical order
+fn canonical_order(domains: Vec<String>) -> Vec<String> {
+    let mut canonical: Vec<String> = domains.iter().map(|d| strip_trailing_dot(d).to_lowercase()).collect();
+    canonical.sort_unstable();
+    canonical.dedup();
+    canonical
+}
+
+fn jsonl_line(domain: &str) -> Result<String> {
+    Ok(serde_json::to_string(&JsonlRecord { domain })?)
+}
+
+/// A CSV row's `first_seen` and `last_seen`, as rendered by the server
+type Seen = (String, String);
+
+/// One CSV record: the domain, then its sighting times
+fn csv_line(domain: &str, (first_seen, last_seen): (&str, &str)) -> Result<String> {
+    csv::record([domain, first_seen, last_seen])
+}
+
+fn seen_columns<'a>(seen: &'a HashMap<String, Seen>, domain: &str) -> (&'a str, &'a str) {
+    seen.get(domain).map_or(("", ""), |(first, last)| (first.as_str(), last.as_str()))
+}
+
+/// Seed for `--shuffle` without `--seed`
+fn random_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0x9E37_79B9_7F4A_7C15)
+}
+
+/// The export query: filtered, then in domain order with `--sort`, or in a
+/// seeded pseudo-random order with `--shuffle`. Shuffling sorts by a hash of
+/// seed and domain server-side, so the same seed and data give the same
+/// permutation and the client only ever streams. For `csv` the sighting
+/// times follow the domain as a second column; for `parquet` they follow it
+/// as two columns of epoch microseconds.
+fn ordered_select<'a>(
+    filter: &'a DomainFilter,
+    sort: bool,
+    seed: Option<&'a String>,
+    format: &str,
+) -> (String, Vec<(&'a (dyn ToSql + Sync), Type)>) {
+    let mut params = filter.params();
+    let columns = match format {
+        "csv" => format!("domain, {}", CSV_SEEN_COLUMNS),
+        "parquet" => format!("domain, {}", PARQUET_SEEN_COLUMNS),
+        _ => "domain".to_string(),
+    };
+    let Some(seed) = seed else {
+        let order = if sort { " ORDER BY domain" } else { "" };
+        return (format!("SELECT {} FROM domains{}{}", columns, filter.where_clause(), order), params);
+    };
+    params.push((seed as &(dyn ToSql + Sync), Type::TEXT));
+    let select = format!(
+        "SELECT {} FROM domains{} ORDER BY md5(${} || domain), domain",
+        columns,
+        filter.where_clause(),
+        params.len()
+    );
+    (select, params)
+}
+
+/// `*.apex` entries that pass the export filter; only wildcards that end up
+/// in the output may stand in for the hosts below them
+async fn load_wildcards(
+    client: &db::Client,
+    match_filter: &[String],
+    regex_filter: &[String],
+    filter_logic: Option<&str>,
+    ignore_case: bool,
+    invert: bool,
+) -> Result<HashSet<String>> {
+    let mut filter = DomainFilter::from_flags(match_filter, regex_filter, filter_logic, ignore_case, invert)?;
+    filter.push("starts_with(domain, {})", "*.");
+    let rows = db::query(client, &filter.select(false), &filter.params()).await?;
+    Ok(rows
+        .iter()
+        .map(|row| row.get::<_, String>(0))
+        .filter(|domain| filter.matches(domain))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::scratch::Scratch;
+
+    fn export_text(domains: &[&str], name: &str) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("bountycatch-canonical-{}-{}.txt", std::process::id(), name));
+        let mut out = FileSink::create(&path, (None, None)).unwrap();
+        for domain in canonical_order(domains.iter().map(|d| d.to_string()).collect()) {
+            out.line(&domain).unwrap();
+        }
+        out.finish().unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_canonical_export_is_byte_identical() {
+        // The same set in a different physical order and spelling
+        let first = export_text(&["b.example.com", "A.example.com", "_dmarc.example.com", "a-b.example.com"], "first");
+        let second = export_text(&["a-b.example.com", "b.example.com.", "_dmarc.example.com", "a.example.com", "a.EXAMPLE.com"], "second");
+        assert_eq!(first, second);
+        // Byte order ('_' before letters, '-' before '.'), where a locale
+        // collation would ignore the punctuation
+        assert_eq!(first, b"_dmarc.example.com\na-b.example.com\na.example.com\nb.example.com\n");
+    }
+
+    #[tokio::test]
+    async fn test_run_orders_output() {
+        let scratch = Scratch::initialized("export_order").await;
+        let client = scratch.pool.get().await.unwrap();
+        client
+            .batch_execute(
+                "INSERT INTO domains (domain) VALUES ('c.example.com'), ('A.example.com'), \
+                 ('b.example.com.'), ('a.example.com'), ('_dmarc.example.com')",
+            )
+            .await
+            .unwrap();
+        let export = |name: &str, opts: FileExportOptions| {
+            let path = std::env::temp_dir().join(format!("bountycatch-order-{}-{}", std::process::id(), name));
+            let pool = &scratch.pool;
+            async move {
+                run(pool, Some(path.clone()), None, FileExportOptions { silent: true, ..opts }).await.unwrap();
+                let text = fs::read_to_string(&path).unwrap();
+                fs::remove_file(&path).unwrap();
+                text
+            }
+        };
+
+        let canonical = export("canonical", FileExportOptions { canonical: true, ..Default::default() }).await;
+        assert_eq!(canonical, "_dmarc.example.com\na.example.com\nb.example.com\nc.example.com\n");
+
+        // --sort keeps each stored spelling, in the server's collation
+        let sorted = export("sorted", FileExportOptions { sort: true, ..Default::default() }).await;
+        let row = client
+            .query_one("SELECT string_agg(domain, E'\\n' ORDER BY domain) || E'\\n' FROM domains", &[])
+            .await
+            .unwrap();
+        assert_eq!(sorted, row.get::<_, String>(0));
+
+        // --shuffle with a seed reproduces its order
+        let shuffled = || FileExportOptions { shuffle: true, seed: Some(7), ..Default::default() };
+        let first = export("shuffled", shuffled()).await;
+        assert_eq!(export("shuffled", shuffled()).await, first);
+        let mut lines: Vec<&str> = first.lines().collect();
+        lines.sort_unstable();
+        let mut stored: Vec<&str> = sorted.lines().collect();
+        stored.sort_unstable();
+        assert_eq!(lines, stored);
+
+        drop(client);
+        scratch.drop().await;
+    }
+}
@@ -0,0 +1,136 @@
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::config::PostgresConfig;
+use crate::db::{self, Pool};
+
+/// Optional columns newer features may add to the `domains` table
+const FEATURE_COLUMNS: &[&str] = &["first_seen", "last_seen", "program", "source", "tags"];
+
+#[derive(Serialize)]
+struct InfoOutput {
+    target: String,
+    server_version: String,
+    schema: String,
+    table: String,
+    persistence: String,
+    columns: Vec<ColumnFeature>,
+    row_count: i64,
+    row_count_estimated: bool,
+    indexes: Vec<IndexInfo>,
+}
+
+#[derive(Serialize)]
+struct ColumnFeature {
+    name: String,
+    present: bool,
+}
+
+#[derive(Serialize)]
+struct IndexInfo {
+    name: String,
+    definition: String,
+}
+
+pub async fn run(pool: &Pool, config: &PostgresConfig, format: String, silent: bool) -> Result<()> {
+    let client = pool.get().await?;
+    db::pin(&client).await?;
+    // The row count may be stale, or block, while an import runs
+    db::check_import(&client, false, silent).await?;
+
+    let row = client.query_one("SHOW server_version", &[]).await?;
+    let server_version: String = row.get(0);
+
+    let row = client.query_one("SELECT current_schema()", &[]).await?;
+    let schema: String = row.get(0);
+
+    // Feature detection: which optional columns exist on the table
+    let rows = client
+        .query(
+            "SELECT column_name::text FROM information_schema.columns \
+             WHERE table_schema = current_schema() AND table_name = 'domains'",
+            &[],
+        )
+        .await?;
+    let present: Vec<String> = rows.iter().map(|r| r.get(0)).collect();
+    let columns = FEATURE_COLUMNS
+        .iter()
+        .map(|c| ColumnFeature {
+            name: c.to_string(),
+            present: present.iter().any(|p| p == c),
+        })
+        .collect();
+
+    // Planner estimate is instant on huge tables; fall back to an exact
+    // count when the table has never been analyzed
+    let row = client
+        .query_one(
+            "SELECT reltuples::bigint, relpersistence = 'u' FROM pg_class \
+             WHERE oid = 'domains'::regclass",
+            &[],
+        )
+        .await?;
+    let estimate: i64 = row.get(0);
+    let unlogged: bool = row.get(1);
+    let (row_count, row_count_estimated) = if estimate >= 0 {
+        (estimate, true)
+    } else {
+        let row = client.query_one("SELECT COUNT(*) FROM domains", &[]).await?;
+        (row.get(0), false)
+    };
+
+    let rows = client
+        .query(
+            "SELECT indexname::text, indexdef FROM pg_indexes \
+             WHERE schemaname = current_schema() AND tablename = 'domains' \
+             ORDER BY indexname",
+            &[],
+        )
+        .await?;
+    let indexes: Vec<IndexInfo> = rows
+        .iter()
+        .map(|r| IndexInfo {
+            name: r.get(0),
+            definition: r.get(1),
+        })
+        .collect();
+    db::unpin(&client).await?;
+
+    let output = InfoOutput {
+        target: config.redacted_target(),
+        server_version,
+        schema,
+        table: "domains".to_string(),
+        persistence: if unlogged { "unlogged" } else { "logged" }.to_string(),
+        columns,
+        row_count,
+        row_count_estimated,
+        indexes,
+    };
+
+    if format == "json" {
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(());
+    }
+
+    println!("Target:         {}", output.target);
+    println!("Server version: {}", output.server_version);
+    println!("Table:          {}.{} ({})", output.schema, output.table, output.persistence);
+    let features: Vec<String> = output
+        .columns
+        .iter()
+        .map(|c| format!("{}={}", c.name, if c.present { "present" } else { "absent" }))
+        .collect();
+    println!("Columns:        {}", features.join(", "));
+    if output.row_count_estimated {
+        println!("Rows:           ~{} (estimate)", output.row_count);
+    } else {
+        println!("Rows:           {}", output.row_count);
+    }
+    println!("Indexes:");
+    for idx in &output.indexes {
+        println!("  {} - {}", idx.name, idx.definition);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use anyhow::{bail, Result};
+use std::io::{self, BufRead, IsTerminal};
+use std::process::Command;
+use tracing::info;
+
+use crate::config::PostgresConfig;
+use crate::keyring;
+
+/// Read one line from stdin, with echo turned off when it is a terminal
+fn read_password(prompt: &str) -> Result<String> {
+    let interactive = io::stdin().is_terminal();
+    if interactive {
+        eprint!("{}", prompt);
+        let _ = Command::new("stty").arg("-echo").status();
+    }
+    let mut line = String::new();
+    let read = io::stdin().lock().read_line(&mut line);
+    if interactive {
+        let _ = Command::new("stty").arg("echo").status();
+        eprintln!();
+    }
+    read?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+pub fn run(config: &PostgresConfig, action: &str, silent: bool) -> Result<()> {
+    let account = keyring::account(config);
+    match action {
+        "set" => {
+            let password = read_password(&format!("Password for {}: ", account))?;
+            if password.is_empty() {
+                bail!("Empty password; nothing stored");
+            }
+            keyring::set(&account, &password)?;
+            if !silent {
+                info!("Stored password for {} in the keyring", account);
+                if config.password_source != "keyring" {
+                    info!("Set \"password_source\": \"keyring\" under postgresql in the config to use it");
+                }
+            }
+        }
+        _ => {
+            keyring::delete(&account)?;
+            if !silent {
+                info!("Removed keyring password for {}", account);
+            }
+        }
+    }
+    Ok(())
+}
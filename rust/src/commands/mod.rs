@@ -1,6 +1,21 @@
 pub mod add;
+pub mod apex;
 pub mod print;
 pub mod count;
 pub mod export;
 pub mod remove;
+pub mod diff;
 pub mod delete_all;
+pub mod info;
+pub mod dedupe;
+pub mod stats;
+pub mod version;
+pub mod normalize;
+pub mod table_mode;
+pub mod tree;
+pub mod query;
+pub mod projects;
+pub mod verify_scope;
+pub mod doctor;
+#[cfg(feature = "keyring")]
+pub mod keyring;
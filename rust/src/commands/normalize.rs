@@ -0,0 +1,134 @@
+use anyhow::Result;
+use std::collections::HashSet;
+use tracing::info;
+
+use crate::db::{self, Pool};
+use crate::domain::{is_valid_domain, normalize_host, to_idna_ascii};
+use crate::human;
+use crate::progress;
+
+const BATCH_SIZE: i64 = 10_000;
+
+/// Canonical form of a stored entry: URL reduced to its host, trailing dot
+/// stripped, IDNA-encoded and lowercased. `None` when the result is not a
+/// valid domain.
+fn normalize(entry: &str) -> Option<String> {
+    let normalized = to_idna_ascii(&normalize_host(entry.trim()))?.to_lowercase();
+    is_valid_domain(&normalized).then_some(normalized)
+}
+
+/// Rewrite the table in key order, one transaction per batch. Normalized
+/// values are fixed points, so an interrupted run can simply be rerun.
+pub async fn run(pool: &Pool, dry_run: bool, drop_invalid: bool, silent: bool) -> Result<()> {
+    let mut client = pool.get().await?;
+    progress::install_signal_handler();
+    progress::set_phase("normalizing");
+
+    let row = client.query_one("SELECT COUNT(*) FROM domains", &[]).await?;
+    let scanned: i64 = row.get(0);
+
+    let mut cursor = String::new();
+    let mut rewritten = 0u64;
+    let mut merged = 0u64;
+    let mut invalid = 0u64;
+    // Dry run only: targets produced so far, to count merges between rewrites
+    let mut produced: HashSet<String> = HashSet::new();
+
+    loop {
+        let tx = client.transaction().await?;
+        let rows = tx
+            .query(
+                "SELECT domain FROM domains WHERE domain > $1 ORDER BY domain LIMIT $2",
+                &[&cursor, &BATCH_SIZE],
+            )
+            .await?;
+        let Some(last) = rows.last() else {
+            break;
+        };
+        // Rewritten rows that sort after the cursor are visited again, but
+        // are already normal and left alone
+        cursor = last.get(0);
+        progress::add_lines(rows.len() as u64);
+
+        let mut old: Vec<String> = Vec::new();
+        let mut new: Vec<String> = Vec::new();
+        let mut bad: Vec<String> = Vec::new();
+        for row in &rows {
+            let domain: String = row.get(0);
+            match normalize(&domain) {
+                Some(n) if n == domain => {}
+                Some(n) => {
+                    if dry_run {
+                        println!("{} -> {}", domain, n);
+                    }
+                    old.push(domain);
+                    new.push(n);
+                }
+                None => {
+                    if dry_run {
+                        println!("{} -> (invalid)", domain);
+                    }
+                    bad.push(domain);
+                }
+            }
+        }
+        rewritten += old.len() as u64;
+        invalid += bad.len() as u64;
+
+        if dry_run {
+            let existing = tx
+                .query("SELECT domain FROM domains WHERE domain = ANY($1)", &[&new])
+                .await?;
+            let existing: HashSet<String> = existing.iter().map(|r| r.get(0)).collect();
+            for n in new {
+                if existing.contains(&n) || !produced.insert(n) {
+                    merged += 1;
+                }
+            }
+            continue;
+        }
+
+        merged += db::rename_domains(&tx, &old, &new).await?;
+        let mut written = old.len() as u64;
+        if drop_invalid {
+            tx.execute("DELETE FROM domains WHERE domain = ANY($1)", &[&bad]).await?;
+            written += bad.len() as u64;
+        }
+        tx.commit().await?;
+        progress::add_written(written);
+    }
+
+    let verb = if dry_run { "would be " } else { "" };
+    let dropped = if drop_invalid { invalid } else { 0 };
+    if !silent {
+        info!("{} rows scanned", scanned);
+        info!("{} rows {}rewritten ({} merged into existing entries)", rewritten, verb, merged);
+        if drop_invalid {
+            info!("{} invalid rows {}removed", invalid, verb);
+        } else {
+            info!("{} invalid rows kept (use --drop-invalid to remove them)", invalid);
+        }
+        info!("{} rows {}removed in total", merged + dropped, verb);
+    }
+
+    if !silent && !dry_run {
+        let row = client.query_one("SELECT COUNT(*) FROM domains", &[]).await?;
+        let after: i64 = row.get(0);
+        info!("{} domains after normalization", human::count(after as u64));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("https://API.Example.com./x").as_deref(), Some("api.example.com"));
+        assert_eq!(normalize("bücher.example").as_deref(), Some("xn--bcher-kva.example"));
+        assert_eq!(normalize("example.com").as_deref(), Some("example.com"));
+        assert_eq!(normalize("not a domain"), None);
+    }
+}
@@ -1,71 +1,581 @@
-use anyhow::Result;
-use deadpool_postgres::Pool;
+use anyhow::{bail, Context, Result};
 use futures_util::StreamExt;
-use regex::Regex;
-use std::io::{self, Write};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::pin::pin;
+use tokio_postgres::types::Type;
+use tracing::info;
 
-pub async fn run(
-    pool: &Pool,
-    match_filter: Option<String>,
-    regex_filter: Option<String>,
-    sort: bool,
-    silent: bool,
-) -> Result<()> {
+use crate::csv;
+use crate::db::{self, check_row_cap, Pool};
+use crate::domain::{strip_comment, strip_trailing_dot, ApexReps};
+use crate::exclude;
+use crate::filter::DomainFilter;
+use crate::human;
+use crate::output::{OutputSink, StdoutSink};
+use crate::resolve::{resolve_all, Lookup, ResolveOptions};
+
+/// Indices of the first `first` and last `last` items of a `len`-long
+/// list, without repeating items when the two ranges overlap. With
+/// neither set, every index is returned.
+fn head_tail_indices(len: usize, first: Option<usize>, last: Option<usize>) -> Vec<usize> {
+    if first.is_none() && last.is_none() {
+        return (0..len).collect();
+    }
+    let head = first.unwrap_or(0).min(len);
+    let tail_start = len - last.unwrap_or(0).min(len);
+    if tail_start <= head {
+        return (0..len).collect();
+    }
+    (0..head).chain(tail_start..len).collect()
+}
+
+/// Where the `index`th (0-based) selected domain falls against
+/// `--offset`/`--limit`
+#[derive(Debug, PartialEq)]
+enum Page {
+    Before,
+    Inside,
+    After,
+}
+
+fn page(index: usize, offset: usize, limit: Option<usize>) -> Page {
+    if index < offset {
+        Page::Before
+    } else if limit.is_some_and(|limit| index - offset >= limit) {
+        Page::After
+    } else {
+        Page::Inside
+    }
+}
+
+/// `--limit`/`--offset` as SQL, for queries whose rows all get printed
+fn sql_page(offset: usize, limit: Option<usize>) -> String {
+    let mut clause = String::new();
+    if let Some(limit) = limit {
+        clause.push_str(&format!(" LIMIT {}", limit));
+    }
+    if offset > 0 {
+        clause.push_str(&format!(" OFFSET {}", offset));
+    }
+    clause
+}
+
+/// Where selected domains go: straight to the output, or collected so
+/// `--resolve` can look them up before printing
+struct Destination {
+    out: Box<dyn OutputSink>,
+    collected: Option<Vec<String>>,
+}
+
+impl Destination {
+    fn emit(&mut self, domain: &str) -> Result<()> {
+        match &mut self.collected {
+            Some(domains) => {
+                domains.push(domain.to_string());
+                Ok(())
+            }
+            None => self.out.line(domain),
+        }
+    }
+}
+
+/// Domains listed in `path`, one per line, skipping blanks and comments
+fn read_domain_list(path: &Path) -> Result<Vec<String>> {
+    let content = fs::read_to_string(path).with_context(|| format!("Failed to read file: {:?}", path))?;
+    Ok(content
+        .lines()
+        .filter_map(strip_comment)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Columns `print --columns` can select, with the SQL that renders each as text
+const COLUMNS: &[(&str, &str)] = &[
+    ("domain", "domain"),
+    ("program", "program"),
+    ("source", "source"),
+    ("tags", "array_to_string(tags, ',')"),
+    ("first_seen", "to_char(first_seen AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"')"),
+    ("last_seen", "to_char(last_seen AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS\"Z\"')"),
+];
+
+/// `print --columns` / `--format csv|json`: which columns, and how to write them
+pub struct Projection {
+    pub columns: Vec<String>,
+    /// `text`, `csv` or `json`
+    pub format: String,
+}
+
+/// One element of `print --format json`: the bare domain, or an object
+/// with every selected column (`null` where a row has no value)
+fn json_element(columns: &[String], domain: &str, fields: &[Option<String>]) -> Result<String> {
+    if columns.len() == 1 && columns[0] == "domain" {
+        return Ok(serde_json::to_string(domain)?);
+    }
+    // Keys in --columns order, which serde_json's map would sort
+    let pairs = columns
+        .iter()
+        .zip(fields)
+        .map(|(column, field)| Ok(format!("{}:{}", serde_json::to_string(column)?, serde_json::to_string(field)?)))
+        .collect::<Result<Vec<String>>>()?;
+    Ok(format!("{{{}}}", pairs.join(",")))
+}
+
+/// Check the requested columns against the known set and the live table,
+/// returning the SELECT list
+async fn projection_select(client: &db::Client, columns: &[String]) -> Result<Vec<&'static str>> {
+    let rows = client
+        .query(
+            "SELECT column_name::text FROM information_schema.columns \
+             WHERE table_schema = current_schema() AND table_name = 'domains'",
+            &[],
+        )
+        .await?;
+    let present: Vec<String> = rows.iter().map(|r| r.get(0)).collect();
+
+    let mut exprs = Vec::with_capacity(columns.len());
+    for column in columns {
+        let Some((name, expr)) = COLUMNS.iter().find(|(name, _)| *name == column.as_str()) else {
+            let known: Vec<&str> = COLUMNS.iter().map(|(name, _)| *name).collect();
+            bail!("Unknown column '{}' (available: {})", column, known.join(", "));
+        };
+        if !present.iter().any(|p| p == name) {
+            bail!(
+                "Column '{}' does not exist in this database yet; it is added by `add --{}`",
+                name,
+                if *name == "tags" { "tag" } else { name }
+            );
+        }
+        exprs.push(*expr);
+    }
+    Ok(exprs)
+}
+
+fn format_ips(ips: &[IpAddr]) -> String {
+    ips.iter().map(IpAddr::to_string).collect::<Vec<_>>().join(",")
+}
+
+/// What `print` selects and how it is written
+#[derive(Default)]
+pub struct PrintOptions {
+    /// Only print stored domains listed in this file
+    pub file: Option<PathBuf>,
+    /// Leave out the domains listed in this file
+    pub exclude_file: Option<PathBuf>,
+    pub match_filter: Vec<String>,
+    pub regex_filter: Vec<String>,
+    /// `and` or `or`
+    pub filter_logic: Option<String>,
+    pub ignore_case: bool,
+    /// Print the domains that do not match instead
+    pub invert: bool,
+    /// Whole dot-delimited labels the domains must have
+    pub labels: Vec<String>,
+    /// Any one of `labels` is enough
+    pub any_label: bool,
+    /// Only domains first seen within this duration
+    pub since: Option<String>,
+    pub sort: bool,
+    /// `--representative` rule when printing one host per apex
+    pub one_per_apex_rule: Option<String>,
+    pub first: Option<usize>,
+    pub last: Option<usize>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    /// Abort a client-side filtered read of more rows than this
+    pub row_cap: Option<u64>,
+    /// Wait for an in-progress import instead of warning
+    pub wait: bool,
+    /// Only print domains that resolve
+    pub resolve: Option<ResolveOptions>,
+    /// `--columns` / `--format`
+    pub projection: Option<Projection>,
+    /// `--prefix` and `--suffix`
+    pub wrap: (Option<String>, Option<String>),
+    /// Only report errors
+    pub silent: bool,
+}
+
+pub async fn run(pool: &Pool, opts: PrintOptions) -> Result<()> {
+    let PrintOptions {
+        file, exclude_file, match_filter, regex_filter, filter_logic, ignore_case, invert, labels, any_label, since,
+        sort, one_per_apex_rule, first, last, limit, offset, row_cap, wait, resolve, projection, wrap, silent,
+    } = opts;
     let client = pool.get().await?;
-    let stdout = io::stdout();
-    let mut handle = stdout.lock();
+    db::pin(&client).await?;
+    db::check_import(&client, wait, silent).await?;
+    let mut sink = Destination {
+        out: Box::new(StdoutSink::new((wrap.0.as_deref(), wrap.1.as_deref()))),
+        collected: resolve.as_ref().map(|_| Vec::new()),
+    };
 
-    // Use fast COPY when no filters are applied
-    if match_filter.is_none() && regex_filter.is_none() && !sort {
-        let reader = client
-            .copy_out("COPY domains (domain) TO STDOUT")
-            .await?;
+    // --first/--last always work on the sorted corpus
+    let head_tail = first.is_some() || last.is_some();
+    let sort = sort || head_tail;
+    let mut filter =
+        DomainFilter::from_flags(&match_filter, &regex_filter, filter_logic.as_deref(), ignore_case, invert)?;
+    filter.push_labels(&labels, any_label);
+    if let Some(since) = &since {
+        filter.push_since(since)?;
+    }
+    if let Some(path) = &file {
+        let listed = read_domain_list(path)?;
+        filter.push("domain = ANY(string_to_array({}, E'\\n'))", &listed.join("\n"));
+    }
+    let unfiltered = filter.is_empty() && one_per_apex_rule.is_none();
+    let excluded = exclude_file.as_deref().map(|path| exclude::load(path, "--exclude-file")).transpose()?;
+    if let (Some(excluded), Some(path), false) = (&excluded, &exclude_file, silent) {
+        info!("Loaded {} domains from {:?}", human::count(excluded.len() as u64), path);
+    }
+    let keep = |domain: &str| !excluded.as_ref().is_some_and(|e| e.contains(domain));
+
+    // --limit/--offset go into SQL when SQL decides every printed row;
+    // otherwise they count the rows that survive the client-side checks
+    let paged = limit.is_some() || offset.is_some();
+    let nothing_found = match offset {
+        Some(n) if n > 0 => format!("No matching domains past --offset {}", n),
+        _ => "No matching domains found".to_string(),
+    };
+    let sql_paged = paged && !filter.has_residual() && excluded.is_none() && one_per_apex_rule.is_none();
+    let page_sql = if sql_paged { sql_page(offset.unwrap_or(0), limit) } else { String::new() };
+    let (offset, limit) = if sql_paged { (0, None) } else { (offset.unwrap_or(0), limit) };
+
+    if let Some(projection) = &projection {
+        let exprs = projection_select(&client, &projection.columns).await?;
+        if filter.has_residual() {
+            check_row_cap(&client, &filter, row_cap).await?;
+        }
+        // The domain always comes first, for the client-side filter
+        let order = if sort { " ORDER BY domain" } else { "" };
+        let query = format!(
+            "SELECT domain, {} FROM domains{}{}{}",
+            exprs.join(", "),
+            filter.where_clause(),
+            order,
+            page_sql
+        );
+        let rows = db::query_stream(&client, &query, &filter.params()).await?;
+        let mut rows = pin!(rows);
+
+        let (csv, json) = (projection.format == "csv", projection.format == "json");
+        if csv {
+            sink.out.line(&csv::record(&projection.columns)?)?;
+        }
+        // JSON: one array, written element by element as rows arrive; each
+        // waits for the next to know whether it needs a comma
+        let mut element: Option<String> = None;
+        let mut found_any = false;
+        let mut index = 0;
+        while let Some(row) = rows.next().await {
+            let row = row?;
+            let domain: &str = row.get(0);
+            if !filter.matches(domain) || !keep(domain) {
+                continue;
+            }
+            index += 1;
+            match page(index - 1, offset, limit) {
+                Page::Before => continue,
+                Page::After => break,
+                Page::Inside => found_any = true,
+            }
+            let fields: Vec<Option<String>> = (1..=exprs.len()).map(|i| row.get(i)).collect();
+            if json {
+                match element.replace(json_element(&projection.columns, domain, &fields)?) {
+                    Some(previous) => sink.out.line(&format!("  {},", previous))?,
+                    None => sink.out.line("[")?,
+                }
+                continue;
+            }
+            let fields = fields.into_iter().map(Option::unwrap_or_default);
+            if csv {
+                sink.out.line(&csv::record(fields)?)?;
+            } else {
+                sink.out.line(&fields.collect::<Vec<String>>().join("\t"))?;
+            }
+        }
+        if json {
+            match element {
+                Some(last) => {
+                    sink.out.line(&format!("  {}", last))?;
+                    sink.out.line("]")?;
+                }
+                None => sink.out.line("[]")?,
+            }
+        }
+        sink.out.finish()?;
+        if !found_any && !db::hint_if_empty(&client, silent).await? && !silent {
+            info!("{}", nothing_found);
+        }
+        db::unpin(&client).await?;
+        return Ok(());
+    }
+
+    // Use fast COPY when no filters are applied; --exclude-file is
+    // anti-joined server-side
+    if unfiltered && !sort && !paged && resolve.is_none() {
+        let copy = match &excluded {
+            Some(excluded) => {
+                exclude::load_table(&client, excluded).await?;
+                exclude::COPY_OUT
+            }
+            None => "COPY domains (domain) TO STDOUT",
+        };
+        let reader = client.copy_out(copy).await?;
         
         let mut pinned = pin!(reader);
+        let mut copied = 0usize;
         while let Some(chunk) = pinned.next().await {
             let data = chunk?;
-            handle.write_all(&data)?;
+            copied += data.len();
+            sink.out.raw(&data)?;
+        }
+        if excluded.is_some() {
+            exclude::release(&client).await?;
+        }
+        if copied == 0 {
+            db::hint_if_empty(&client, silent).await?;
+        }
+    } else if unfiltered && head_tail && excluded.is_none() {
+        // Lexicographic extremes straight off the ordered index
+        let mut lines: Vec<String> = Vec::new();
+        if let Some(n) = first {
+            let limit = n as i64;
+            let rows = db::query(
+                &client,
+                "SELECT domain FROM domains ORDER BY domain LIMIT $1",
+                &[(&limit, Type::INT8)],
+            )
+            .await?;
+            lines.extend(rows.iter().map(|r| r.get::<_, String>(0)));
+        }
+        if let Some(n) = last {
+            // Fetch the tail descending, then restore ascending order
+            let limit = n as i64;
+            let rows = db::query(
+                &client,
+                "SELECT domain FROM (SELECT domain FROM domains ORDER BY domain DESC LIMIT $1) t \
+                 ORDER BY domain",
+                &[(&limit, Type::INT8)],
+            )
+            .await?;
+            for row in &rows {
+                let domain: String = row.get(0);
+                // Skip overlap with the head when the corpus is small
+                if !lines.contains(&domain) {
+                    lines.push(domain);
+                }
+            }
         }
-    } else {
-        let regex = if let Some(pattern) = &regex_filter {
-            Some(Regex::new(pattern)?)
-        } else {
-            None
-        };
 
-        let query = if sort {
-            "SELECT domain FROM domains ORDER BY domain"
-        } else {
-            "SELECT domain FROM domains"
-        };
+        for domain in &lines {
+            sink.emit(domain)?;
+        }
+        if lines.is_empty() {
+            db::hint_if_empty(&client, silent).await?;
+        }
+    } else {
+        // Only rows the client filters out count against the cap; a read
+        // filtered entirely in SQL streams just the matches
+        if filter.has_residual() {
+            check_row_cap(&client, &filter, row_cap).await?;
+        }
 
-        let rows = client.query(query, &[]).await?;
+        // Streamed, so only one host per apex (--one-per-apex) or the
+        // matches --first/--last pick from are held in memory
+        let query = format!("{}{}", filter.select(sort), page_sql);
+        let rows = db::query_stream(&client, &query, &filter.params()).await?;
+        let mut rows = pin!(rows);
         let mut found_any = false;
+        let mut reps = one_per_apex_rule.as_deref().map(ApexReps::new);
+        let mut matched: Vec<String> = Vec::new();
+        let mut index = 0;
 
-        for row in rows {
+        while let Some(row) = rows.next().await {
+            let row = row?;
             let domain: &str = row.get(0);
+            if !filter.matches(domain) || !keep(domain) {
+                continue;
+            }
 
-            if let Some(ref m) = match_filter {
-                if !domain.contains(m.as_str()) {
-                    continue;
+            if let Some(reps) = &mut reps {
+                found_any = true;
+                reps.push(domain);
+                continue;
+            }
+            if head_tail {
+                found_any = true;
+                matched.push(domain.to_string());
+                continue;
+            }
+            index += 1;
+            match page(index - 1, offset, limit) {
+                Page::Before => {}
+                Page::After => break,
+                Page::Inside => {
+                    found_any = true;
+                    sink.emit(domain)?;
                 }
             }
+        }
 
-            if let Some(ref re) = regex {
-                if !re.is_match(domain) {
-                    continue;
+        if reps.is_some() || head_tail {
+            let lines: Vec<String> = match reps {
+                Some(reps) => {
+                    let mut reps = reps.into_sorted();
+                    if sort {
+                        reps.sort();
+                    }
+                    reps
+                }
+                None => matched,
+            };
+            for (index, i) in head_tail_indices(lines.len(), first, last).into_iter().enumerate() {
+                match page(index, offset, limit) {
+                    Page::Before => {}
+                    Page::After => break,
+                    Page::Inside => sink.emit(&lines[i])?,
                 }
             }
+        }
 
-            found_any = true;
-            writeln!(handle, "{}", domain)?;
+        if !found_any && !db::hint_if_empty(&client, silent).await? && !silent {
+            info!("{}", nothing_found);
         }
+    }
+
+    db::unpin(&client).await?;
 
-        if !found_any && !silent {
-            eprintln!("No domains found in database");
+    if let (Some(opts), Some(domains)) = (&resolve, sink.collected.take()) {
+        let mut retry = match &opts.retry_file {
+            Some(path) => Some(BufWriter::new(
+                File::create(path).with_context(|| format!("Failed to create retry file: {:?}", path))?,
+            )),
+            None => None,
+        };
+        let mut retried = 0u64;
+
+        let mut results = pin!(resolve_all(domains, opts.jobs));
+        while let Some((domain, lookup)) = results.next().await {
+            if let (Lookup::Failed, Some(writer)) = (&lookup, &mut retry) {
+                writeln!(writer, "{}", domain)?;
+                retried += 1;
+            }
+            let ips = lookup.ips();
+            if ips.is_empty() && !opts.keep_unresolved {
+                continue;
+            }
+            if opts.show_ip {
+                sink.out.line(&format!("{}\t{}", domain, format_ips(ips)))?;
+            } else {
+                sink.out.line(&domain)?;
+            }
+        }
+
+        if let (Some(mut writer), Some(path)) = (retry, &opts.retry_file) {
+            writer.flush()?;
+            if !silent {
+                info!("Wrote {} timed-out or failed lookups to {:?}", human::count(retried), path);
+            }
         }
     }
+    sink.out.finish()?;
 
     Ok(())
 }
+
+/// The wildcard entries that would cover `host`: `*.` followed by each of
+/// its proper suffixes, most specific first. A wildcard covers the names
+/// strictly below it, so `*.example.com` covers `api.example.com` but not
+/// `example.com`; a wildcard `host` is also covered by itself.
+fn covering_wildcards(host: &str) -> Vec<String> {
+    let host = strip_trailing_dot(host.trim()).to_ascii_lowercase();
+    let mut candidates = Vec::new();
+    let mut rest = host.as_str();
+    if let Some(below) = host.strip_prefix("*.") {
+        candidates.push(host.clone());
+        rest = below;
+    }
+    while let Some((_, parent)) = rest.split_once('.') {
+        if !parent.is_empty() {
+            candidates.push(format!("*.{}", parent));
+        }
+        rest = parent;
+    }
+    candidates
+}
+
+/// Print the stored `*.` entries covering `host`, most specific first
+pub async fn wildcards_covering(pool: &Pool, host: &str, silent: bool) -> Result<()> {
+    let candidates = covering_wildcards(host);
+    let client = pool.get().await?;
+    db::pin(&client).await?;
+    // Exact lookups of the candidates use the primary key index
+    let rows = db::query(
+        &client,
+        "SELECT domain FROM domains WHERE domain = ANY($1) ORDER BY length(domain) DESC",
+        &[(&candidates, Type::TEXT_ARRAY)],
+    )
+    .await?;
+    db::unpin(&client).await?;
+    let mut out = StdoutSink::new((None, None));
+    for row in &rows {
+        out.line(row.get(0))?;
+    }
+    out.finish()?;
+    if rows.is_empty() && !silent {
+        info!("No stored wildcard covers {}", host);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_element() {
+        let domain_only = vec!["domain".to_string()];
+        let field = vec![Some("a\"b.example.com".to_string())];
+        assert_eq!(json_element(&domain_only, "a\"b.example.com", &field).unwrap(), r#""a\"b.example.com""#);
+
+        let columns = vec!["domain".to_string(), "program".to_string(), "tags".to_string()];
+        let fields = vec![Some("a.example.com".to_string()), Some("acme".to_string()), None];
+        assert_eq!(
+            json_element(&columns, "a.example.com", &fields).unwrap(),
+            r#"{"domain":"a.example.com","program":"acme","tags":null}"#
+        );
+    }
+
+    #[test]
+    fn test_page() {
+        assert_eq!(page(0, 0, None), Page::Inside);
+        assert_eq!(page(1, 2, Some(3)), Page::Before);
+        assert_eq!(page(2, 2, Some(3)), Page::Inside);
+        assert_eq!(page(4, 2, Some(3)), Page::Inside);
+        assert_eq!(page(5, 2, Some(3)), Page::After);
+        assert_eq!(page(0, 0, Some(0)), Page::After);
+        assert_eq!(sql_page(0, Some(10)), " LIMIT 10");
+        assert_eq!(sql_page(20, Some(10)), " LIMIT 10 OFFSET 20");
+        assert_eq!(sql_page(20, None), " OFFSET 20");
+    }
+
+    #[test]
+    fn test_head_tail_indices() {
+        assert_eq!(head_tail_indices(10, Some(2), None), vec![0, 1]);
+        assert_eq!(head_tail_indices(10, None, Some(2)), vec![8, 9]);
+        assert_eq!(head_tail_indices(10, Some(2), Some(3)), vec![0, 1, 7, 8, 9]);
+        assert_eq!(head_tail_indices(4, Some(3), Some(3)), vec![0, 1, 2, 3]);
+        assert_eq!(head_tail_indices(3, None, None), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_covering_wildcards() {
+        assert_eq!(covering_wildcards("api.dev.Example.com."), ["*.dev.example.com", "*.example.com", "*.com"]);
+        assert_eq!(covering_wildcards("*.dev.example.com"), ["*.dev.example.com", "*.example.com", "*.com"]);
+        assert_eq!(covering_wildcards("localhost"), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,35 @@
+use anyhow::Result;
+
+use crate::db::{Pool, DEFAULT_PROJECT, PROJECT_SCHEMA_PREFIX};
+
+/// Every project that has a domains table with its domain count, the
+/// default project first
+pub async fn run(pool: &Pool) -> Result<Vec<(String, i64)>> {
+    let client = pool.get().await?;
+
+    // Schema-qualified lookups: the connection's search_path only covers
+    // the active project. The default project is the one in `public`.
+    let rows = client
+        .query(
+            "SELECT schemaname::text FROM pg_tables WHERE tablename = 'domains' \
+             AND (schemaname = 'public' OR starts_with(schemaname, $1)) ORDER BY schemaname",
+            &[&PROJECT_SCHEMA_PREFIX],
+        )
+        .await?;
+
+    let mut projects: Vec<(String, i64)> = Vec::with_capacity(rows.len());
+    for row in &rows {
+        let schema: String = row.get(0);
+        let project = match schema.strip_prefix(PROJECT_SCHEMA_PREFIX) {
+            Some(name) => name.to_string(),
+            None => DEFAULT_PROJECT.to_string(),
+        };
+        let row = client
+            .query_one(&format!("SELECT COUNT(*) FROM \"{}\".domains", schema.replace('"', "\"\"")), &[])
+            .await?;
+        projects.push((project, row.get(0)));
+    }
+    projects.sort_by(|a, b| (a.0 != DEFAULT_PROJECT, &a.0).cmp(&(b.0 != DEFAULT_PROJECT, &b.0)));
+
+    Ok(projects)
+}
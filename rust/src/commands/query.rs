@@ -0,0 +1,24 @@
+use anyhow::{Context, Result};
+
+use crate::db::Pool;
+use crate::expr::validate_where;
+
+/// Count rows matching a validated, user-supplied `WHERE` fragment
+pub async fn run(pool: &Pool, count_where: &str) -> Result<i64> {
+    validate_where(count_where).context("Rejected --count-where expression")?;
+
+    let mut client = pool.get().await?;
+    // Defense in depth on top of the validator: a read-only transaction,
+    // standard string literals so a backslash can never end a literal early,
+    // and the extended protocol, which refuses multiple statements. The
+    // transaction rolls back on drop, so a failed query doesn't return the
+    // connection to the pool mid-transaction.
+    let tx = client.build_transaction().read_only(true).start().await?;
+    tx.batch_execute("SET LOCAL standard_conforming_strings = on").await?;
+    let query = format!("SELECT COUNT(*) FROM domains WHERE ({})", count_where);
+    let row = tx.query_one(&query, &[]).await?;
+    let count: i64 = row.get(0);
+    tx.commit().await?;
+
+    Ok(count)
+}
@@ -1,90 +1,239 @@
-use anyhow::Result;
-use deadpool_postgres::Pool;
-use regex::Regex;
-use std::fs::File;
-use std::io::{self, BufRead, BufReader};
+use anyhow::{bail, Result};
+use futures_util::StreamExt;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::net::IpAddr;
 use std::path::PathBuf;
+use std::pin::pin;
 use std::time::Instant;
-use tokio_postgres::types::ToSql;
+use tokio_postgres::types::Type;
+use tracing::info;
+
+use crate::cidr::Cidr;
+use crate::db::{self, Pool};
+use crate::domain::strip_comment;
+use crate::filter::DomainFilter;
+use crate::human;
+use crate::input;
+use crate::progress;
+use crate::prompt;
+
+/// Default domains per DELETE batch (`--batch-size`, `tuning.batch_size`)
+pub const BATCH_SIZE: usize = 10_000;
+
+/// What `remove` deletes and how; everything except the input file
+pub struct RemoveOptions {
+    /// Remove this one domain
+    pub domain: Option<String>,
+    /// With `domain`, don't report a domain that was not found
+    pub quiet: bool,
+    pub match_filter: Vec<String>,
+    pub regex_filter: Vec<String>,
+    /// `and` or `or`
+    pub filter_logic: Option<String>,
+    pub ignore_case: bool,
+    /// Remove the domains that do not match instead
+    pub invert: bool,
+    /// Remove domains first seen before this date or RFC 3339 time
+    pub added_before: Option<String>,
+    /// Remove domains last seen before this date or RFC 3339 time
+    pub last_seen_before: Option<String>,
+    /// Skip the confirmation prompt
+    pub confirm: bool,
+    /// Abort a filter or `cidr` removal matching more domains than this
+    pub max_delete: Option<u64>,
+    /// Ignore `max_delete`
+    pub force: bool,
+    /// Remove stored IP address entries inside this network
+    pub cidr: Option<String>,
+    /// Report what would be removed without removing it
+    pub dry_run: bool,
+    /// Skip `#`/`//` comment lines and strip inline annotations
+    pub allow_comments: bool,
+    /// Commit after every N batches instead of all at once
+    pub batch_commit_every: Option<usize>,
+    /// Domains per DELETE batch
+    pub batch_size: usize,
+    /// Only report errors
+    pub silent: bool,
+}
 
-const BATCH_SIZE: usize = 10_000;
+impl Default for RemoveOptions {
+    fn default() -> Self {
+        Self {
+            domain: None,
+            quiet: false,
+            match_filter: Vec::new(),
+            regex_filter: Vec::new(),
+            filter_logic: None,
+            ignore_case: false,
+            invert: false,
+            added_before: None,
+            last_seen_before: None,
+            confirm: false,
+            max_delete: None,
+            force: false,
+            cidr: None,
+            dry_run: false,
+            allow_comments: false,
+            batch_commit_every: None,
+            batch_size: BATCH_SIZE,
+            silent: false,
+        }
+    }
+}
 
-pub async fn run(
-    pool: &Pool,
-    file: Option<PathBuf>,
-    domain: Option<String>,
-    match_filter: Option<String>,
-    regex_filter: Option<String>,
-    silent: bool,
-) -> Result<()> {
+pub async fn run(pool: &Pool, file: Option<PathBuf>, input_format: input::Format, opts: RemoveOptions) -> Result<()> {
+    let RemoveOptions {
+        domain, quiet, match_filter, regex_filter, filter_logic, ignore_case, invert, added_before, last_seen_before,
+        confirm, max_delete, force, cidr, dry_run, allow_comments, batch_commit_every, batch_size, silent,
+    } = opts;
+    let max_delete = if force { None } else { max_delete };
+    if batch_size == 0 {
+        bail!("--batch-size must be positive");
+    }
+    if batch_commit_every == Some(0) {
+        bail!("--batch-commit-every must be positive");
+    }
     let client = pool.get().await?;
 
     if let Some(d) = domain {
-        // Single domain removal
-        let result = client
-            .execute("DELETE FROM domains WHERE domain = $1", &[&d])
-            .await?;
+        // Single domain removal: a primary-key lookup, cheap enough to run
+        // once per domain from xargs. Both outcomes go to stderr so stdout
+        // stays clean for chaining
+        db::pin(&client).await?;
+        let result = db::execute(
+            &client,
+            "DELETE FROM domains WHERE domain = $1",
+            &[(&d, Type::TEXT)],
+        )
+        .await?;
+        db::unpin(&client).await?;
         if result > 0 {
-            println!("Domain '{}' removed from database", d);
-        } else if !silent {
-            eprintln!("Domain '{}' not found in database", d);
+            if !silent {
+                info!("Domain '{}' removed from database", d);
+            }
+        } else if !silent && !quiet {
+            info!("Domain '{}' not found in database", d);
         }
         return Ok(());
     }
 
-    if match_filter.is_some() || regex_filter.is_some() {
-        // Filter-based removal
-        let regex = if let Some(pattern) = &regex_filter {
-            Some(Regex::new(pattern)?)
-        } else {
-            None
-        };
-
-        let rows = client.query("SELECT domain FROM domains", &[]).await?;
-        let mut to_remove: Vec<String> = Vec::new();
-
-        for row in rows {
-            let d: String = row.get(0);
+    if let Some(cidr) = cidr {
+        return remove_cidr(&client, &Cidr::parse(&cidr)?, dry_run, max_delete, batch_size, silent).await;
+    }
 
-            if let Some(ref m) = match_filter {
-                if !d.contains(m.as_str()) {
-                    continue;
-                }
+    let mut filter =
+        DomainFilter::from_flags(&match_filter, &regex_filter, filter_logic.as_deref(), ignore_case, invert)?;
+    if let Some(time) = &added_before {
+        filter.push_seen_before("first_seen", time)?;
+    }
+    if let Some(time) = &last_seen_before {
+        filter.push_seen_before("last_seen", time)?;
+    }
+    if dry_run {
+        if filter.is_empty() {
+            bail!("--dry-run needs --cidr, --match, --regex, --added-before or --last-seen-before");
+        }
+        return list_matching(&client, &filter, silent).await;
+    }
+    if !filter.is_empty() {
+        // Filter-based removal
+        db::pin(&client).await?;
+        let removed = if filter.has_residual() {
+            let rows = db::query(&client, &filter.select(false), &filter.params()).await?;
+            let to_remove: Vec<String> = rows
+                .iter()
+                .map(|r| r.get::<_, String>(0))
+                .filter(|d| filter.matches(d))
+                .collect();
+            check_max_delete(to_remove.len() as u64, max_delete)?;
+            if !confirm && !to_remove.is_empty() && !confirm_removal(to_remove.len() as u64, invert, silent)? {
+                return db::unpin(&client).await;
             }
-
-            if let Some(ref re) = regex {
-                if !re.is_match(&d) {
-                    continue;
+            let mut removed = 0u64;
+            for chunk in to_remove.chunks(batch_size) {
+                removed += remove_batch(&client, chunk).await?;
+            }
+            removed
+        } else {
+            if !confirm || max_delete.is_some() {
+                let query = format!("SELECT COUNT(*) FROM domains{}", filter.where_clause());
+                let count = db::query(&client, &query, &filter.params()).await?[0].get::<_, i64>(0) as u64;
+                check_max_delete(count, max_delete)?;
+                if !confirm && count > 0 && !confirm_removal(count, invert, silent)? {
+                    return db::unpin(&client).await;
                 }
             }
-
-            to_remove.push(d);
-        }
-
-        let removed = remove_batch(&client, &to_remove).await?;
+            // Every condition is in SQL: delete server-side
+            let query = format!("DELETE FROM domains{}", filter.where_clause());
+            db::execute(&client, &query, &filter.params()).await?
+        };
+        db::unpin(&client).await?;
         if !silent {
-            eprintln!("Removed {} domains using filter", removed);
+            info!(removed, "Removed {} domains using filter", human::count(removed));
         }
         return Ok(());
     }
 
-    // File/stdin-based removal - use fast COPY by default
+    // File/stdin-based removal - use fast COPY by default, falling back to
+    // batched DELETEs when incremental commits are requested
     let start = Instant::now();
+    progress::install_signal_handler();
 
-    run_fast_remove(pool, file, silent).await?;
+    if let Some(n) = batch_commit_every {
+        run_batch_remove(&client, file, &input_format, allow_comments, n, batch_size, silent).await?;
+    } else {
+        run_fast_remove(pool, file, &input_format, allow_comments, silent).await?;
+    }
 
     if !silent {
-        eprintln!("Completed in {:.1}s", start.elapsed().as_secs_f64());
+        let elapsed = start.elapsed().as_secs_f64();
+        info!(elapsed_s = elapsed, "Completed in {:.1}s", elapsed);
     }
 
     Ok(())
 }
 
-async fn run_fast_remove(pool: &Pool, file: Option<PathBuf>, silent: bool) -> Result<()> {
+/// `--max-delete`: refuse a removal matching more than `max` domains
+fn check_max_delete(count: u64, max: Option<u64>) -> Result<()> {
+    match max {
+        Some(max) if count > max => bail!(
+            "Refusing to remove {} domains, more than --max-delete {}; tighten the filter or pass --force",
+            human::count(count),
+            human::count(max)
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Ask before a filter removes `count` domains: a typo in the pattern, or
+/// in an allowlist with `--invert`, could otherwise delete thousands of rows
+fn confirm_removal(count: u64, invert: bool, silent: bool) -> Result<bool> {
+    let question = if invert {
+        format!("Remove {} domains that do NOT match the filter?", human::count(count))
+    } else {
+        format!("Remove {} domains matching the filter?", human::count(count))
+    };
+    let confirmed = prompt::confirm(&question)?;
+    if !confirmed && !silent {
+        info!("Remove operation cancelled");
+    }
+    Ok(confirmed)
+}
+
+async fn run_fast_remove(
+    pool: &Pool,
+    file: Option<PathBuf>,
+    input_format: &input::Format,
+    allow_comments: bool,
+    silent: bool,
+) -> Result<()> {
     let client = pool.get().await?;
     let start = Instant::now();
 
-    // Create temp table
+    // Create temp table; it lives for the transaction, so the whole
+    // COPY + DELETE runs inside one
+    client.batch_execute("BEGIN").await?;
     client
         .execute(
             "CREATE TEMP TABLE temp_remove (domain TEXT) ON COMMIT DROP",
@@ -92,24 +241,40 @@ async fn run_fast_remove(pool: &Pool, file: Option<PathBuf>, silent: bool) -> Re
         )
         .await?;
 
-    let reader: Box<dyn BufRead> = match file {
-        Some(path) => Box::new(BufReader::with_capacity(512 * 1024, File::open(path)?)),
-        None => Box::new(BufReader::with_capacity(512 * 1024, io::stdin().lock())),
-    };
+    let reader = input::domains(input::open(file.as_deref(), 512 * 1024)?, input_format)?;
 
     let mut domains: Vec<String> = Vec::new();
+    let mut comments = 0u64;
+    progress::set_phase("reading");
+    let ticker = progress::ticker(silent);
     for line in reader.lines() {
         let line = line?;
-        let domain = line.trim();
+        progress::add_lines(1);
+        let domain = if allow_comments {
+            match strip_comment(&line) {
+                Some(d) => d,
+                None => {
+                    comments += 1;
+                    continue;
+                }
+            }
+        } else {
+            line.trim()
+        };
         if !domain.is_empty() {
             domains.push(domain.to_string());
         }
     }
+    drop(ticker);
+
+    if !silent && comments > 0 {
+        info!("Skipped {} comment lines", human::count(comments));
+    }
 
     // Use COPY to insert into temp table
     if !domains.is_empty() {
         let sink = client
-            .copy_in("COPY temp_remove (domain) FROM STDIN")
+            .copy_in("COPY temp_remove (domain) FROM STDIN (FORMAT binary)")
             .await?;
         
         let writer = tokio_postgres::binary_copy::BinaryCopyInWriter::new(
@@ -119,11 +284,14 @@ async fn run_fast_remove(pool: &Pool, file: Option<PathBuf>, silent: bool) -> Re
         
         tokio::pin!(writer);
         
+        progress::set_phase("copying");
         for domain in &domains {
             writer.as_mut().write(&[domain]).await?;
         }
         
         writer.finish().await?;
+        progress::add_written(domains.len() as u64);
+        progress::set_phase("deleting");
 
         // Delete matching domains
         let result = client
@@ -134,34 +302,55 @@ async fn run_fast_remove(pool: &Pool, file: Option<PathBuf>, silent: bool) -> Re
             .await?;
 
         if !silent {
-            eprintln!(
+            info!(
+                removed = result,
                 "Removed {} domains in {:.1}s (fast COPY)",
-                result,
+                human::count(result),
                 start.elapsed().as_secs_f64()
             );
         }
     }
+    client.batch_execute("COMMIT").await?;
 
     Ok(())
 }
 
+/// Batched DELETE path, committing after every `commit_every` batches so an
+/// interrupted run keeps the progress made so far
 async fn run_batch_remove(
-    client: &deadpool_postgres::Client,
+    client: &db::Client,
     file: Option<PathBuf>,
+    input_format: &input::Format,
+    allow_comments: bool,
+    commit_every: usize,
+    batch_size: usize,
     silent: bool,
 ) -> Result<()> {
-    let reader: Box<dyn BufRead> = match file {
-        Some(path) => Box::new(BufReader::with_capacity(512 * 1024, File::open(path)?)),
-        None => Box::new(BufReader::with_capacity(512 * 1024, io::stdin().lock())),
-    };
+    let reader = input::domains(input::open(file.as_deref(), 512 * 1024)?, input_format)?;
 
     let mut total = 0u64;
     let mut removed = 0u64;
-    let mut batch: Vec<String> = Vec::with_capacity(BATCH_SIZE);
+    let mut batches = 0usize;
+    let mut comments = 0u64;
+    let mut batch: Vec<String> = Vec::with_capacity(batch_size);
 
+    progress::set_phase("deleting");
+    let ticker = progress::ticker(silent);
+    client.batch_execute("BEGIN").await?;
     for line in reader.lines() {
         let line = line?;
-        let domain = line.trim();
+        progress::add_lines(1);
+        let domain = if allow_comments {
+            match strip_comment(&line) {
+                Some(d) => d,
+                None => {
+                    comments += 1;
+                    continue;
+                }
+            }
+        } else {
+            line.trim()
+        };
         if domain.is_empty() {
             continue;
         }
@@ -169,41 +358,138 @@ async fn run_batch_remove(
         total += 1;
         batch.push(domain.to_string());
 
-        if batch.len() >= BATCH_SIZE {
+        if batch.len() >= batch_size {
             removed += remove_batch(client, &batch).await?;
             batch.clear();
+            batches += 1;
+
+            if batches.is_multiple_of(commit_every) {
+                client.batch_execute("COMMIT; BEGIN").await?;
+            }
         }
     }
 
     if !batch.is_empty() {
         removed += remove_batch(client, &batch).await?;
     }
+    client.batch_execute("COMMIT").await?;
+    drop(ticker);
 
     if !silent {
-        eprintln!(
-            "Processed {} domains: {} removed, {} not found",
-            total,
+        info!(
+            processed = total,
             removed,
-            total - removed
+            "Processed {} domains: {} removed, {} not found",
+            human::count(total),
+            human::count(removed),
+            human::count(total - removed)
         );
+        if comments > 0 {
+            info!("Skipped {} comment lines", human::count(comments));
+        }
+    }
+
+    Ok(())
+}
+
+/// `--dry-run` with a filter: the domains it would remove, on stdout
+async fn list_matching(client: &db::Client, filter: &DomainFilter, silent: bool) -> Result<()> {
+    db::pin(client).await?;
+    let rows = db::query_stream(client, &filter.select(false), &filter.params()).await?;
+    let mut rows = pin!(rows);
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let mut matched = 0u64;
+    while let Some(row) = rows.next().await {
+        let row = row?;
+        let domain: &str = row.get(0);
+        if filter.matches(domain) {
+            writeln!(out, "{}", domain)?;
+            matched += 1;
+        }
+    }
+    out.flush()?;
+    db::unpin(client).await?;
+    if !silent {
+        info!(matched, "Dry run: would remove {} domains using filter", human::count(matched));
+    }
+    Ok(())
+}
+
+/// `--cidr`: stored IP literals inside `cidr`, listed on stdout with
+/// `dry_run` and deleted otherwise
+async fn remove_cidr(
+    client: &db::Client,
+    cidr: &Cidr,
+    dry_run: bool,
+    max_delete: Option<u64>,
+    batch_size: usize,
+    silent: bool,
+) -> Result<()> {
+    db::pin(client).await?;
+    // Only entries made of address characters can parse as an IP
+    let rows = db::query_stream(client, "SELECT domain FROM domains WHERE domain ~ '^[0-9A-Fa-f.:]+$'", &[]).await?;
+    let mut rows = pin!(rows);
+    let mut matched: Vec<String> = Vec::new();
+    while let Some(row) = rows.next().await {
+        let entry: String = row?.get(0);
+        if entry.parse::<IpAddr>().is_ok_and(|ip| cidr.contains(ip)) {
+            matched.push(entry);
+        }
+    }
+
+    if dry_run {
+        db::unpin(client).await?;
+        let stdout = io::stdout();
+        let mut out = BufWriter::new(stdout.lock());
+        for entry in &matched {
+            writeln!(out, "{}", entry)?;
+        }
+        out.flush()?;
+        if !silent {
+            info!("Dry run: would remove {} IP entries in {}", human::count(matched.len() as u64), cidr);
+        }
+        return Ok(());
     }
 
+    check_max_delete(matched.len() as u64, max_delete)?;
+    let mut removed = 0u64;
+    for chunk in matched.chunks(batch_size) {
+        removed += remove_batch(client, chunk).await?;
+    }
+    db::unpin(client).await?;
+    if !silent {
+        info!(removed, "Removed {} IP entries in {}", human::count(removed), cidr);
+    }
     Ok(())
 }
 
-async fn remove_batch(client: &deadpool_postgres::Client, domains: &[String]) -> Result<u64> {
+async fn remove_batch(client: &db::Client, domains: &[String]) -> Result<u64> {
     if domains.is_empty() {
         return Ok(0);
     }
 
-    // Build parameterized query
-    let placeholders: Vec<String> = (1..=domains.len()).map(|i| format!("${}", i)).collect();
-    let query = format!(
-        "DELETE FROM domains WHERE domain IN ({})",
-        placeholders.join(", ")
-    );
-
-    let params: Vec<&(dyn ToSql + Sync)> = domains.iter().map(|d| d as &(dyn ToSql + Sync)).collect();
-    let result = client.execute(&query, &params).await?;
+    // The whole batch binds as one array, so its size is not limited by
+    // PostgreSQL's 65535 parameters per statement
+    let result = db::execute(
+        client,
+        "DELETE FROM domains WHERE domain = ANY($1)",
+        &[(&domains, Type::TEXT_ARRAY)],
+    )
+    .await?;
+    progress::add_written(domains.len() as u64);
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_max_delete() {
+        assert!(check_max_delete(10, None).is_ok());
+        assert!(check_max_delete(10, Some(10)).is_ok());
+        let err = check_max_delete(11, Some(10)).unwrap_err();
+        assert!(err.to_string().contains("more than --max-delete 10"), "{}", err);
+    }
+}
@@ -0,0 +1,257 @@
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use futures_util::StreamExt;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use tokio_postgres::types::Type;
+use tracing::info;
+
+use crate::db::{self, Pool};
+use crate::domain::apex_of;
+
+/// A `stats` snapshot, as printed and as appended to `--history`
+#[derive(Serialize)]
+pub struct Stats {
+    pub timestamp: String,
+    pub total: u64,
+    pub apex_count: u64,
+    pub subdomain_count: u64,
+    pub wildcard_count: u64,
+    pub avg_label_depth: f64,
+    pub top_tlds: Vec<TldCount>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TldCount {
+    pub tld: String,
+    pub count: u64,
+}
+
+/// The parts of an earlier `stats --format json` snapshot `--compare` uses
+#[derive(Deserialize)]
+struct Baseline {
+    #[serde(default)]
+    timestamp: String,
+    total: u64,
+    #[serde(default)]
+    top_tlds: Vec<TldCount>,
+}
+
+/// A compared metric: the total, or one of the baseline's TLDs
+pub struct Change {
+    pub metric: String,
+    pub before: u64,
+    pub after: u64,
+}
+
+impl Change {
+    /// How far the count fell, as a percentage of the baseline (negative
+    /// for growth)
+    pub fn drop_percent(&self) -> f64 {
+        if self.before == 0 {
+            return 0.0;
+        }
+        (self.before as f64 - self.after as f64) / self.before as f64 * 100.0
+    }
+}
+
+/// `--compare`: the current counts against a baseline snapshot
+pub struct Comparison {
+    /// The baseline file
+    pub path: PathBuf,
+    /// When the baseline snapshot was taken
+    pub timestamp: String,
+    pub changes: Vec<Change>,
+    /// `--fail-if-drop`, as a percentage
+    pub threshold: Option<f64>,
+}
+
+impl Comparison {
+    /// Whether `change` dropped more than `--fail-if-drop` allows
+    pub fn over_limit(&self, change: &Change) -> bool {
+        self.threshold.is_some_and(|max| change.drop_percent() > max)
+    }
+
+    /// Metrics that dropped more than `--fail-if-drop` allows; any fails
+    /// the run
+    pub fn regressed(&self) -> usize {
+        self.changes.iter().filter(|c| self.over_limit(c)).count()
+    }
+}
+
+/// What `stats` found, before formatting
+pub struct StatsReport {
+    pub stats: Stats,
+    pub comparison: Option<Comparison>,
+}
+
+/// Read a snapshot written by `stats --format json`, or the last record of
+/// a `--history` file
+fn load_baseline(path: &Path) -> Result<Baseline> {
+    let text = fs::read_to_string(path).with_context(|| format!("Failed to read baseline {:?}", path))?;
+    serde_json::from_str(&text)
+        .or_else(|e| match text.lines().rev().find(|line| !line.trim().is_empty()) {
+            Some(last) => serde_json::from_str(last).map_err(|_| e),
+            None => Err(e),
+        })
+        .with_context(|| format!("{:?} is not a `stats --format json` snapshot", path))
+}
+
+/// `--fail-if-drop` threshold: a percentage, with or without `%`
+fn parse_percent(value: &str) -> Result<f64> {
+    match value.trim().trim_end_matches('%').parse::<f64>() {
+        Ok(pct) if (0.0..=100.0).contains(&pct) => Ok(pct),
+        _ => bail!("--fail-if-drop must be a percentage between 0 and 100 (e.g. 10%), got '{}'", value),
+    }
+}
+
+/// The total and every baseline TLD, against their current counts; a TLD
+/// that has vanished counts as 0
+fn compare(baseline: &Baseline, total: u64, tld_counts: &HashMap<String, u64>) -> Vec<Change> {
+    let mut changes = vec![Change { metric: "total".to_string(), before: baseline.total, after: total }];
+    changes.extend(baseline.top_tlds.iter().map(|t| Change {
+        metric: format!("tld {}", t.tld),
+        before: t.count,
+        after: tld_counts.get(&t.tld).copied().unwrap_or(0),
+    }));
+    changes
+}
+
+/// Totals computed by PostgreSQL. Label depth ignores a leading `*.`.
+const TOTALS: &str = "SELECT COUNT(*), COUNT(*) FILTER (WHERE strpos(domain, '*') > 0), \
+     COALESCE(avg(length(domain) - length(replace(domain, '.', '')) + 1 - (left(domain, 2) = '*.')::int), 0)::float8 \
+     FROM domains";
+
+/// The most common TLDs (the text after the last dot), ties by name
+const TOP_TLDS: &str = "SELECT tld, COUNT(*) FROM (SELECT substring(domain FROM '[^.]*$') AS tld FROM domains) t \
+     GROUP BY tld HAVING COUNT(*) >= $1 ORDER BY 2 DESC, tld COLLATE \"C\" LIMIT $2";
+
+/// Current counts of the given TLDs, for `--compare`
+const TLD_COUNTS: &str = "SELECT tld, COUNT(*) FROM (SELECT substring(domain FROM '[^.]*$') AS tld FROM domains) t \
+     WHERE tld = ANY($1) GROUP BY tld";
+
+pub async fn run(
+    pool: &Pool,
+    top: usize,
+    min_count: u64,
+    history: Option<PathBuf>,
+    baseline_path: Option<PathBuf>,
+    fail_if_drop: Option<String>,
+    silent: bool,
+) -> Result<StatsReport> {
+    // Bad arguments fail before any query runs
+    let threshold = fail_if_drop.as_deref().map(parse_percent).transpose()?;
+    let baseline = baseline_path.as_deref().map(load_baseline).transpose()?;
+
+    let client = pool.get().await?;
+    // One snapshot, so the SQL totals and the streamed apex counts agree
+    client.batch_execute("BEGIN ISOLATION LEVEL REPEATABLE READ, READ ONLY").await?;
+
+    let rows = db::query(&client, TOTALS, &[]).await?;
+    let total = rows[0].get::<_, i64>(0) as u64;
+    let wildcard_count = rows[0].get::<_, i64>(1) as u64;
+    let avg_label_depth: f64 = rows[0].get(2);
+    if total == 0 {
+        db::hint_if_empty(&client, silent).await?;
+    }
+
+    let (min_count, limit) = (min_count as i64, top as i64);
+    let top_tlds = db::query(&client, TOP_TLDS, &[(&min_count, Type::INT8), (&limit, Type::INT8)])
+        .await?
+        .iter()
+        .map(|row| TldCount { tld: row.get(0), count: row.get::<_, i64>(1) as u64 })
+        .collect();
+
+    // The apex lookup lives in Rust, so only this part reads the rows;
+    // they are streamed rather than collected
+    let mut apexes: HashSet<String> = HashSet::new();
+    let mut subdomain_count = 0u64;
+    let mut tld_counts: HashMap<String, u64> = HashMap::new();
+    if let Some(baseline) = &baseline {
+        let tlds: Vec<&str> = baseline.top_tlds.iter().map(|t| t.tld.as_str()).collect();
+        for row in db::query(&client, TLD_COUNTS, &[(&tlds, Type::TEXT_ARRAY)]).await? {
+            tld_counts.insert(row.get(0), row.get::<_, i64>(1) as u64);
+        }
+    }
+
+    let mut stream = pin!(db::query_stream(&client, "SELECT domain FROM domains", &[]).await?);
+    while let Some(row) = stream.next().await {
+        let row = row?;
+        let domain: &str = row.get(0);
+        let apex = apex_of(domain);
+        if !domain.contains('*') && apex != domain {
+            subdomain_count += 1;
+        }
+        if !apexes.contains(apex) {
+            apexes.insert(apex.to_string());
+        }
+    }
+    client.batch_execute("COMMIT").await?;
+
+    let stats = Stats {
+        timestamp: Utc::now().to_rfc3339(),
+        total,
+        apex_count: apexes.len() as u64,
+        subdomain_count,
+        wildcard_count,
+        avg_label_depth,
+        top_tlds,
+    };
+
+    if let Some(path) = history {
+        // One JSON line per run, written with a single O_APPEND write so
+        // concurrent runs never interleave partial records
+        let mut line = serde_json::to_vec(&stats)?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open history file: {:?}", path))?;
+        file.write_all(&line)?;
+
+        if !silent {
+            info!("Appended snapshot to {:?}", path);
+        }
+    }
+
+    let comparison = baseline.zip(baseline_path).map(|(baseline, path)| Comparison {
+        changes: compare(&baseline, stats.total, &tld_counts),
+        path,
+        timestamp: baseline.timestamp,
+        threshold,
+    });
+    Ok(StatsReport { stats, comparison })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_and_threshold() {
+        let baseline = Baseline {
+            timestamp: String::new(),
+            total: 1000,
+            top_tlds: vec![
+                TldCount { tld: "com".to_string(), count: 600 },
+                TldCount { tld: "io".to_string(), count: 50 },
+            ],
+        };
+        let current = HashMap::from([("com".to_string(), 480)]);
+        let changes = compare(&baseline, 950, &current);
+        let drops: Vec<(&str, f64)> = changes.iter().map(|c| (c.metric.as_str(), c.drop_percent())).collect();
+        assert_eq!(drops, vec![("total", 5.0), ("tld com", 20.0), ("tld io", 100.0)]);
+        assert!(Change { metric: "total".to_string(), before: 10, after: 12 }.drop_percent() < 0.0);
+        assert_eq!(Change { metric: "total".to_string(), before: 0, after: 0 }.drop_percent(), 0.0);
+
+        assert_eq!(parse_percent("10%").unwrap(), 10.0);
+        assert_eq!(parse_percent("2.5").unwrap(), 2.5);
+        assert!(parse_percent("150%").is_err());
+        assert!(parse_percent("ten").is_err());
+    }
+}
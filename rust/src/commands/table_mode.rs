@@ -0,0 +1,40 @@
+use anyhow::Result;
+use tracing::info;
+
+use crate::db::Pool;
+
+pub async fn run(pool: &Pool, mode: String, silent: bool) -> Result<()> {
+    let client = pool.get().await?;
+
+    let row = client
+        .query_one(
+            "SELECT relpersistence = 'u' FROM pg_class WHERE oid = 'domains'::regclass",
+            &[],
+        )
+        .await?;
+    let unlogged: bool = row.get(0);
+    let want_unlogged = mode == "unlogged";
+
+    if unlogged == want_unlogged {
+        if !silent {
+            info!("domains table is already {}", mode);
+        }
+        return Ok(());
+    }
+
+    // SET LOGGED rewrites the whole table through the WAL
+    if !silent && !want_unlogged {
+        info!("Writing the table to the WAL; this can take a while on large tables...");
+    }
+    let statement = if want_unlogged {
+        "ALTER TABLE domains SET UNLOGGED"
+    } else {
+        "ALTER TABLE domains SET LOGGED"
+    };
+    client.batch_execute(statement).await?;
+    if !silent {
+        info!("domains table is now {}", mode);
+    }
+
+    Ok(())
+}
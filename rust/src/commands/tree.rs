@@ -0,0 +1,73 @@
+use anyhow::Result;
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::pin::pin;
+use tracing::info;
+
+use crate::db::{self, Pool};
+use crate::human;
+use crate::output::{OutputSink, StdoutSink};
+use crate::psl::SuffixList;
+
+/// Each apex and its number of stored subdomains (`apex<TAB>count`), most
+/// first; with `subdomains`, followed by those subdomains indented
+pub async fn run(pool: &Pool, psl: Option<PathBuf>, min: u64, subdomains: bool, silent: bool) -> Result<()> {
+    let list = match &psl {
+        Some(path) => SuffixList::load(path)?,
+        None => SuffixList::builtin(),
+    };
+
+    // Apexes are derived with the same rules as `apex`, so the two agree;
+    // a stored apex is not its own subdomain
+    let client = pool.get().await?;
+    db::pin(&client).await?;
+    let rows = db::query_stream(&client, "SELECT domain FROM domains", &[]).await?;
+    let mut rows = pin!(rows);
+    let mut groups: HashMap<String, (u64, Vec<String>)> = HashMap::new();
+    let mut stored = 0u64;
+    while let Some(row) = rows.next().await {
+        let row = row?;
+        let domain: &str = row.get(0);
+        stored += 1;
+        let Some((host, start)) = list.split_apex(domain) else {
+            continue;
+        };
+        let group = groups.entry(host[start..].to_string()).or_default();
+        if start > 0 {
+            group.0 += 1;
+            if subdomains {
+                group.1.push(domain.to_string());
+            }
+        }
+    }
+    if stored == 0 {
+        db::hint_if_empty(&client, silent).await?;
+    }
+    db::unpin(&client).await?;
+
+    let mut groups: Vec<(String, (u64, Vec<String>))> =
+        groups.into_iter().filter(|(_, (count, _))| *count >= min).collect();
+    groups.sort_unstable_by(|(a, (x, _)), (b, (y, _))| y.cmp(x).then_with(|| a.cmp(b)));
+    let mut out = StdoutSink::new((None, None));
+    let (apexes, mut total) = (groups.len() as u64, 0u64);
+    for (apex, (count, mut domains)) in groups {
+        total += count;
+        out.line(&format!("{}\t{}", apex, count))?;
+        domains.sort_unstable();
+        for domain in domains {
+            out.line(&format!("  {}", domain))?;
+        }
+    }
+    out.finish()?;
+
+    if !silent {
+        info!(
+            apexes,
+            "{} apex domains with {} subdomains",
+            human::count(apexes),
+            human::count(total)
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::domain::{scope_rule_matches, strip_comment};
+
+/// Scope rules from `path`, one per line; `#` comments and blanks are skipped
+fn load_rules(path: &Path) -> Result<Vec<String>> {
+    let file = File::open(path).with_context(|| format!("Failed to open scope rules {:?}", path))?;
+    let mut rules = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if let Some(rule) = strip_comment(&line) {
+            if !rule.is_empty() {
+                rules.push(rule.to_string());
+            }
+        }
+    }
+    Ok(rules)
+}
+
+/// Classify each candidate host against the scope rules; never touches the
+/// database
+pub fn run(rules_path: PathBuf, hosts: Option<PathBuf>, in_scope_only: bool, silent: bool) -> Result<()> {
+    let rules = load_rules(&rules_path)?;
+    if rules.is_empty() {
+        bail!("No scope rules found in {:?}", rules_path);
+    }
+
+    let reader: Box<dyn BufRead> = match hosts {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(BufReader::new(io::stdin().lock())),
+    };
+
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+    let mut in_scope = 0u64;
+    let mut out_of_scope = 0u64;
+
+    for line in reader.lines() {
+        let line = line?;
+        let host = line.trim();
+        if host.is_empty() {
+            continue;
+        }
+
+        match rules.iter().find(|rule| scope_rule_matches(rule, host)) {
+            Some(rule) => {
+                in_scope += 1;
+                if in_scope_only {
+                    writeln!(handle, "{}", host)?;
+                } else {
+                    writeln!(handle, "{}\tin-scope\t{}", host, rule)?;
+                }
+            }
+            None => {
+                out_of_scope += 1;
+                if !in_scope_only {
+                    writeln!(handle, "{}\tout-of-scope", host)?;
+                }
+            }
+        }
+    }
+
+    if !silent {
+        info!("{} in scope, {} out of scope", in_scope, out_of_scope);
+    }
+
+    Ok(())
+}
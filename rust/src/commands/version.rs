@@ -0,0 +1,38 @@
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::db::SCHEMA_VERSION;
+use crate::output::OutputFormat;
+
+#[derive(Serialize)]
+struct VersionOutput {
+    version: &'static str,
+    git_commit: &'static str,
+    schema_version: u32,
+    features: Vec<&'static str>,
+}
+
+/// Print build and schema compatibility info; never touches the database
+pub fn run(format: OutputFormat) -> Result<()> {
+    let features = env!("BOUNTYCATCH_FEATURES");
+    let output = VersionOutput {
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: env!("BOUNTYCATCH_GIT_COMMIT"),
+        schema_version: SCHEMA_VERSION,
+        features: features.split(',').filter(|f| !f.is_empty()).collect(),
+    };
+
+    if format == OutputFormat::Json {
+        println!("{}", serde_json::to_string(&output)?);
+    } else {
+        println!("bountycatch {} ({})", output.version, output.git_commit);
+        println!("schema version: {}", output.schema_version);
+        if output.features.is_empty() {
+            println!("features: none");
+        } else {
+            println!("features: {}", output.features.join(", "));
+        }
+    }
+
+    Ok(())
+}
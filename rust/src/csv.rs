@@ -0,0 +1,31 @@
+//! CSV records for `print --format csv` and `export --format csv`, written
+//! with the `csv` crate so quoting follows RFC 4180.
+
+use anyhow::Result;
+
+/// One record rendered as a line without its terminator, ready for an
+/// `OutputSink` or `writeln!`
+pub fn record<I, T>(fields: I) -> Result<String>
+where
+    I: IntoIterator<Item = T>,
+    T: AsRef<[u8]>,
+{
+    let mut writer = ::csv::WriterBuilder::new().buffer_capacity(256).from_writer(Vec::new());
+    writer.write_record(fields)?;
+    let mut line = writer.into_inner().map_err(|e| e.into_error())?;
+    // The `\n` terminator; the caller ends the line
+    line.pop();
+    Ok(String::from_utf8(line)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        assert_eq!(record(["a.example.com", "", "2024-01-01T00:00:00Z"]).unwrap(), "a.example.com,,2024-01-01T00:00:00Z");
+        assert_eq!(record(["recon,web", "say \"hi\""]).unwrap(), "\"recon,web\",\"say \"\"hi\"\"\"");
+        assert_eq!(record(["two\nlines"]).unwrap(), "\"two\nlines\"");
+    }
+}
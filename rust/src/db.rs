@@ -1,40 +1,800 @@
-use anyhow::{Context, Result};
-use deadpool_postgres::{Config, Pool, Runtime};
-use tokio_postgres::NoTls;
+use anyhow::{bail, Context, Result};
+use deadpool_postgres::{Config, PoolError, Runtime};
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use tokio_postgres::types::{ToSql, Type};
+use tokio_postgres::{NoTls, Row, RowStream};
+use tracing::{info, warn};
 
 use crate::config::PostgresConfig;
+use crate::filter::DomainFilter;
+use crate::tls;
+
+/// Version of the table layout created by `init_schema`, reported by
+/// `bountycatch version` so wrappers can check compatibility. Version 2 added
+/// `first_seen`/`last_seen`; version 3 the `program`/`source`/`tags`
+/// columns, which the first import that stamps them adds.
+pub const SCHEMA_VERSION: u32 = 3;
+
+/// Advisory lock key `add` holds (shared) for the length of an import
+const IMPORT_LOCK_KEY: i64 = 0x6263_696d_706f_7274;
+
+/// Project used when none is selected; it lives in the server's default
+/// schema, so databases from before projects existed are the default project
+pub const DEFAULT_PROJECT: &str = "default";
+
+/// Schema prefix for every other project
+pub const PROJECT_SCHEMA_PREFIX: &str = "project_";
+
+/// How the connections of a [`Pool`] behave, fixed when it is created
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Connections go through a transaction-pooling proxy, where each
+    /// statement outside an explicit transaction may land on a different
+    /// server connection
+    pub transaction_pooling: bool,
+    /// Send dynamically built statements unprepared
+    /// (`prepared_statements = false`)
+    pub unprepared: bool,
+    /// The project's schema, or `None` for the default project
+    pub schema: Option<String>,
+    /// The project's import lock key
+    lock_key: i64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self { transaction_pooling: false, unprepared: false, schema: None, lock_key: IMPORT_LOCK_KEY }
+    }
+}
+
+/// A connection pool for one project, carrying the [`Settings`] every
+/// connection taken from it follows
+#[derive(Clone)]
+pub struct Pool {
+    inner: deadpool_postgres::Pool,
+    settings: Arc<Settings>,
+}
+
+impl Pool {
+    pub fn new(inner: deadpool_postgres::Pool, settings: Settings) -> Self {
+        Self { inner, settings: Arc::new(settings) }
+    }
+
+    /// A connection from the pool
+    pub async fn get(&self) -> Result<Client, PoolError> {
+        Ok(Client { inner: self.inner.get().await?, settings: self.settings.clone() })
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Whether connections go through a transaction-pooling proxy
+    pub fn transaction_pooling(&self) -> bool {
+        self.settings.transaction_pooling
+    }
+}
+
+/// A pooled connection; derefs to the [`deadpool_postgres::Client`] and
+/// knows its pool's [`Settings`]
+pub struct Client {
+    inner: deadpool_postgres::Client,
+    settings: Arc<Settings>,
+}
+
+impl Client {
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Whether this connection goes through a transaction-pooling proxy
+    pub fn transaction_pooling(&self) -> bool {
+        self.settings.transaction_pooling
+    }
+}
+
+impl Deref for Client {
+    type Target = deadpool_postgres::Client;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for Client {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
 
 pub async fn create_pool(config: &PostgresConfig) -> Result<Pool> {
+    let transaction_pooling = match config.pooler_mode.as_str() {
+        "session" => false,
+        "transaction" => true,
+        other => bail!("Unknown pooler_mode '{}' (expected session or transaction)", other),
+    };
+    let schema = project_schema(&config.project)?;
+    if schema.is_some() && transaction_pooling {
+        // A startup search_path does not survive PgBouncer handing the
+        // session's statements to other server connections
+        bail!("--project needs session pooling; it is not available with pooler_mode = \"transaction\"");
+    }
+
     let mut cfg = Config::new();
     cfg.host = Some(config.host.clone());
     cfg.port = Some(config.port);
     cfg.dbname = Some(config.database.clone());
     cfg.user = Some(config.user.clone());
     cfg.password = Some(config.password.clone());
+    if let Some(schema) = &schema {
+        // Every unqualified table name then resolves inside the project
+        cfg.options = Some(format!("-c search_path={}", schema));
+    }
+
+    let pool = match tls::connector(config)? {
+        Some((mode, connector)) => {
+            cfg.ssl_mode = Some(mode);
+            cfg.create_pool(Some(Runtime::Tokio1), connector)
+        }
+        None => cfg.create_pool(Some(Runtime::Tokio1), NoTls),
+    }
+    .context("Failed to create connection pool")?;
+    let settings = Settings {
+        transaction_pooling,
+        unprepared: !config.prepared_statements,
+        lock_key: import_lock_key(schema.as_deref()),
+        schema,
+    };
+
+    Ok(Pool::new(pool, settings))
+}
 
-    let pool = cfg
-        .create_pool(Some(Runtime::Tokio1), NoTls)
-        .context("Failed to create connection pool")?;
+/// Schema holding a project's tables, or `None` for the default project.
+/// Names are limited to lowercase letters, digits and `_` so the schema
+/// never needs quoting.
+pub fn project_schema(project: &str) -> Result<Option<String>> {
+    if project == DEFAULT_PROJECT {
+        return Ok(None);
+    }
+    let valid = !project.is_empty()
+        && project.len() <= 48
+        && project.starts_with(|c: char| c.is_ascii_lowercase())
+        && project.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if !valid {
+        bail!(
+            "Invalid project name '{}': use up to 48 lowercase letters, digits and '_', starting with a letter",
+            project
+        );
+    }
+    Ok(Some(format!("{}{}", PROJECT_SCHEMA_PREFIX, project)))
+}
 
-    Ok(pool)
+/// Import lock key for a project: the default project keeps the original
+/// key, others mix in an FNV-1a hash of their schema so an import in one
+/// project doesn't show up as running in another
+fn import_lock_key(schema: Option<&str>) -> i64 {
+    let Some(schema) = schema else {
+        return IMPORT_LOCK_KEY;
+    };
+    let hash = schema.bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    IMPORT_LOCK_KEY ^ hash as i64
 }
 
-pub async fn init_schema(pool: &Pool) -> Result<()> {
+/// Fail unless the active project's schema exists. Reads check this before
+/// [`init_schema`], so a mistyped `--project` is an error rather than a new,
+/// empty project; only the commands that write create one.
+pub async fn require_project(pool: &Pool) -> Result<()> {
+    let Some(schema) = &pool.settings().schema else {
+        return Ok(());
+    };
     let client = pool.get().await?;
-    
-    client
-        .execute(
-            "CREATE TABLE IF NOT EXISTS domains (domain TEXT PRIMARY KEY)",
+    // Project names are limited to [a-z0-9_], so the literal is safe
+    let row = client
+        .query_one(&format!("SELECT to_regnamespace('{}') IS NOT NULL", schema), &[])
+        .await?;
+    if !row.get::<_, bool>(0) {
+        let project = schema.strip_prefix(PROJECT_SCHEMA_PREFIX).unwrap_or(schema);
+        bail!("Unknown project '{}' (see `bountycatch projects`)", project);
+    }
+    Ok(())
+}
+
+/// Create the table and index if missing. `unlogged` only applies when the
+/// table is created; use `bountycatch table-mode` to change an existing one.
+/// A missing primary key is always reported, and rebuilt with `repair`.
+pub async fn init_schema(pool: &Pool, unlogged: bool, repair: bool, silent: bool) -> Result<()> {
+    let client = pool.get().await?;
+
+    if let Some(schema) = &pool.settings().schema {
+        client.batch_execute(&format!("CREATE SCHEMA IF NOT EXISTS {}", schema)).await?;
+    }
+
+    // Simple-protocol statements: no prepared statement to lose between
+    // round trips behind a transaction pooler
+    let create = format!(
+        "CREATE {}TABLE IF NOT EXISTS domains (domain TEXT PRIMARY KEY, \
+         first_seen TIMESTAMPTZ DEFAULT now(), last_seen TIMESTAMPTZ DEFAULT now())",
+        if unlogged { "UNLOGGED " } else { "" }
+    );
+    client.batch_execute(&create).await?;
+
+    // Schema version 2 added the sighting timestamps. Checked first, since
+    // `ADD COLUMN IF NOT EXISTS` still takes an exclusive lock. Rows stored
+    // before the upgrade keep NULL: when they were first seen is unknown.
+    let row = client
+        .query_one(
+            "SELECT COUNT(*) FROM information_schema.columns \
+             WHERE table_schema = current_schema() AND table_name = 'domains' \
+             AND column_name IN ('first_seen', 'last_seen')",
             &[],
         )
         .await?;
+    let present: i64 = row.get(0);
+    if present < 2 {
+        client
+            .batch_execute(
+                "ALTER TABLE domains ADD COLUMN IF NOT EXISTS first_seen TIMESTAMPTZ, \
+                 ADD COLUMN IF NOT EXISTS last_seen TIMESTAMPTZ, \
+                 ALTER COLUMN first_seen SET DEFAULT now(), ALTER COLUMN last_seen SET DEFAULT now()",
+            )
+            .await
+            .context("Failed to add the first_seen/last_seen columns (needs table ownership; see `bountycatch doctor`)")?;
+    }
 
     client
-        .execute(
+        .batch_execute(
             "CREATE INDEX IF NOT EXISTS idx_domains_domain ON domains (domain text_pattern_ops)",
-            &[],
         )
         .await?;
 
+    check_primary_key(&client, repair, silent).await
+}
+
+/// Detect a missing `domains_pkey`, as left by a bulk import from a version
+/// that dropped it outside a transaction and was interrupted. With
+/// `repair`, rebuild it unless an import is running or duplicates exist.
+pub async fn check_primary_key(client: &Client, repair: bool, silent: bool) -> Result<()> {
+    let row = client
+        .query_one("SELECT to_regclass('domains_pkey') IS NULL", &[])
+        .await?;
+    let pkey_missing: bool = row.get(0);
+    if !pkey_missing {
+        return Ok(());
+    }
+
+    if import_running(client).await? {
+        if !silent {
+            warn!(
+                "the domains primary key is missing while a bulk import rebuilds it; \
+                 results may include duplicates"
+            );
+        }
+        return Ok(());
+    }
+
+    if !repair {
+        if !silent {
+            warn!(
+                "the domains primary key is missing (interrupted bulk import?); \
+                 results may include duplicates. Rerun with --repair to rebuild it"
+            );
+        }
+        return Ok(());
+    }
+
+    let row = client
+        .query_one("SELECT COUNT(*) - COUNT(DISTINCT domain) FROM domains", &[])
+        .await?;
+    let duplicates: i64 = row.get(0);
+    if duplicates > 0 {
+        bail!(
+            "Cannot rebuild the domains primary key: {} duplicate rows exist; \
+             run `bountycatch dedupe` to review them and remove the extra copies first",
+            duplicates
+        );
+    }
+
+    if !silent {
+        info!("Rebuilding the missing domains primary key...");
+    }
+    client.batch_execute("ALTER TABLE domains ADD PRIMARY KEY (domain)").await?;
     Ok(())
 }
+
+/// Run a dynamically built statement, either prepared (the default) or as
+/// an unnamed statement when `prepared_statements = false`, in which case
+/// the explicit parameter types stand in for the server's prepare step
+pub async fn execute(
+    client: &Client,
+    sql: &str,
+    params: &[(&(dyn ToSql + Sync), Type)],
+) -> Result<u64> {
+    if client.settings().unprepared {
+        return Ok(client.execute_typed(sql, params).await?);
+    }
+    let values: Vec<&(dyn ToSql + Sync)> = params.iter().map(|(v, _)| *v).collect();
+    Ok(client.execute(sql, &values).await?)
+}
+
+/// Query counterpart of [`execute`]
+pub async fn query(
+    client: &Client,
+    sql: &str,
+    params: &[(&(dyn ToSql + Sync), Type)],
+) -> Result<Vec<Row>> {
+    if client.settings().unprepared {
+        return Ok(client.query_typed(sql, params).await?);
+    }
+    let values: Vec<&(dyn ToSql + Sync)> = params.iter().map(|(v, _)| *v).collect();
+    Ok(client.query(sql, &values).await?)
+}
+
+/// Streaming counterpart of [`query`]: rows are handed over as they arrive
+/// instead of being collected first, so client memory stays flat
+pub async fn query_stream(
+    client: &Client,
+    sql: &str,
+    params: &[(&(dyn ToSql + Sync), Type)],
+) -> Result<RowStream> {
+    if client.settings().unprepared {
+        return Ok(client.query_typed_raw(sql, params.iter().map(|(v, t)| (*v, t.clone()))).await?);
+    }
+    Ok(client.query_raw(sql, params.iter().map(|(v, _)| *v)).await?)
+}
+
+/// Take the shared import lock on a dedicated connection, so readers can
+/// tell an import is running. Concurrent imports share it. Skipped in
+/// transaction-pooling mode, where a session lock would outlive the import
+/// on a pooled server connection.
+pub async fn lock_import(pool: &Pool) -> Result<Option<Client>> {
+    if pool.transaction_pooling() {
+        return Ok(None);
+    }
+    let client = pool.get().await?;
+    client
+        .execute("SELECT pg_advisory_lock_shared($1)", &[&client.settings().lock_key])
+        .await?;
+    Ok(Some(client))
+}
+
+/// Release the lock taken by [`lock_import`]
+pub async fn unlock_import(client: Option<Client>) -> Result<()> {
+    if let Some(client) = client {
+        client
+            .execute("SELECT pg_advisory_unlock_shared($1)", &[&client.settings().lock_key])
+            .await?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn import_running(client: &Client) -> Result<bool> {
+    let row = client
+        .query_one(
+            "SELECT EXISTS (SELECT 1 FROM pg_locks \
+             WHERE locktype = 'advisory' AND granted AND objsubid = 1 \
+             AND database = (SELECT oid FROM pg_database WHERE datname = current_database()) \
+             AND ((classid::bigint << 32) | objid::bigint) = $1)",
+            &[&client.settings().lock_key],
+        )
+        .await?;
+    Ok(row.get(0))
+}
+
+/// Make readers aware of an in-progress import: with `wait`, poll until it
+/// finishes; otherwise warn what the read will see. A bulk import holds the
+/// table exclusively until it commits, so the read blocks; a
+/// `--batch-commit-every` import has committed part of its rows; any other
+/// has committed none yet. A missing primary key is reported by
+/// [`init_schema`].
+pub async fn check_import(client: &Client, wait: bool, silent: bool) -> Result<()> {
+    if import_running(client).await? {
+        if !wait {
+            if !silent {
+                warn!(
+                    "an import is in progress: a bulk import blocks this read until it commits, \
+                     a --batch-commit-every import shows only its committed batches and any other \
+                     shows none of its rows yet (use --wait to wait for it to finish)"
+                );
+            }
+        } else {
+            if !silent {
+                info!("Waiting for the in-progress import to finish...");
+            }
+            while import_running(client).await? {
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Open a transaction in transaction-pooling mode so the statements that
+/// follow (and their prepare/execute round trips) share one server
+/// connection. A no-op in session mode; pair with [`unpin`].
+pub async fn pin(client: &Client) -> Result<()> {
+    if client.transaction_pooling() {
+        client.batch_execute("BEGIN").await?;
+    }
+    Ok(())
+}
+
+/// Close the transaction opened by [`pin`]
+pub async fn unpin(client: &Client) -> Result<()> {
+    if client.transaction_pooling() {
+        client.batch_execute("COMMIT").await?;
+    }
+    Ok(())
+}
+
+/// A client-side filtered read stopped by `--max-rows`
+#[derive(Debug)]
+pub struct RowCapExceeded {
+    pub rows: u64,
+    pub cap: u64,
+}
+
+impl std::fmt::Display for RowCapExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Filtered read would scan {} rows, above the --max-rows cap of {}; \
+             use a tighter filter or pass --force",
+            self.rows, self.cap
+        )
+    }
+}
+
+impl std::error::Error for RowCapExceeded {}
+
+/// Abort a client-side filtered read that would stream more than `cap`
+/// rows: those the SQL part of `filter` lets through
+pub async fn check_row_cap(client: &Client, filter: &DomainFilter, cap: Option<u64>) -> Result<()> {
+    let Some(cap) = cap else {
+        return Ok(());
+    };
+
+    let sql = format!("SELECT COUNT(*) FROM domains{}", filter.where_clause());
+    let rows = query(client, &sql, &filter.params()).await?[0].get::<_, i64>(0) as u64;
+    if rows > cap {
+        return Err(RowCapExceeded { rows, cap }.into());
+    }
+    Ok(())
+}
+
+/// Rename stored domains (`old[i]` becomes `new[i]`) keeping what is
+/// known about them. A rename that collides with nothing is an `UPDATE` of
+/// the key. Rows that meet an existing entry, or each other, merge into one:
+/// the earliest `first_seen` and latest `last_seen` win, and when the
+/// metadata columns exist the surviving entry keeps its own `program` and
+/// `source` (taking the merged rows' when it has none) and the union of all
+/// tags. Returns how many rows merged away.
+pub async fn rename_domains(tx: &tokio_postgres::Transaction<'_>, old: &[String], new: &[String]) -> Result<u64> {
+    if old.is_empty() {
+        return Ok(0);
+    }
+    let row = tx
+        .query_one(
+            "SELECT COUNT(*) FROM information_schema.columns \
+             WHERE table_schema = current_schema() AND table_name = 'domains' \
+             AND column_name IN ('program', 'source', 'tags')",
+            &[],
+        )
+        .await?;
+    let metadata = row.get::<_, i64>(0) == 3;
+
+    let pairs = "WITH pairs AS (SELECT * FROM unnest($1::text[], $2::text[]) AS p(old, new))";
+    tx.execute(
+        &format!(
+            "{}, sole AS (SELECT new FROM pairs GROUP BY new HAVING COUNT(*) = 1) \
+             UPDATE domains d SET domain = p.new FROM pairs p JOIN sole s ON s.new = p.new \
+             WHERE d.domain = p.old AND NOT EXISTS (SELECT 1 FROM domains e WHERE e.domain = p.new)",
+            pairs
+        ),
+        &[&old, &new],
+    )
+    .await?;
+
+    // Whatever was not renamed merges into its target
+    let (columns, returned, merged, updates) = if metadata {
+        (
+            ", program, source, tags",
+            ", d.program, d.source, d.tags",
+            ", MAX(program), MAX(source), array_agg(DISTINCT tag ORDER BY tag) FILTER (WHERE tag IS NOT NULL)",
+            ", program = COALESCE(domains.program, EXCLUDED.program), \
+             source = COALESCE(domains.source, EXCLUDED.source), \
+             tags = CASE WHEN EXCLUDED.tags IS NULL THEN domains.tags ELSE ARRAY( \
+                 SELECT DISTINCT t FROM unnest(COALESCE(domains.tags, '{}') || EXCLUDED.tags) AS t ORDER BY t) END",
+        )
+    } else {
+        ("", "", "", "")
+    };
+    let tags = if metadata { "LEFT JOIN LATERAL unnest(moved.tags) AS tag ON true" } else { "" };
+    let rows = tx
+        .query(
+            &format!(
+                "{pairs}, moved AS ( \
+                     DELETE FROM domains d USING pairs p WHERE d.domain = p.old \
+                     RETURNING p.new AS domain, d.first_seen, d.last_seen{returned}) \
+                 INSERT INTO domains (domain, first_seen, last_seen{columns}) \
+                 SELECT domain, MIN(first_seen), MAX(last_seen){merged} FROM moved {tags} GROUP BY domain \
+                 ON CONFLICT (domain) DO UPDATE SET \
+                     first_seen = LEAST(domains.first_seen, EXCLUDED.first_seen), \
+                     last_seen = GREATEST(domains.last_seen, EXCLUDED.last_seen){updates} \
+                 RETURNING xmax = 0, (SELECT COUNT(*) FROM moved m WHERE m.domain = domains.domain)"
+            ),
+            &[&old, &new],
+        )
+        .await?;
+    // Each target absorbs its moved rows, less one if it is new
+    Ok(rows.iter().map(|r| (r.get::<_, i64>(1) - i64::from(r.get::<_, bool>(0))) as u64).sum())
+}
+
+/// Whether the domains table has no rows; the `EXISTS` probe stops at the
+/// first row, so this is cheap on any table size
+pub async fn is_empty(client: &Client) -> Result<bool> {
+    let row = client
+        .query_one("SELECT EXISTS(SELECT 1 FROM domains LIMIT 1)", &[])
+        .await?;
+    let has_data: bool = row.get(0);
+    Ok(!has_data)
+}
+
+/// First-run hint for reads that came back empty. Returns whether the hint
+/// was printed; under `--silent` the probe is skipped altogether.
+pub async fn hint_if_empty(client: &Client, silent: bool) -> Result<bool> {
+    if silent || !is_empty(client).await? {
+        return Ok(false);
+    }
+    info!("Database is empty; run `add` to import domains");
+    Ok(true)
+}
+
+/// Throwaway schemas for the database tests, in the database at
+/// `BOUNTYCATCH_TEST_DATABASE_URL` (a `postgres://` URL) or a container
+/// started for the run. Each test gets its own schema, so tests can run in parallel and never touch the
+/// database's own domains table.
+#[cfg(test)]
+pub(crate) mod scratch {
+    use super::*;
+    use std::sync::OnceLock;
+    use testcontainers_modules::postgres::Postgres;
+    use testcontainers_modules::testcontainers::runners::SyncRunner;
+    use testcontainers_modules::testcontainers::Container;
+
+    pub(crate) struct Scratch {
+        pub pool: Pool,
+        schema: String,
+    }
+
+    /// `BOUNTYCATCH_TEST_DATABASE_URL`, or else a PostgreSQL container
+    /// started for this test binary. Panics when neither is available, so a
+    /// machine without a database fails the tests instead of skipping them.
+    pub(crate) fn database_url() -> String {
+        static CONTAINER: OnceLock<(Container<Postgres>, String)> = OnceLock::new();
+        if let Ok(url) = std::env::var("BOUNTYCATCH_TEST_DATABASE_URL") {
+            return url;
+        }
+        // The blocking runner needs a thread outside the test's runtime
+        let (_, url) = CONTAINER.get_or_init(|| {
+            std::thread::spawn(start_container).join().unwrap_or_else(|e| std::panic::resume_unwind(e))
+        });
+        url.clone()
+    }
+
+    fn start_container() -> (Container<Postgres>, String) {
+        let container = Postgres::default().start().unwrap_or_else(|e| {
+            panic!(
+                "the database tests need PostgreSQL: set BOUNTYCATCH_TEST_DATABASE_URL to a postgres:// URL \
+                 or make Docker available for a throwaway container ({})",
+                e
+            )
+        });
+        let host = container.get_host().unwrap();
+        let port = container.get_host_port_ipv4(5432).unwrap();
+        let url = format!("postgres://postgres:postgres@{}:{}/postgres", host, port);
+        (container, url)
+    }
+
+    impl Scratch {
+        /// Empty schema named `bc_test_<name>` in the [`database_url`]
+        /// database
+        pub async fn empty(name: &str) -> Scratch {
+            let url = database_url();
+            let dsn = crate::dsn::parse(&url).expect("BOUNTYCATCH_TEST_DATABASE_URL is not a valid URL");
+            let schema = format!("bc_test_{}", name);
+
+            let mut cfg = Config::new();
+            cfg.host = dsn.host;
+            cfg.port = dsn.port;
+            cfg.dbname = dsn.database;
+            cfg.user = dsn.user;
+            cfg.password = dsn.password;
+            cfg.options = Some(format!("-c search_path={}", schema));
+            // A lock key per schema, as projects get, so a test holding the
+            // import lock doesn't make repairs in other tests stand down
+            let settings = Settings {
+                lock_key: import_lock_key(Some(&schema)),
+                schema: Some(schema.clone()),
+                ..Settings::default()
+            };
+            let pool = Pool::new(cfg.create_pool(Some(Runtime::Tokio1), NoTls).unwrap(), settings);
+            pool.get()
+                .await
+                .unwrap_or_else(|e| panic!("cannot connect to the test database {}: {}", url, e))
+                .batch_execute(&format!("DROP SCHEMA IF EXISTS {0} CASCADE; CREATE SCHEMA {0}", schema))
+                .await
+                .unwrap();
+            Scratch { pool, schema }
+        }
+
+        /// Schema with the table `init_schema` creates
+        pub async fn initialized(name: &str) -> Scratch {
+            let scratch = Scratch::empty(name).await;
+            init_schema(&scratch.pool, false, false, true).await.unwrap();
+            scratch
+        }
+
+        pub async fn drop(self) {
+            let client = self.pool.get().await.unwrap();
+            client.batch_execute(&format!("DROP SCHEMA {} CASCADE", self.schema)).await.unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::scratch::Scratch;
+    use super::*;
+
+    #[test]
+    fn test_project_schema() {
+        assert_eq!(project_schema("default").unwrap(), None);
+        assert_eq!(project_schema("tesla_2").unwrap().as_deref(), Some("project_tesla_2"));
+        for bad in ["", "Tesla", "2tesla", "tes-la", "a;b"] {
+            assert!(project_schema(bad).is_err(), "{}", bad);
+        }
+        assert_eq!(import_lock_key(None), IMPORT_LOCK_KEY);
+        assert_ne!(import_lock_key(Some("project_a")), import_lock_key(Some("project_b")));
+    }
+
+    async fn has_pkey(client: &Client) -> bool {
+        let row = client
+            .query_one("SELECT to_regclass('domains_pkey') IS NOT NULL", &[])
+            .await
+            .unwrap();
+        row.get(0)
+    }
+
+    #[tokio::test]
+    async fn test_repair_rebuilds_missing_primary_key() {
+        let scratch = Scratch::empty("pkey_repair").await;
+        let client = scratch.pool.get().await.unwrap();
+        client.batch_execute("CREATE TABLE domains (domain TEXT)").await.unwrap();
+        client
+            .batch_execute("INSERT INTO domains VALUES ('a.example.com'), ('b.example.com')")
+            .await
+            .unwrap();
+
+        check_primary_key(&client, false, true).await.unwrap();
+        assert!(!has_pkey(&client).await);
+        check_primary_key(&client, true, true).await.unwrap();
+        assert!(has_pkey(&client).await);
+
+        drop(client);
+        scratch.drop().await;
+    }
+
+    #[tokio::test]
+    async fn test_repair_refuses_with_duplicates() {
+        let scratch = Scratch::empty("pkey_duplicates").await;
+        let client = scratch.pool.get().await.unwrap();
+        client.batch_execute("CREATE TABLE domains (domain TEXT)").await.unwrap();
+        client
+            .batch_execute("INSERT INTO domains VALUES ('a.example.com'), ('a.example.com')")
+            .await
+            .unwrap();
+
+        let err = check_primary_key(&client, true, true).await.unwrap_err();
+        assert!(err.to_string().contains("1 duplicate rows"));
+        assert!(!has_pkey(&client).await);
+
+        drop(client);
+        scratch.drop().await;
+    }
+
+    #[tokio::test]
+    async fn test_require_project() {
+        let scratch = Scratch::empty("require_project").await;
+        require_project(&scratch.pool).await.unwrap();
+        let client = scratch.pool.get().await.unwrap();
+        client.batch_execute("DROP SCHEMA bc_test_require_project").await.unwrap();
+        let err = require_project(&scratch.pool).await.unwrap_err();
+        assert_eq!(err.to_string(), "Unknown project 'bc_test_require_project' (see `bountycatch projects`)");
+    }
+
+    #[tokio::test]
+    async fn test_row_cap_counts_pushed_down_rows() {
+        let scratch = Scratch::initialized("row_cap").await;
+        let client = scratch.pool.get().await.unwrap();
+        client
+            .batch_execute(
+                "INSERT INTO domains (domain) VALUES ('a1.example.com'), ('b.example.com'), \
+                 ('c2.other.net'), ('d.other.net'), ('e.other.net')",
+            )
+            .await
+            .unwrap();
+
+        // `example` runs in SQL, `\d` only client-side
+        let filter = DomainFilter::from_flags(&["example".into()], &[r"\d".into()], Some("and"), false, false).unwrap();
+        assert!(filter.has_residual());
+        check_row_cap(&client, &filter, Some(2)).await.unwrap();
+        let err = check_row_cap(&client, &filter, Some(1)).await.unwrap_err();
+        let err = err.downcast::<RowCapExceeded>().unwrap();
+        assert_eq!((err.rows, err.cap), (2, 1));
+
+        drop(client);
+        scratch.drop().await;
+    }
+
+    #[tokio::test]
+    async fn test_rename_keeps_sightings_and_metadata() {
+        let scratch = Scratch::empty("rename").await;
+        let mut client = scratch.pool.get().await.unwrap();
+        client.batch_execute("CREATE TABLE domains (domain TEXT)").await.unwrap();
+        client
+            .batch_execute(
+                "ALTER TABLE domains ADD PRIMARY KEY (domain), ADD COLUMN first_seen TIMESTAMPTZ, \
+                 ADD COLUMN last_seen TIMESTAMPTZ, ADD COLUMN program TEXT, ADD COLUMN source TEXT, \
+                 ADD COLUMN tags TEXT[]; \
+                 INSERT INTO domains VALUES \
+                     ('Solo.example.com', '2020-01-01', '2021-01-01', 'acme', 'amass', '{a}'), \
+                     ('Api.example.com', '2019-01-01', '2020-01-01', NULL, 'subfinder', '{b}'), \
+                     ('api.example.com', '2022-01-01', '2023-01-01', 'acme', NULL, '{c}'), \
+                     ('WWW.example.com', '2018-01-01', '2018-06-01', NULL, NULL, NULL), \
+                     ('Www.example.com', '2017-01-01', '2019-01-01', 'beta', NULL, '{d}')",
+            )
+            .await
+            .unwrap();
+
+        let tx = client.transaction().await.unwrap();
+        let old: Vec<String> = ["Solo.example.com", "Api.example.com", "WWW.example.com", "Www.example.com"]
+            .map(String::from)
+            .to_vec();
+        let new: Vec<String> = old.iter().map(|d| d.to_lowercase()).collect();
+        assert_eq!(rename_domains(&tx, &old, &new).await.unwrap(), 2);
+        tx.commit().await.unwrap();
+
+        let rows = client
+            .query(
+                "SELECT domain, first_seen::date::text, last_seen::date::text, program, source, tags \
+                 FROM domains ORDER BY domain",
+                &[],
+            )
+            .await
+            .unwrap();
+        type Stored = (String, String, String, Option<String>, Option<String>, Option<Vec<String>>);
+        let stored: Vec<Stored> =
+            rows.iter().map(|r| (r.get(0), r.get(1), r.get(2), r.get(3), r.get(4), r.get(5))).collect();
+        let row = |d: &str, f: &str, l: &str, p: Option<&str>, s: Option<&str>, t: &[&str]| {
+            let tags = (!t.is_empty()).then(|| t.iter().map(|t| t.to_string()).collect());
+            (d.to_string(), f.to_string(), l.to_string(), p.map(String::from), s.map(String::from), tags)
+        };
+        assert_eq!(
+            stored,
+            [
+                // Merged into the existing entry: its own program, the other's source
+                row("api.example.com", "2019-01-01", "2023-01-01", Some("acme"), Some("subfinder"), &["b", "c"]),
+                // Renamed in place
+                row("solo.example.com", "2020-01-01", "2021-01-01", Some("acme"), Some("amass"), &["a"]),
+                // Two renames onto the same new entry
+                row("www.example.com", "2017-01-01", "2019-01-01", Some("beta"), None, &["d"]),
+            ]
+        );
+
+        drop(client);
+        scratch.drop().await;
+    }
+}
@@ -1,7 +1,13 @@
 use lazy_static::lazy_static;
 use regex::Regex;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+use crate::psl::SuffixList;
 
 lazy_static! {
+    /// The built-in public suffix list behind [`apex_of`]
+    static ref SUFFIXES: SuffixList = SuffixList::builtin();
     static ref DOMAIN_PATTERN: Regex = Regex::new(
         r"^(?:(?:\*\.)?(?:[a-zA-Z0-9_*](?:[a-zA-Z0-9_*-]{0,61}[a-zA-Z0-9_*])?\.)+[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?)$"
     ).unwrap();
@@ -9,6 +15,10 @@ lazy_static! {
 
 #[inline]
 pub fn is_valid_domain(domain: &str) -> bool {
+    // Internationalized domains are checked in their `xn--` form
+    if !domain.is_ascii() {
+        return to_idna_ascii(domain).is_some_and(|ascii| ascii.is_ascii() && is_valid_domain(&ascii));
+    }
     if domain.is_empty() || domain.len() > 253 {
         return false;
     }
@@ -29,16 +39,306 @@ pub fn is_valid_domain(domain: &str) -> bool {
     DOMAIN_PATTERN.is_match(domain)
 }
 
+/// Strip `#` / `//` comments from an input line.
+///
+/// Returns `None` when the whole line is a comment, otherwise the trimmed
+/// text before any inline `# note` annotation.
+pub fn strip_comment(line: &str) -> Option<&str> {
+    let line = line.trim();
+    if line.starts_with('#') || line.starts_with("//") {
+        return None;
+    }
+
+    match line.find('#') {
+        Some(idx) => Some(line[..idx].trim_end()),
+        None => Some(line),
+    }
+}
+
+/// Whether an input entry contains a control character (tab, CR, NUL, ...).
+/// Such entries are never imported, even with `--no-validate`.
+pub fn has_control_chars(entry: &str) -> bool {
+    // Byte scan first: C1 controls are the only non-ASCII ones
+    entry.bytes().any(|b| b < 0x20 || b == 0x7f) || (!entry.is_ascii() && entry.contains(char::is_control))
+}
+
+/// Drop entries that differ only by ASCII case, keeping the first-seen casing.
+pub fn dedup_case_insensitive(domains: Vec<String>) -> Vec<String> {
+    let mut seen: HashSet<String> = HashSet::with_capacity(domains.len());
+    domains
+        .into_iter()
+        .filter(|d| seen.insert(d.to_ascii_lowercase()))
+        .collect()
+}
+
+/// The bare host of a `www.` host (`www.example.com` -> `example.com`), or
+/// `None` when there is no `www.` label or nothing but a TLD after it.
+pub fn strip_www(domain: &str) -> Option<&str> {
+    let rest = domain
+        .get(..4)
+        .filter(|prefix| prefix.eq_ignore_ascii_case("www."))
+        .and(domain.get(4..))?;
+    rest.contains('.').then_some(rest)
+}
+
+/// The other spelling of `domain` when it is not in the canonical form:
+/// its bare host when `prefer_www` is off, its `www.` host when it is on.
+fn www_counterpart(domain: &str, prefer_www: bool) -> Option<String> {
+    match (strip_www(domain), prefer_www) {
+        (Some(bare), false) => Some(bare.to_string()),
+        (None, true) => Some(format!("www.{}", domain)),
+        _ => None,
+    }
+}
+
+/// Counterparts to look up in storage before calling [`fold_www`],
+/// lowercased as `fold_www` compares them
+pub fn www_counterparts(domains: &[String], prefer_www: bool) -> Vec<String> {
+    domains
+        .iter()
+        .filter_map(|d| www_counterpart(&d.to_ascii_lowercase(), prefer_www))
+        .collect()
+}
+
+/// Drop each domain whose canonical spelling is also in the input or in
+/// `stored` (lowercased), so only one of `www.example.com`/`example.com`
+/// remains. Hosts compare case-insensitively, so `WWW.Example.com` folds
+/// into `example.com`, but kept domains keep their spelling for
+/// `--preserve-input-case`. Returns the kept domains and how many were
+/// folded away.
+pub fn fold_www(domains: Vec<String>, prefer_www: bool, stored: &HashSet<String>) -> (Vec<String>, u64) {
+    let input: HashSet<String> = domains.iter().map(|d| d.to_ascii_lowercase()).collect();
+    let before = domains.len();
+    let kept: Vec<String> = domains
+        .into_iter()
+        .filter(|d| {
+            !www_counterpart(&d.to_ascii_lowercase(), prefer_www)
+                .is_some_and(|c| input.contains(&c) || stored.contains(&c))
+        })
+        .collect();
+    let folded = (before - kept.len()) as u64;
+    (kept, folded)
+}
+
+/// Remove a single trailing root dot (`example.com.` -> `example.com`).
+pub fn strip_trailing_dot(domain: &str) -> &str {
+    domain.strip_suffix('.').unwrap_or(domain)
+}
+
+/// Reduce a URL-shaped entry to its host
+/// (`https://user@example.com:8443/login?x` -> `example.com`).
+/// Bare hosts are returned unchanged.
+pub fn strip_url(entry: &str) -> &str {
+    let rest = match entry.find("://") {
+        Some(idx) => &entry[idx + 3..],
+        None => entry,
+    };
+    let authority = &rest[..rest.find(['/', '?', '#']).unwrap_or(rest.len())];
+    let host = authority.rsplit_once('@').map_or(authority, |(_, h)| h);
+    host.split_once(':').map_or(host, |(h, _)| h)
+}
+
+/// The lowercased host of an input entry, with any scheme, credentials,
+/// port, path and a single trailing dot removed
+/// (`https://Example.com:443/login` -> `example.com`). Borrows when the
+/// entry is already a bare lowercase host.
+pub fn normalize_host(entry: &str) -> Cow<'_, str> {
+    let host = strip_trailing_dot(strip_url(entry));
+    if host.chars().any(char::is_uppercase) {
+        Cow::Owned(host.to_lowercase())
+    } else {
+        Cow::Borrowed(host)
+    }
+}
+
+/// The ASCII (`xn--`) form of a possibly internationalized domain
+/// (`münchen.de` -> `xn--mnchen-3ya.de`) by UTS #46 processing, which also
+/// applies the RFC 5893 bidi rules. ASCII input is returned untouched.
+/// Returns `None` when a label breaks the bidi rules or cannot be encoded.
+/// Mixed-script labels are allowed, as in IDNA2008; confusable checks are
+/// left to registries.
+pub fn to_idna_ascii(domain: &str) -> Option<String> {
+    if domain.is_ascii() {
+        return Some(domain.to_string());
+    }
+    idna::domain_to_ascii(domain).ok()
+}
+
+/// Registrable domain (eTLD+1) of a host by the built-in public suffix
+/// list (`api.example.co.uk` -> `example.co.uk`, `x.acme.github.io` ->
+/// `acme.github.io`). A leading `*.` is ignored, case is kept, and a host
+/// that is itself a public suffix is returned whole.
+pub fn apex_of(domain: &str) -> &str {
+    let host = domain.strip_prefix("*.").unwrap_or(domain);
+    match SUFFIXES.registrable(&host.to_ascii_lowercase()) {
+        // ASCII lowercasing keeps byte offsets, so the apex is a suffix of `host`
+        Some(apex) => &host[host.len() - apex.len()..],
+        None => host,
+    }
+}
+
+/// Labels left of the apex (`api.dev.example.com` -> `api`, `dev`); a
+/// leading `*` wildcard label is not included
+pub fn subdomain_labels(domain: &str) -> impl Iterator<Item = &str> {
+    let host = domain.strip_prefix("*.").unwrap_or(domain);
+    let apex = apex_of(host);
+    let sub = host[..host.len() - apex.len()].trim_end_matches('.');
+    sub.split('.').filter(|label| !label.is_empty())
+}
+
+/// Public suffix of a host (`example.co.uk` -> `co.uk`), by the same list
+/// as [`apex_of`].
+pub fn public_suffix_of(domain: &str) -> &str {
+    let host = domain.strip_prefix("*.").unwrap_or(domain);
+    let suffix = SUFFIXES.public_suffix(&host.to_ascii_lowercase()).len();
+    &host[host.len() - suffix..]
+}
+
+/// Scope rules on public suffixes applied at add time.
+///
+/// An entry matches a suffix equal to it or ending in `.<entry>`, so `uk`
+/// covers `co.uk` while `co.uk` covers only `co.uk`. A domain passes when it
+/// matches the allow list (if any) and does not match the deny list.
+#[derive(Default)]
+pub struct TldFilter {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl TldFilter {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn permits(&self, domain: &str) -> bool {
+        let suffix = public_suffix_of(domain).to_ascii_lowercase();
+        let matches = |entry: &String| {
+            let entry = entry.trim_start_matches('.').to_ascii_lowercase();
+            suffix == entry || suffix.ends_with(&format!(".{}", entry))
+        };
+        (self.allow.is_empty() || self.allow.iter().any(matches)) && !self.deny.iter().any(matches)
+    }
+}
+
+/// Whether `host` falls under a bug bounty scope `rule`. `*.example.com`
+/// covers every subdomain but not `example.com` itself; any other rule
+/// covers exactly that host. Case and a trailing root dot are ignored.
+pub fn scope_rule_matches(rule: &str, host: &str) -> bool {
+    let rule = strip_trailing_dot(rule).to_ascii_lowercase();
+    let host = strip_trailing_dot(host).to_ascii_lowercase();
+    match rule.strip_prefix("*.") {
+        Some(base) => host.ends_with(&format!(".{}", base)),
+        None => host == rule,
+    }
+}
+
+/// Whether one of `wildcards` (`*.apex` entries) strictly covers `domain`.
+///
+/// A wildcard covers every host and narrower wildcard below its base, but not
+/// the base itself or the wildcard entry itself, so the remaining set stays
+/// equivalent when covered entries are dropped.
+pub fn covered_by_wildcard(domain: &str, wildcards: &HashSet<String>) -> bool {
+    let host = domain.strip_prefix("*.").unwrap_or(domain);
+    let mut rest = host;
+    while let Some(idx) = rest.find('.') {
+        rest = &rest[idx + 1..];
+        if wildcards.contains(&format!("*.{}", rest)) {
+            return true;
+        }
+    }
+    false
+}
+
+/// One representative host per apex, chosen as hosts stream past, so
+/// memory grows with the number of apexes rather than hosts.
+///
+/// `rule` is `shortest` (fewest characters, ties alphabetical), `first`
+/// (alphabetically first) or `apex` (the registrable domain itself).
+pub struct ApexReps<'r> {
+    rule: &'r str,
+    reps: HashMap<String, String>,
+}
+
+impl<'r> ApexReps<'r> {
+    pub fn new(rule: &'r str) -> Self {
+        Self { rule, reps: HashMap::new() }
+    }
+
+    pub fn push(&mut self, domain: &str) {
+        let apex = apex_of(domain);
+        let Some(current) = self.reps.get_mut(apex) else {
+            self.reps.insert(apex.to_string(), domain.to_string());
+            return;
+        };
+        let better = match self.rule {
+            "shortest" => (domain.len(), domain) < (current.len(), current.as_str()),
+            _ => domain < current.as_str(),
+        };
+        if better {
+            domain.clone_into(current);
+        }
+    }
+
+    /// The representatives, ordered by apex
+    pub fn into_sorted(self) -> Vec<String> {
+        let mut reps: Vec<(String, String)> = self.reps.into_iter().collect();
+        reps.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let apex = self.rule == "apex";
+        reps.into_iter().map(|(a, rep)| if apex { a } else { rep }).collect()
+    }
+}
+
+/// Reduce a host list to one representative per apex, ordered by apex;
+/// see [`ApexReps`] for the rules
+pub fn one_per_apex<'a, I>(domains: I, rule: &str) -> Vec<String>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut reps = ApexReps::new(rule);
+    for domain in domains {
+        reps.push(domain);
+    }
+    reps.into_sorted()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_strip_www() {
+        assert_eq!(strip_www("www.example.com"), Some("example.com"));
+        assert_eq!(strip_www("WWW.Example.com"), Some("Example.com"));
+        assert_eq!(strip_www("www.com"), None);
+        assert_eq!(strip_www("www2.example.com"), None);
+        assert_eq!(strip_www("api.www.example.com"), None);
+    }
+
+    #[test]
+    fn test_fold_www() {
+        let input = |ds: &[&str]| ds.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+        let stored: HashSet<String> = ["www.stored.com".to_string()].into();
+
+        let (kept, folded) = fold_www(input(&["www.a.com", "a.com", "www.b.com"]), false, &stored);
+        assert_eq!((kept, folded), (input(&["a.com", "www.b.com"]), 1));
+
+        let (kept, folded) = fold_www(input(&["www.a.com", "a.com", "stored.com", "c.com"]), true, &stored);
+        assert_eq!((kept, folded), (input(&["www.a.com", "c.com"]), 2));
+
+        let (kept, folded) = fold_www(input(&["WWW.A.com", "a.COM", "Stored.com", "API.b.com"]), true, &stored);
+        assert_eq!((kept, folded), (input(&["WWW.A.com", "API.b.com"]), 2));
+        assert_eq!(www_counterparts(&input(&["WWW.B.com"]), false), input(&["b.com"]));
+    }
+
     #[test]
     fn test_valid_domains() {
         assert!(is_valid_domain("example.com"));
         assert!(is_valid_domain("sub.example.com"));
         assert!(is_valid_domain("*.example.com"));
         assert!(is_valid_domain("_service.example.com"));
+        // IPv4 literals pass as dotted labels, IPv6 ones do not
+        assert!(is_valid_domain("10.1.2.3"));
+        assert!(!is_valid_domain("2001:db8::1"));
         assert!(is_valid_domain("svc-*.domain.com"));
     }
 
@@ -51,4 +351,185 @@ mod tests {
         assert!(!is_valid_domain("domain.*"));
         assert!(!is_valid_domain("-.example.com"));
     }
+
+    #[test]
+    fn test_strip_comment() {
+        assert_eq!(strip_comment("# scope for acme"), None);
+        assert_eq!(strip_comment("  // old entries"), None);
+        assert_eq!(strip_comment("api.example.com # prod"), Some("api.example.com"));
+        assert_eq!(strip_comment("api.example.com"), Some("api.example.com"));
+        assert_eq!(strip_comment(""), Some(""));
+    }
+
+    #[test]
+    fn test_has_control_chars() {
+        assert!(!has_control_chars("api.example.com"));
+        assert!(!has_control_chars("ünïcode.example"));
+        assert!(has_control_chars("api\texample.com"));
+        assert!(has_control_chars("a\rb.example.com"));
+        assert!(has_control_chars("nul\0.example.com"));
+        assert!(has_control_chars("esc\x1b[0m.example.com"));
+        assert!(has_control_chars("del\x7f.example.com"));
+        assert!(has_control_chars("nel\u{85}.example.com"));
+    }
+
+    #[test]
+    fn test_dedup_case_insensitive_keeps_first_seen() {
+        let input = vec![
+            "API.Example.com".to_string(),
+            "api.example.com".to_string(),
+            "www.example.com".to_string(),
+            "WWW.EXAMPLE.COM".to_string(),
+        ];
+        assert_eq!(
+            dedup_case_insensitive(input),
+            vec!["API.Example.com".to_string(), "www.example.com".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_dedup_case_insensitive_preserves_order() {
+        let input = vec!["b.com".to_string(), "A.com".to_string(), "a.COM".to_string()];
+        assert_eq!(
+            dedup_case_insensitive(input),
+            vec!["b.com".to_string(), "A.com".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_strip_trailing_dot() {
+        assert_eq!(strip_trailing_dot("example.com."), "example.com");
+        assert_eq!(strip_trailing_dot("example.com"), "example.com");
+        assert_eq!(strip_trailing_dot(""), "");
+    }
+
+    #[test]
+    fn test_normalize_host() {
+        assert_eq!(normalize_host("API.Example.COM"), "api.example.com");
+        assert_eq!(normalize_host("https://Example.com:443/login"), "example.com");
+        assert_eq!(normalize_host("http://api.example.com/a/b?x=1"), "api.example.com");
+        assert_eq!(normalize_host("example.com:8080"), "example.com");
+        assert_eq!(normalize_host("EXAMPLE.COM."), "example.com");
+        assert_eq!(normalize_host("*.Example.com"), "*.example.com");
+        assert!(matches!(normalize_host("api.example.com"), Cow::Borrowed("api.example.com")));
+        assert_eq!(normalize_host("https://"), "");
+    }
+
+    #[test]
+    fn test_strip_url() {
+        assert_eq!(strip_url("https://user@example.com:8443/login?x=1"), "example.com");
+        assert_eq!(strip_url("http://api.example.com#frag"), "api.example.com");
+        assert_eq!(strip_url("example.com/path"), "example.com");
+        assert_eq!(strip_url("example.com"), "example.com");
+    }
+
+    #[test]
+    fn test_to_idna_ascii() {
+        assert_eq!(to_idna_ascii("bücher.example").as_deref(), Some("xn--bcher-kva.example"));
+        assert_eq!(to_idna_ascii("api.MÜNCHEN.de").as_deref(), Some("api.xn--mnchen-3ya.de"));
+        assert_eq!(to_idna_ascii("plain.example.com").as_deref(), Some("plain.example.com"));
+        assert!(is_valid_domain("münchen.de"));
+        assert!(is_valid_domain("xn--mnchen-3ya.de"));
+        assert!(!is_valid_domain("mün chen.de"));
+
+        // Mixed scripts (a Cyrillic `а` in a Latin label) are encoded as is
+        assert!(to_idna_ascii("pаypal.com").is_some_and(|d| d.starts_with("xn--")));
+        assert!(to_idna_ascii("שלום.example").is_some());
+        assert!(to_idna_ascii("مثال٣.example").is_some());
+
+        // Bidi violations
+        assert_eq!(to_idna_ascii("abcשלום.com"), None);
+        assert_eq!(to_idna_ascii("1שלום.com"), None);
+        assert_eq!(to_idna_ascii("שלום-.com"), None);
+        assert_eq!(to_idna_ascii("مثال1٣.com"), None);
+        assert!(!is_valid_domain("abcשלום.com"));
+    }
+
+    #[test]
+    fn test_apex_of() {
+        assert_eq!(apex_of("api.dev.example.com"), "example.com");
+        assert_eq!(apex_of("example.com"), "example.com");
+        assert_eq!(apex_of("*.shop.example.co.uk"), "example.co.uk");
+        assert_eq!(apex_of("www.example.de"), "example.de");
+        assert_eq!(apex_of("localhost"), "localhost");
+        assert_eq!(apex_of("x.acme.github.io"), "acme.github.io");
+        assert_eq!(apex_of("API.Example.COM"), "Example.COM");
+        assert_eq!(apex_of("co.uk"), "co.uk");
+    }
+
+    #[test]
+    fn test_subdomain_labels() {
+        let labels = |d| subdomain_labels(d).collect::<Vec<_>>();
+        assert_eq!(labels("api.dev.example.com"), ["api", "dev"]);
+        assert_eq!(labels("*.svc-*.example.co.uk"), ["svc-*"]);
+        assert!(labels("example.com").is_empty());
+        assert!(labels("*.example.com").is_empty());
+    }
+
+    #[test]
+    fn test_one_per_apex_rules() {
+        let hosts = ["www.example.com", "a.b.example.com", "zz.example.com", "api.other.org"];
+        assert_eq!(
+            one_per_apex(hosts, "shortest"),
+            vec!["zz.example.com".to_string(), "api.other.org".to_string()]
+        );
+        assert_eq!(
+            one_per_apex(hosts, "first"),
+            vec!["a.b.example.com".to_string(), "api.other.org".to_string()]
+        );
+        assert_eq!(
+            one_per_apex(hosts, "apex"),
+            vec!["example.com".to_string(), "other.org".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_public_suffix_of() {
+        assert_eq!(public_suffix_of("api.example.com"), "com");
+        assert_eq!(public_suffix_of("shop.example.co.uk"), "co.uk");
+        assert_eq!(public_suffix_of("localhost"), "localhost");
+        assert_eq!(public_suffix_of("x.acme.github.io"), "github.io");
+    }
+
+    #[test]
+    fn test_scope_rule_matches() {
+        assert!(scope_rule_matches("example.com", "Example.com."));
+        assert!(!scope_rule_matches("example.com", "api.example.com"));
+        assert!(scope_rule_matches("*.example.com", "api.example.com"));
+        assert!(scope_rule_matches("*.example.com", "a.b.example.com"));
+        assert!(!scope_rule_matches("*.example.com", "example.com"));
+        assert!(!scope_rule_matches("*.example.com", "badexample.com"));
+    }
+
+    #[test]
+    fn test_covered_by_wildcard() {
+        let wildcards: HashSet<String> = ["*.example.com".to_string()].into();
+        assert!(covered_by_wildcard("api.example.com", &wildcards));
+        assert!(covered_by_wildcard("a.b.example.com", &wildcards));
+        assert!(covered_by_wildcard("*.dev.example.com", &wildcards));
+        assert!(!covered_by_wildcard("*.example.com", &wildcards));
+        assert!(!covered_by_wildcard("example.com", &wildcards));
+        assert!(!covered_by_wildcard("badexample.com", &wildcards));
+    }
+
+    #[test]
+    fn test_tld_filter_allow_and_deny() {
+        let allow = TldFilter { allow: vec!["com".into(), "co.uk".into()], deny: vec![] };
+        assert!(allow.permits("api.example.com"));
+        assert!(allow.permits("a.example.co.uk"));
+        assert!(!allow.permits("example.net"));
+        assert!(!allow.permits("example.org.uk"));
+
+        let deny = TldFilter { allow: vec![], deny: vec!["uk".into()] };
+        assert!(deny.permits("example.com"));
+        assert!(!deny.permits("example.co.uk"));
+        assert!(!deny.permits("example.uk"));
+
+        assert!(TldFilter::default().permits("anything.test"));
+
+        // Private suffixes from the suffix list count as TLDs too
+        let pages = TldFilter { allow: vec!["github.io".into()], deny: vec![] };
+        assert!(pages.permits("x.acme.github.io"));
+        assert!(!pages.permits("github.com"));
+    }
 }
@@ -0,0 +1,129 @@
+//! Domain lists subtracted from read output: `export --diff-against` and
+//! `print`/`export --exclude-file`.
+//!
+//! Filtered reads check each row against the in-memory set; the unfiltered
+//! `COPY` fast paths load it into a temp table and anti-join server-side.
+
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::io::BufRead;
+use std::path::Path;
+use std::pin::pin;
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::types::Type;
+
+use crate::db;
+use crate::input;
+
+/// The domain column of every stored row not in `temp_exclude`
+pub const COPY_OUT: &str = "COPY (SELECT domain FROM domains d \
+     WHERE NOT EXISTS (SELECT 1 FROM temp_exclude e WHERE e.domain = d.domain)) TO STDOUT";
+
+/// Domains listed in the file given to `flag`, one per line; gzip and
+/// zstd files are decompressed (see [`input`])
+pub fn load(path: &Path, flag: &str) -> Result<HashSet<String>> {
+    let reader = input::open(Some(path), 64 * 1024).with_context(|| format!("Failed to read {} file", flag))?;
+    read_domain_set(reader).with_context(|| format!("Failed to read {} file {:?}", flag, path))
+}
+
+fn read_domain_set(reader: impl BufRead) -> Result<HashSet<String>> {
+    let mut domains = HashSet::new();
+    for line in reader.lines() {
+        let line = line?;
+        let domain = line.trim();
+        if !domain.is_empty() {
+            domains.insert(domain.to_string());
+        }
+    }
+    Ok(domains)
+}
+
+/// Copy `domains` into the `temp_exclude` table that [`COPY_OUT`] reads.
+/// The table lives in a transaction that [`release`] ends; in
+/// transaction-pooling mode that is the one opened by [`db::pin`].
+pub async fn load_table(client: &db::Client, domains: &HashSet<String>) -> Result<()> {
+    if !client.transaction_pooling() {
+        client.batch_execute("BEGIN").await?;
+    }
+    client
+        .batch_execute("CREATE TEMP TABLE temp_exclude (domain TEXT) ON COMMIT DROP")
+        .await?;
+    let sink = client
+        .copy_in("COPY temp_exclude (domain) FROM STDIN (FORMAT binary)")
+        .await?;
+    let mut writer = pin!(BinaryCopyInWriter::new(sink, &[Type::TEXT]));
+    for domain in domains {
+        writer.as_mut().write(&[domain]).await?;
+    }
+    writer.finish().await?;
+    // Temp tables are never auto-analyzed; the anti-join plan needs the row count
+    client.batch_execute("ANALYZE temp_exclude").await?;
+    Ok(())
+}
+
+/// End the transaction opened by [`load_table`], dropping the table
+pub async fn release(client: &db::Client) -> Result<()> {
+    if !client.transaction_pooling() {
+        client.batch_execute("COMMIT").await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::scratch::Scratch;
+    use futures_util::StreamExt;
+    use std::io::Cursor;
+
+    fn temp_file(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("bountycatch-exclude-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_read_domain_set() {
+        let set = read_domain_set(Cursor::new("a.example.com\n\n  b.example.com \r\na.example.com\n   \n")).unwrap();
+        let mut domains: Vec<&str> = set.iter().map(String::as_str).collect();
+        domains.sort_unstable();
+        assert_eq!(domains, ["a.example.com", "b.example.com"]);
+        assert!(read_domain_set(Cursor::new("")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_load() {
+        let path = temp_file("plain.txt");
+        std::fs::write(&path, "a.example.com\nb.example.com\n").unwrap();
+        let set = load(&path, "--exclude-file").unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(set.len(), 2);
+        assert!(set.contains("b.example.com"));
+
+        let error = load(&temp_file("missing.txt"), "--diff-against").unwrap_err();
+        assert!(format!("{:#}", error).contains("--diff-against"), "{:#}", error);
+    }
+
+    #[tokio::test]
+    async fn test_copy_out_skips_loaded_domains() {
+        let scratch = Scratch::initialized("exclude").await;
+        let client = scratch.pool.get().await.unwrap();
+        client
+            .batch_execute("INSERT INTO domains (domain) VALUES ('a.example.com'), ('b.example.com'), ('c.example.com')")
+            .await
+            .unwrap();
+
+        let excluded: HashSet<String> = ["b.example.com", "unknown.example.com"].iter().map(|d| d.to_string()).collect();
+        load_table(&client, &excluded).await.unwrap();
+        let mut data = Vec::new();
+        let mut stream = pin!(client.copy_out(COPY_OUT).await.unwrap());
+        while let Some(chunk) = stream.next().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        release(&client).await.unwrap();
+        let mut lines: Vec<&str> = std::str::from_utf8(&data).unwrap().lines().collect();
+        lines.sort_unstable();
+        assert_eq!(lines, ["a.example.com", "c.example.com"]);
+
+        drop(client);
+        scratch.drop().await;
+    }
+}
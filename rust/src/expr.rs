@@ -0,0 +1,287 @@
+//! Validator for the user-supplied `WHERE` fragment of `query --count-where`.
+//!
+//! The fragment is tokenized and parsed against a small grammar: boolean
+//! combinations (`AND`/`OR`/`NOT`, parentheses) of comparisons whose
+//! operands are the `domain` column, string or number literals, `||`
+//! concatenation and a handful of pure string functions. Anything else
+//! (other columns, subqueries, casts, comments, `;`) is rejected before the
+//! fragment reaches PostgreSQL.
+
+use anyhow::{bail, Result};
+
+/// Functions allowed in operands; all are immutable string functions
+const FUNCTIONS: &[&str] = &[
+    "lower", "upper", "length", "char_length", "reverse", "left", "right", "split_part", "strpos",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Str,
+    Num,
+    Sym(&'static str),
+}
+
+const SYMBOLS: &[&str] = &[
+    "!~*", "<>", "!=", "<=", ">=", "||", "~*", "!~", "(", ")", ",", "=", "<", ">", "~",
+];
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '\'' {
+            // String literal with '' as the only escape
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => bail!("unterminated string literal"),
+                    Some('\'') if chars.get(i + 1) == Some(&'\'') => i += 2,
+                    Some('\'') => break,
+                    Some(_) => i += 1,
+                }
+            }
+            i += 1;
+            tokens.push(Token::Str);
+        } else if c.is_ascii_digit() {
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            tokens.push(Token::Num);
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            tokens.push(Token::Word(word.to_ascii_lowercase()));
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 3)].iter().collect();
+            let Some(sym) = SYMBOLS.iter().find(|s| rest.starts_with(**s)) else {
+                bail!("character '{}' is not allowed", c);
+            };
+            i += sym.len();
+            tokens.push(Token::Sym(sym));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_word(&self, word: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w == word)
+    }
+
+    fn eat_word(&mut self, word: &str) -> bool {
+        if self.peek_word(word) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_sym(&mut self, sym: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Sym(s)) if *s == sym) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_sym(&mut self, sym: &str) -> Result<()> {
+        if !self.eat_sym(sym) {
+            bail!("expected '{}'", sym);
+        }
+        Ok(())
+    }
+
+    fn or_expr(&mut self) -> Result<()> {
+        self.and_expr()?;
+        while self.eat_word("or") {
+            self.and_expr()?;
+        }
+        Ok(())
+    }
+
+    fn and_expr(&mut self) -> Result<()> {
+        self.not_expr()?;
+        while self.eat_word("and") {
+            self.not_expr()?;
+        }
+        Ok(())
+    }
+
+    fn not_expr(&mut self) -> Result<()> {
+        if self.eat_word("not") {
+            return self.not_expr();
+        }
+        // A parenthesis may open a nested boolean expression or an operand;
+        // try the boolean reading first and backtrack if it fails
+        if matches!(self.peek(), Some(Token::Sym("("))) {
+            let saved = self.pos;
+            self.pos += 1;
+            if self.or_expr().is_ok() && self.eat_sym(")") && !self.at_operator() {
+                return Ok(());
+            }
+            self.pos = saved;
+        }
+        self.predicate()
+    }
+
+    /// Whether the next token continues an operand or comparison
+    fn at_operator(&self) -> bool {
+        match self.peek() {
+            Some(Token::Sym(s)) => *s != ")" && *s != ",",
+            Some(Token::Word(w)) => matches!(w.as_str(), "like" | "ilike" | "is" | "in" | "between" | "not"),
+            _ => false,
+        }
+    }
+
+    fn predicate(&mut self) -> Result<()> {
+        self.operand()?;
+
+        if self.eat_word("is") {
+            self.eat_word("not");
+            if !self.eat_word("null") {
+                bail!("expected NULL after IS");
+            }
+            return Ok(());
+        }
+
+        let negated = self.eat_word("not");
+        if self.eat_word("like") || self.eat_word("ilike") {
+            return self.operand();
+        }
+        if self.eat_word("in") {
+            self.expect_sym("(")?;
+            self.operand()?;
+            while self.eat_sym(",") {
+                self.operand()?;
+            }
+            return self.expect_sym(")");
+        }
+        if self.eat_word("between") {
+            self.operand()?;
+            if !self.eat_word("and") {
+                bail!("expected AND in BETWEEN");
+            }
+            return self.operand();
+        }
+        if negated {
+            bail!("expected LIKE, ILIKE, IN or BETWEEN after NOT");
+        }
+
+        for op in ["=", "<>", "!=", "<=", ">=", "<", ">", "~", "~*", "!~", "!~*"] {
+            if self.eat_sym(op) {
+                return self.operand();
+            }
+        }
+        bail!("expected a comparison; the fragment must be a boolean expression")
+    }
+
+    fn operand(&mut self) -> Result<()> {
+        self.primary()?;
+        while self.eat_sym("||") {
+            self.primary()?;
+        }
+        Ok(())
+    }
+
+    fn primary(&mut self) -> Result<()> {
+        match self.peek().cloned() {
+            Some(Token::Str) | Some(Token::Num) => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(Token::Word(w)) if w == "domain" => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(Token::Word(w)) if FUNCTIONS.contains(&w.as_str()) => {
+                self.pos += 1;
+                self.expect_sym("(")?;
+                self.operand()?;
+                while self.eat_sym(",") {
+                    self.operand()?;
+                }
+                self.expect_sym(")")
+            }
+            Some(Token::Sym("(")) => {
+                self.pos += 1;
+                self.operand()?;
+                self.expect_sym(")")
+            }
+            Some(Token::Word(w)) => bail!("'{}' is not allowed (only the domain column and {})", w, FUNCTIONS.join(", ")),
+            Some(Token::Sym(s)) => bail!("unexpected '{}'", s),
+            None => bail!("unexpected end of expression"),
+        }
+    }
+}
+
+/// Check that `fragment` is a pure boolean expression over `domain`
+pub fn validate_where(fragment: &str) -> Result<()> {
+    let tokens = tokenize(fragment)?;
+    if tokens.is_empty() {
+        bail!("empty expression");
+    }
+    let mut parser = Parser { tokens, pos: 0 };
+    parser.or_expr()?;
+    if parser.pos != parser.tokens.len() {
+        bail!("unexpected trailing input");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accepts_boolean_expressions() {
+        for ok in [
+            "domain LIKE '%.gov'",
+            "domain ~ '^api\\.' AND NOT domain LIKE '%.dev.%'",
+            "(domain LIKE 'a%' OR domain LIKE 'b%') AND length(domain) < 20",
+            "split_part(domain, '.', 1) IN ('api', 'www')",
+            "lower(domain) = 'it''s.example.com'",
+            "domain IS NOT NULL",
+        ] {
+            assert!(validate_where(ok).is_ok(), "{}", ok);
+        }
+    }
+
+    #[test]
+    fn test_rejects_everything_else() {
+        for bad in [
+            "",
+            "domain",
+            "domain LIKE '%' ; DELETE FROM domains",
+            "domain LIKE '%' -- comment",
+            "domain = (SELECT 'x')",
+            "program = 'x'",
+            "pg_sleep(10) IS NULL",
+            "domain::text = 'x'",
+            "domain LIKE $1",
+            "domain LIKE 'unterminated",
+            "1 = 1 UNION SELECT 1",
+        ] {
+            assert!(validate_where(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,449 @@
+//! Shared filter builder for the commands that take `--match` / `--regex`.
+//!
+//! Conditions PostgreSQL can evaluate exactly are pushed into the `WHERE`
+//! clause with bound parameters. `--regex` uses Rust regex syntax, which
+//! differs from PostgreSQL's; only patterns that match the same strings in
+//! both are pushed down to `~`, the rest stay client-side. With
+//! `--ignore-case`, `--match` becomes an `ILIKE` and `--regex` a `~*`.
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, NaiveDate, NaiveTime};
+use regex::{Regex, RegexBuilder};
+use tokio_postgres::types::{ToSql, Type};
+
+#[derive(Default)]
+pub struct DomainFilter {
+    conditions: Vec<String>,
+    params: Vec<String>,
+    /// Client-side tests: a row must pass at least one regex of every group
+    regex: Vec<Vec<Regex>>,
+    /// Keep the rows that fail the client-side tests instead
+    invert: bool,
+}
+
+/// One `--match` or `--regex` value
+struct Test {
+    /// Condition and parameter, when PostgreSQL can evaluate it exactly
+    sql: Option<(&'static str, String)>,
+    /// The same test client-side
+    regex: Regex,
+}
+
+impl DomainFilter {
+    /// Build the filter for the standard `--match` / `--regex` flags, each
+    /// repeatable. By default a domain must pass any `--match` and any
+    /// `--regex`; `logic` (`--filter-logic`) `and` requires every value and
+    /// `or` any one of them. `ignore_case` (`--ignore-case`) applies to all
+    /// of them and `invert` (`--invert`) negates the combined test.
+    pub fn from_flags(
+        matches: &[String],
+        regexes: &[String],
+        logic: Option<&str>,
+        ignore_case: bool,
+        invert: bool,
+    ) -> Result<Self> {
+        if invert && matches.is_empty() && regexes.is_empty() {
+            bail!("--invert needs --match or --regex");
+        }
+        let mut substrings = Vec::new();
+        for m in matches {
+            let sql = match ignore_case {
+                false => ("strpos(domain, {}) > 0", m.clone()),
+                true => ("domain ILIKE {}", like_substring(m)),
+            };
+            let regex = RegexBuilder::new(&regex::escape(m)).case_insensitive(ignore_case).build()?;
+            substrings.push(Test { sql: Some(sql), regex });
+        }
+        let mut patterns = Vec::new();
+        for pattern in regexes {
+            // Parsed either way, so an invalid pattern fails the same way
+            let regex = RegexBuilder::new(pattern).case_insensitive(ignore_case).build()?;
+            let operator = if ignore_case { "domain ~* {}" } else { "domain ~ {}" };
+            let sql = postgres_compatible(pattern).then(|| (operator, pattern.clone()));
+            patterns.push(Test { sql, regex });
+        }
+        let groups: Vec<Vec<Test>> = match logic {
+            Some("and") => substrings.into_iter().chain(patterns).map(|test| vec![test]).collect(),
+            Some("or") => vec![substrings.into_iter().chain(patterns).collect()],
+            _ => vec![substrings, patterns],
+        };
+        let groups = groups.into_iter().filter(|group| !group.is_empty());
+
+        let mut filter = Self::default();
+        let client_side = |group: &Vec<Test>| group.iter().any(|test| test.sql.is_none());
+        let (client, pushed): (Vec<_>, Vec<_>) = groups.partition(client_side);
+        if invert && !client.is_empty() {
+            // NOT (a AND b) needs every result, so the tests PostgreSQL
+            // could run move client-side too
+            filter.regex = pushed.into_iter().chain(client).map(into_regexes).collect();
+            filter.invert = true;
+            return Ok(filter);
+        }
+        filter.regex = client.into_iter().map(into_regexes).collect();
+        let nested = !invert || pushed.len() > 1;
+        for group in pushed {
+            let alternatives: Vec<String> = group
+                .into_iter()
+                .filter_map(|test| test.sql)
+                .map(|(condition, value)| filter.bind(condition, &value))
+                .collect();
+            filter.conditions.push(match alternatives.len() {
+                1 => alternatives.join(""),
+                _ if nested => format!("({})", alternatives.join(" OR ")),
+                _ => alternatives.join(" OR "),
+            });
+        }
+        if invert {
+            let tests = std::mem::take(&mut filter.conditions);
+            filter.conditions.push(format!("NOT ({})", tests.join(" AND ")));
+        }
+        Ok(filter)
+    }
+
+    /// Require whole dot-delimited labels (`--label`): every one of them, or
+    /// at least one with `any`. Evaluated server-side with `~*`.
+    pub fn push_labels(&mut self, labels: &[String], any: bool) {
+        if labels.is_empty() {
+            return;
+        }
+        if any {
+            self.push("domain ~* {}", &label_pattern(labels));
+        } else {
+            for label in labels {
+                self.push("domain ~* {}", &label_pattern(std::slice::from_ref(label)));
+            }
+        }
+    }
+
+    /// Only domains first seen within `since` (`--since 24h`). Rows stored
+    /// before sighting times were recorded never match.
+    pub fn push_since(&mut self, since: &str) -> Result<()> {
+        self.push("first_seen >= now() - {}::text::interval", &since_interval(since)?);
+        Ok(())
+    }
+
+    /// Only domains whose `column` (`first_seen` or `last_seen`) is before
+    /// `time`, a date (`2024-01-01`, midnight UTC) or an RFC 3339 timestamp.
+    /// Rows without a sighting time never match.
+    pub fn push_seen_before(&mut self, column: &str, time: &str) -> Result<()> {
+        let time = parse_time(time)?;
+        self.push(&format!("{} < {{}}::text::timestamptz", column), &time);
+        Ok(())
+    }
+
+    /// Add a SQL condition; `{}` stands for the next text parameter
+    pub fn push(&mut self, condition: &str, value: &str) {
+        let condition = self.bind(condition, value);
+        self.conditions.push(condition);
+    }
+
+    /// `condition` with `{}` replaced by the placeholder for `value`
+    fn bind(&mut self, condition: &str, value: &str) -> String {
+        self.params.push(value.to_string());
+        condition.replace("{}", &format!("${}", self.params.len()))
+    }
+
+    /// No filter flag is active
+    pub fn is_empty(&self) -> bool {
+        self.conditions.is_empty() && self.regex.is_empty()
+    }
+
+    /// Whether rows still need [`DomainFilter::matches`] after the query
+    pub fn has_residual(&self) -> bool {
+        !self.regex.is_empty()
+    }
+
+    /// ` WHERE a AND b`, or an empty string without SQL conditions
+    pub fn where_clause(&self) -> String {
+        if self.conditions.is_empty() {
+            String::new()
+        } else {
+            format!(" WHERE {}", self.conditions.join(" AND "))
+        }
+    }
+
+    /// `SELECT domain FROM domains` with the filter's `WHERE` clause
+    pub fn select(&self, sort: bool) -> String {
+        let order = if sort { " ORDER BY domain" } else { "" };
+        format!("SELECT domain FROM domains{}{}", self.where_clause(), order)
+    }
+
+    /// Parameters for the placeholders in [`DomainFilter::where_clause`]
+    pub fn params(&self) -> Vec<(&(dyn ToSql + Sync), Type)> {
+        self.params
+            .iter()
+            .map(|p| (p as &(dyn ToSql + Sync), Type::TEXT))
+            .collect()
+    }
+
+    /// Client-side part of the filter, applied to rows the query returned
+    pub fn matches(&self, domain: &str) -> bool {
+        self.regex.is_empty()
+            || self.regex.iter().all(|group| group.iter().any(|re| re.is_match(domain))) != self.invert
+    }
+}
+
+fn into_regexes(group: Vec<Test>) -> Vec<Regex> {
+    group.into_iter().map(|test| test.regex).collect()
+}
+
+/// `LIKE` pattern for `value` anywhere in the string, with the wildcards
+/// `%` and `_` (and the escape character) in `value` taken literally
+fn like_substring(value: &str) -> String {
+    let mut pattern = String::from("%");
+    for c in value.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+/// PostgreSQL regex matching any of `labels` between label boundaries, so
+/// `staging` matches `api.staging.example.com` but not `stagingx.example.com`
+fn label_pattern(labels: &[String]) -> String {
+    let alternatives: Vec<String> = labels
+        .iter()
+        .map(|label| {
+            label
+                .trim_matches('.')
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c.to_string() } else { format!("\\{}", c) })
+                .collect()
+        })
+        .collect();
+    format!(r"(^|\.)({})(\.|$)", alternatives.join("|"))
+}
+
+/// Escaped characters that are the same literal in both engines
+const PG_LITERAL_ESCAPES: &str = r".^$|?*+()[]{}\-/";
+
+/// Largest `{n,m}` bound PostgreSQL's regex engine accepts
+const PG_MAX_REPEAT: u32 = 255;
+
+/// Whether a (valid) Rust regex matches the same strings under PostgreSQL's
+/// `~`. Only a conservative subset qualifies: literals, `.`, anchors, plain
+/// groups, alternation, quantifiers and simple bracket classes, with
+/// backslash escapes limited to the metacharacters in `PG_LITERAL_ESCAPES`.
+/// Class escapes and assertions (`\d`, `\w`, `\b`, and `\<`/`\>`, which are
+/// word boundaries in Rust but literals in PostgreSQL), inline flags, nested
+/// classes and set operations differ between the engines and stay
+/// client-side. Greediness doesn't
+/// matter, since only whether a domain matches is used.
+fn postgres_compatible(pattern: &str) -> bool {
+    let mut chars = pattern.chars().peekable();
+    let mut in_class = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped) if PG_LITERAL_ESCAPES.contains(escaped) => {}
+                _ => return false,
+            },
+            ']' if in_class => in_class = false,
+            '[' if in_class => return false,
+            '&' | '-' | '~' if in_class && chars.peek() == Some(&c) => return false,
+            _ if in_class => {}
+            '[' => {
+                in_class = true;
+                chars.next_if_eq(&'^');
+                // A leading `]` is literal in Rust but needs care in PostgreSQL
+                if chars.peek() == Some(&']') {
+                    return false;
+                }
+            }
+            '(' if chars.peek() == Some(&'?') => return false,
+            '{' => {
+                let bounds: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                let (min, max) = bounds.split_once(',').unwrap_or((&bounds, &bounds));
+                let within = |n: &str| n.parse::<u32>().is_ok_and(|n| n <= PG_MAX_REPEAT);
+                if !within(min) || !(max.is_empty() || within(max)) {
+                    return false;
+                }
+            }
+            _ => {}
+        }
+    }
+    !in_class
+}
+
+/// RFC 3339 form of a `--added-before`/`--last-seen-before` value, so a
+/// typo fails here rather than as a PostgreSQL cast error
+fn parse_time(time: &str) -> Result<String> {
+    if let Ok(date) = NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        return Ok(date.and_time(NaiveTime::MIN).and_utc().to_rfc3339());
+    }
+    DateTime::parse_from_rfc3339(time)
+        .map(|t| t.to_rfc3339())
+        .map_err(|_| anyhow!("Invalid time '{}': expected a date (2024-01-01) or an RFC 3339 timestamp", time))
+}
+
+/// PostgreSQL interval for a `--since` duration: a whole number followed
+/// by `m` (minutes), `h` (hours), `d` (days) or `w` (weeks)
+fn since_interval(since: &str) -> Result<String> {
+    let split = since.find(|c: char| !c.is_ascii_digit()).unwrap_or(since.len());
+    let (amount, unit) = since.split_at(split);
+    let amount: u32 = amount
+        .parse()
+        .map_err(|_| anyhow!("Invalid --since '{}': expected a number and a unit, e.g. 24h or 7d", since))?;
+    let unit = match unit {
+        "m" => "minutes",
+        "h" => "hours",
+        "d" => "days",
+        "w" => "weeks",
+        _ => bail!("Invalid --since unit in '{}': use m, h, d or w", since),
+    };
+    Ok(format!("{} {}", amount, unit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_where_clause_numbers_params() {
+        let mut filter = DomainFilter::from_flags(&strings(&["api"]), &[], None, false, false).unwrap();
+        filter.push("domain > {}", "m");
+        assert_eq!(filter.where_clause(), " WHERE strpos(domain, $1) > 0 AND domain > $2");
+        assert_eq!(filter.params().len(), 2);
+        assert!(!filter.has_residual());
+    }
+
+    #[test]
+    fn test_label_conditions() {
+        assert_eq!(label_pattern(&["staging".into()]), r"(^|\.)(staging)(\.|$)");
+        assert_eq!(label_pattern(&["dev-1".into(), "qa".into()]), r"(^|\.)(dev\-1|qa)(\.|$)");
+
+        let mut filter = DomainFilter::default();
+        filter.push_labels(&["staging".into(), "api".into()], false);
+        assert_eq!(filter.where_clause(), " WHERE domain ~* $1 AND domain ~* $2");
+
+        let mut filter = DomainFilter::default();
+        filter.push_labels(&["staging".into(), "api".into()], true);
+        assert_eq!(filter.where_clause(), " WHERE domain ~* $1");
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("2024-01-01").unwrap(), "2024-01-01T00:00:00+00:00");
+        assert_eq!(parse_time("2024-03-05T10:30:00+02:00").unwrap(), "2024-03-05T10:30:00+02:00");
+        for bad in ["2024-13-01", "yesterday", "2024-01-01 10:00"] {
+            assert!(parse_time(bad).is_err(), "{}", bad);
+        }
+
+        let mut filter = DomainFilter::default();
+        filter.push_seen_before("first_seen", "2024-01-01").unwrap();
+        assert_eq!(filter.where_clause(), " WHERE first_seen < $1::text::timestamptz");
+    }
+
+    #[test]
+    fn test_since_interval() {
+        assert_eq!(since_interval("24h").unwrap(), "24 hours");
+        assert_eq!(since_interval("7d").unwrap(), "7 days");
+        assert_eq!(since_interval("90m").unwrap(), "90 minutes");
+        assert_eq!(since_interval("2w").unwrap(), "2 weeks");
+        for bad in ["", "h", "24", "24x", "1.5h", "-1d"] {
+            assert!(since_interval(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_empty_and_residual() {
+        let filter = DomainFilter::from_flags(&[], &[], None, false, false).unwrap();
+        assert!(filter.is_empty());
+        assert_eq!(filter.select(true), "SELECT domain FROM domains ORDER BY domain");
+
+        let filter = DomainFilter::from_flags(&[], &strings(&[r"^api\d+\."]), None, false, false).unwrap();
+        assert!(!filter.is_empty());
+        assert!(filter.has_residual());
+        assert!(filter.matches("api1.example.gov"));
+        assert!(!filter.matches("api.example.com"));
+
+        let filter = DomainFilter::from_flags(&strings(&["api"]), &strings(&[r"\.gov$"]), None, false, false).unwrap();
+        assert!(!filter.has_residual());
+        assert_eq!(filter.where_clause(), " WHERE strpos(domain, $1) > 0 AND domain ~ $2");
+        assert!(DomainFilter::from_flags(&[], &strings(&["("]), None, false, false).is_err());
+    }
+
+    #[test]
+    fn test_ignore_case() {
+        let filter = DomainFilter::from_flags(&strings(&["Admin"]), &strings(&[r"\.GOV$"]), None, true, false).unwrap();
+        assert_eq!(filter.where_clause(), " WHERE domain ILIKE $1 AND domain ~* $2");
+        assert_eq!(filter.params[0], "%Admin%");
+        assert!(!filter.has_residual());
+
+        let filter = DomainFilter::from_flags(&[], &strings(&[r"^API\d"]), None, true, false).unwrap();
+        assert!(filter.has_residual());
+        assert!(filter.matches("api1.example.com"));
+        let filter = DomainFilter::from_flags(&[], &strings(&[r"^API\d"]), None, false, false).unwrap();
+        assert!(!filter.matches("api1.example.com"));
+
+        assert_eq!(like_substring(r"a_b%c\d"), r"%a\_b\%c\\d%");
+    }
+
+    #[test]
+    fn test_invert() {
+        let filter = DomainFilter::from_flags(&strings(&["cdn"]), &strings(&[r"\.com$"]), None, false, true).unwrap();
+        assert_eq!(filter.where_clause(), " WHERE NOT (strpos(domain, $1) > 0 AND domain ~ $2)");
+        assert!(!filter.has_residual());
+
+        let filter = DomainFilter::from_flags(&strings(&["CDN"]), &strings(&[r"\d\."]), None, true, true).unwrap();
+        assert_eq!(filter.where_clause(), "");
+        assert!(filter.has_residual());
+        assert!(!filter.matches("cdn1.example.com"));
+        assert!(filter.matches("cdn.example.com"));
+        assert!(filter.matches("api1.example.com"));
+
+        assert!(DomainFilter::from_flags(&[], &[], None, false, true).is_err());
+    }
+
+    #[test]
+    fn test_filter_logic() {
+        let (dev, staging) = (r"\.dev$", r"\.staging$");
+        let filter = DomainFilter::from_flags(&[], &strings(&[dev, staging]), None, false, false).unwrap();
+        assert_eq!(filter.where_clause(), " WHERE (domain ~ $1 OR domain ~ $2)");
+
+        let filter = DomainFilter::from_flags(&strings(&["api", "www"]), &strings(&[dev]), None, false, false).unwrap();
+        assert_eq!(
+            filter.where_clause(),
+            " WHERE (strpos(domain, $1) > 0 OR strpos(domain, $2) > 0) AND domain ~ $3"
+        );
+        let filter = DomainFilter::from_flags(&strings(&["api", "www"]), &[], Some("and"), false, false).unwrap();
+        assert_eq!(filter.where_clause(), " WHERE strpos(domain, $1) > 0 AND strpos(domain, $2) > 0");
+        let filter = DomainFilter::from_flags(&strings(&["api"]), &strings(&[dev]), Some("or"), false, true).unwrap();
+        assert_eq!(filter.where_clause(), " WHERE NOT (strpos(domain, $1) > 0 OR domain ~ $2)");
+
+        // A group with a client-side regex is tested client-side as a whole
+        let (api, digit) = (strings(&["api"]), r"\d$");
+        let filter = DomainFilter::from_flags(&api, &strings(&[digit, dev]), None, false, false).unwrap();
+        assert_eq!(filter.where_clause(), " WHERE strpos(domain, $1) > 0");
+        assert!(filter.matches("api.example.dev"));
+        assert!(filter.matches("api.host1"));
+        assert!(!filter.matches("api.example.com"));
+
+        let filter = DomainFilter::from_flags(&api, &strings(&[digit]), Some("or"), false, false).unwrap();
+        assert_eq!(filter.where_clause(), "");
+        assert!(filter.matches("api.example.com"));
+        assert!(filter.matches("host1"));
+        assert!(!filter.matches("www.example.com"));
+    }
+
+    #[test]
+    fn test_postgres_compatible() {
+        for ok in [
+            r"\.gov$", r"^(api|dev)[0-9]*\.example\.com$", r"[^a-z.\-]", r"a{2}", r"x{1,3}?", r"b{2,}", "svc-.*",
+        ] {
+            assert!(postgres_compatible(ok), "{}", ok);
+        }
+        for client_side in [
+            r"\d+", r"\bapi\b", r"\<api", r"api\>", r"\#", r"(?i)API", r"[[:alpha:]]", r"[a-z&&[^x]]", r"[]a]", r"a{1000}", r"\p{L}", r"(?:a|b)",
+        ] {
+            assert!(!postgres_compatible(client_side), "{}", client_side);
+        }
+    }
+}
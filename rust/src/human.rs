@@ -0,0 +1,44 @@
+//! Human-friendly number formatting for stderr status summaries, enabled
+//! with the global `--human` flag. Command stdout is never affected.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// `n` with thousands separators when `--human` is set, otherwise bare
+pub fn count(n: u64) -> String {
+    if ENABLED.load(Ordering::Relaxed) {
+        with_separators(n)
+    } else {
+        n.to_string()
+    }
+}
+
+fn with_separators(n: u64) -> String {
+    let digits = n.to_string();
+    let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_separators() {
+        assert_eq!(with_separators(0), "0");
+        assert_eq!(with_separators(999), "999");
+        assert_eq!(with_separators(1000), "1,000");
+        assert_eq!(with_separators(52341234), "52,341,234");
+    }
+}
@@ -0,0 +1,302 @@
+//! Line input from a file or stdin, decompressed on the fly when it starts
+//! with gzip or zstd magic bytes: `add`/`remove` input and the
+//! `--exclude-file`/`--diff-against` lists.
+//!
+//! Compressed input is decoded in-process by streaming `flate2` and `zstd`
+//! decoders; uncompressed input is read directly.
+//!
+//! `add`/`remove` input can also be JSON (`--input-format`); [`domains`]
+//! turns it into the same one-domain-per-line stream as plain text.
+
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::MultiGzDecoder;
+use serde_json::Value;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// How input is compressed, told by its magic number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        })
+    }
+}
+
+/// The compression of data starting with `head`, if any
+fn detect(head: &[u8]) -> Option<Compression> {
+    if head.starts_with(GZIP_MAGIC) {
+        Some(Compression::Gzip)
+    } else if head.starts_with(ZSTD_MAGIC) {
+        Some(Compression::Zstd)
+    } else {
+        None
+    }
+}
+
+/// Up to the first four bytes of `reader`, enough for either magic number
+fn read_head(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(ZSTD_MAGIC.len());
+    reader.take(ZSTD_MAGIC.len() as u64).read_to_end(&mut head)?;
+    Ok(head)
+}
+
+/// How `path` is compressed, if it is
+pub fn compression(path: &Path) -> Result<Option<Compression>> {
+    let mut file = File::open(path)?;
+    Ok(detect(&read_head(&mut file)?))
+}
+
+/// Lines of `path`, or of stdin when `None`
+pub fn open(path: Option<&Path>, capacity: usize) -> Result<Box<dyn BufRead>> {
+    let Some(path) = path else {
+        return read(io::stdin(), capacity);
+    };
+    let mut file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let compression = detect(&read_head(&mut file)?);
+    file.seek(SeekFrom::Start(0))?;
+    decoded(compression, file, capacity)
+}
+
+/// Lines of a stream such as stdin, which is sniffed without seeking
+pub fn read(mut reader: impl Read + Send + 'static, capacity: usize) -> Result<Box<dyn BufRead>> {
+    let head = read_head(&mut reader)?;
+    decoded(detect(&head), Cursor::new(head).chain(reader), capacity)
+}
+
+/// `reader` buffered, through a decoder when it is compressed. Corrupt or
+/// truncated data is a read error, not a silent short read.
+fn decoded(
+    compression: Option<Compression>,
+    reader: impl Read + Send + 'static,
+    capacity: usize,
+) -> Result<Box<dyn BufRead>> {
+    Ok(match compression {
+        // Multi-member, so concatenated gzip files (`cat a.gz b.gz`) read whole
+        Some(Compression::Gzip) => Box::new(BufReader::with_capacity(capacity, MultiGzDecoder::new(reader))),
+        Some(Compression::Zstd) => Box::new(BufReader::with_capacity(
+            capacity,
+            zstd::stream::read::Decoder::new(reader).context("Failed to start the zstd decoder")?,
+        )),
+        None => Box::new(BufReader::with_capacity(capacity, reader)),
+    })
+}
+
+/// How `add`/`remove` input is laid out (`--input-format`)
+pub enum Format {
+    /// One domain per line
+    Text,
+    /// One JSON array of strings or objects
+    Json(Vec<String>),
+    /// One JSON string or object per line
+    JsonLines(Vec<String>),
+}
+
+impl Format {
+    /// `format` is `text`, `json` or `jsonl`. Objects are read by `key`
+    /// (`--json-key`), or by `domain` and then `host` without one.
+    pub fn new(format: &str, key: Option<String>) -> Result<Self> {
+        let keys = |key: Option<String>| key.map_or_else(|| vec!["domain".into(), "host".into()], |key| vec![key]);
+        match (format, key) {
+            ("json", key) => Ok(Self::Json(keys(key))),
+            ("jsonl", key) => Ok(Self::JsonLines(keys(key))),
+            (_, Some(_)) => bail!("--json-key only applies to --input-format json or jsonl"),
+            (_, None) => Ok(Self::Text),
+        }
+    }
+
+    pub fn is_text(&self) -> bool {
+        matches!(self, Self::Text)
+    }
+}
+
+/// `reader` as one domain per line. `jsonl` is converted as it streams;
+/// a `json` array is parsed whole first.
+pub fn domains(reader: Box<dyn BufRead>, format: &Format) -> Result<Box<dyn BufRead>> {
+    match format {
+        Format::Text => Ok(reader),
+        Format::Json(keys) => {
+            let document: Value = serde_json::from_reader(reader).context("Invalid JSON input")?;
+            let Value::Array(elements) = document else {
+                bail!("JSON input must be an array of domains or objects; use --input-format jsonl for one per line");
+            };
+            let mut lines = String::new();
+            for (index, element) in elements.iter().enumerate() {
+                let domain = domain_of(element, keys).with_context(|| format!("JSON input element {}", index))?;
+                lines.push_str(domain);
+                lines.push('\n');
+            }
+            Ok(Box::new(Cursor::new(lines.into_bytes())))
+        }
+        Format::JsonLines(keys) => Ok(Box::new(JsonLines {
+            inner: reader,
+            keys: keys.clone(),
+            line: String::new(),
+            line_no: 0,
+            out: Vec::new(),
+            pos: 0,
+        })),
+    }
+}
+
+/// The domain in a JSON string, or in an object under the first of `keys`
+/// it has
+fn domain_of<'a>(value: &'a Value, keys: &[String]) -> Result<&'a str> {
+    let domain = match value {
+        Value::String(domain) => domain,
+        Value::Object(object) => {
+            let Some(found) = keys.iter().find_map(|key| object.get(key)) else {
+                let names: Vec<String> = keys.iter().map(|key| format!("\"{}\"", key)).collect();
+                bail!("object has no {} key", names.join(" or "));
+            };
+            found.as_str().ok_or_else(|| anyhow!("expected a string domain, found {}", found))?
+        }
+        other => bail!("expected a string or an object, found {}", other),
+    };
+    if domain.contains(['\n', '\r']) {
+        bail!("domain {:?} contains a line break", domain);
+    }
+    Ok(domain)
+}
+
+/// JSON lines rewritten to bare domains one at a time
+struct JsonLines {
+    inner: Box<dyn BufRead>,
+    keys: Vec<String>,
+    line: String,
+    line_no: usize,
+    out: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for JsonLines {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.fill_buf()?.read(buf)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for JsonLines {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.pos == self.out.len() {
+            self.line.clear();
+            if self.inner.read_line(&mut self.line)? == 0 {
+                break;
+            }
+            self.line_no += 1;
+            let line = self.line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let line_no = self.line_no;
+            let invalid = |e: String| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("JSON input line {}: {}", line_no, e))
+            };
+            let value: Value = serde_json::from_str(line).map_err(|e| invalid(format!("invalid JSON: {}", e)))?;
+            let domain = domain_of(&value, &self.keys).map_err(|e| invalid(e.to_string()))?;
+            self.out.clear();
+            self.out.extend_from_slice(domain.as_bytes());
+            self.out.push(b'\n');
+            self.pos = 0;
+        }
+        Ok(&self.out[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.out.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(detect(&[0x1f, 0x8b, 0x08, 0x00]), Some(Compression::Gzip));
+        assert_eq!(detect(&[0x28, 0xb5, 0x2f, 0xfd]), Some(Compression::Zstd));
+        assert_eq!(detect(b"a.ex"), None);
+        assert_eq!(detect(&[0x1f]), None);
+        assert_eq!(detect(&[]), None);
+    }
+
+    fn compress(compression: Compression, data: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+        match compression {
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Zstd => zstd::encode_all(data, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_compressed_round_trip() {
+        let data = "a.example.com\nb.example.com\nc.example.com\n";
+        let expected = ["a.example.com", "b.example.com", "c.example.com"];
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let compressed = compress(compression, data.as_bytes());
+            let path =
+                std::env::temp_dir().join(format!("bountycatch-input-{}-{}", std::process::id(), compression));
+            std::fs::write(&path, &compressed).unwrap();
+            assert_eq!(super::compression(&path).unwrap(), Some(compression));
+            let from_file: Vec<String> = open(Some(&path), 64).unwrap().lines().collect::<io::Result<_>>().unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(from_file, expected, "{} file", compression);
+
+            let from_stream: Vec<String> =
+                read(Cursor::new(compressed.clone()), 64).unwrap().lines().collect::<io::Result<_>>().unwrap();
+            assert_eq!(from_stream, expected, "{} stream", compression);
+
+            // Truncated input fails at the end instead of reading short
+            let truncated = compressed[..compressed.len() - 4].to_vec();
+            let result: io::Result<Vec<String>> = read(Cursor::new(truncated), 64).unwrap().lines().collect();
+            assert!(result.is_err(), "{} truncated", compression);
+        }
+
+        // Concatenated gzip members read as one stream
+        let mut joined = compress(Compression::Gzip, b"a.example.com\n");
+        joined.extend(compress(Compression::Gzip, b"b.example.com\n"));
+        let lines: Vec<String> = read(Cursor::new(joined), 64).unwrap().lines().collect::<io::Result<_>>().unwrap();
+        assert_eq!(lines, ["a.example.com", "b.example.com"]);
+    }
+
+    fn lines(data: &str, format: &str, key: Option<&str>) -> Result<Vec<String>> {
+        let format = Format::new(format, key.map(str::to_string))?;
+        let reader = domains(Box::new(Cursor::new(data.as_bytes().to_vec())), &format)?;
+        Ok(reader.lines().collect::<io::Result<_>>()?)
+    }
+
+    #[test]
+    fn test_json_input() {
+        let expected = vec!["a.example.com".to_string(), "b.example.com".to_string(), "c.example.com".to_string()];
+        let array = r#"["a.example.com", {"domain": "b.example.com"}, {"host": "c.example.com", "ip": "1.2.3.4"}]"#;
+        assert_eq!(lines(array, "json", None).unwrap(), expected);
+        let jsonl = "{\"host\":\"a.example.com\"}\n\n\"b.example.com\"\n{\"host\":\"c.example.com\"}\n";
+        assert_eq!(lines(jsonl, "jsonl", None).unwrap(), expected);
+        assert_eq!(lines(r#"[{"name": "a.example.com"}]"#, "json", Some("name")).unwrap(), ["a.example.com"]);
+
+        let error = lines("{\"host\":\"a.example.com\"}\nnot json\n", "jsonl", None).unwrap_err();
+        assert!(error.to_string().contains("line 2: invalid JSON"), "{}", error);
+        assert!(lines(r#"{"domain": "a.example.com"}"#, "json", None).is_err());
+        assert!(lines(r#"[{"name": "a.example.com"}]"#, "json", None).is_err());
+        assert!(lines(r#"[1]"#, "json", None).is_err());
+        assert!(lines(r#"["a.example.com\nb.example.com"]"#, "json", None).is_err());
+        assert!(lines("a.example.com", "text", Some("host")).is_err());
+    }
+}
@@ -0,0 +1,44 @@
+//! Database password storage in the OS keyring (`--features keyring`).
+//!
+//! Goes through the `keyring` crate: the Secret Service (GNOME Keyring,
+//! KWallet) on Linux, the Keychain on macOS and the Credential Manager on
+//! Windows. Entries are keyed by service `bountycatch` and an account of
+//! `user@host:port/database`.
+
+use ::keyring::{Entry, Error};
+use anyhow::{bail, Context, Result};
+
+use crate::config::PostgresConfig;
+
+const SERVICE: &str = "bountycatch";
+
+/// Keyring account name for a connection target
+pub fn account(config: &PostgresConfig) -> String {
+    format!("{}@{}:{}/{}", config.user, config.host, config.port, config.database)
+}
+
+fn entry(account: &str) -> Result<Entry> {
+    Entry::new(SERVICE, account).with_context(|| format!("Cannot open the keyring entry for {}", account))
+}
+
+/// Stored password for `account`, or `None` when there is no entry
+pub fn get(account: &str) -> Result<Option<String>> {
+    match entry(account)?.get_password() {
+        Ok(password) => Ok((!password.is_empty()).then_some(password)),
+        Err(Error::NoEntry) => Ok(None),
+        Err(e) => Err(e).context("Keyring lookup failed"),
+    }
+}
+
+/// Create or replace the password for `account`
+pub fn set(account: &str, password: &str) -> Result<()> {
+    entry(account)?.set_password(password).context("Cannot store the password in the keyring")
+}
+
+/// Remove the password for `account`
+pub fn delete(account: &str) -> Result<()> {
+    match entry(account)?.delete_credential() {
+        Err(Error::NoEntry) => bail!("No keyring password stored for {}", account),
+        deleted => deleted.context("Cannot remove the password from the keyring"),
+    }
+}
@@ -0,0 +1,159 @@
+//! bountycatch as a library: the same ingest and query code the CLI runs,
+//! for recon tooling that would rather not shell out to it.
+//!
+//! ```no_run
+//! use bountycatch::{AddOptions, Config, ExportFormat, ExportOptions, Filter};
+//!
+//! # async fn demo() -> anyhow::Result<()> {
+//! let config = Config::load(None)?;
+//! let pool = bountycatch::create_pool(&config.postgresql).await?;
+//! bountycatch::init_schema(&pool, config.unlogged, false, true).await?;
+//!
+//! let input = std::io::Cursor::new("api.example.com\nwww.example.com\n");
+//! let summary = bountycatch::add_domains(&pool, input, AddOptions { silent: true, ..Default::default() }).await?;
+//! println!("{} new", summary.new);
+//!
+//! let filter = Filter { matches: vec!["example.com".into()], ..Default::default() };
+//! println!("{} stored", bountycatch::count(&pool, &filter).await?);
+//!
+//! let options = ExportOptions { format: ExportFormat::Jsonl, filter, sort: true };
+//! bountycatch::export(&pool, std::io::stdout().lock(), &options).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Status messages go through `tracing`, so they are only shown once a
+//! subscriber is installed (`logging::init` is the CLI's).
+
+mod cidr;
+pub mod config;
+mod csv;
+pub mod db;
+pub mod domain;
+mod dsn;
+mod exclude;
+mod expr;
+mod filter;
+pub mod human;
+pub mod input;
+#[cfg(feature = "keyring")]
+mod keyring;
+pub mod logging;
+pub mod output;
+#[cfg(feature = "parquet")]
+mod parquet;
+pub mod progress;
+mod prompt;
+mod psl;
+pub mod resolve;
+mod tls;
+pub mod webhook;
+mod wrap;
+pub mod commands;
+
+use anyhow::Result;
+use std::io::{BufRead, Write};
+
+pub use commands::add::{AddOptions, ChunkTuner, DedupStrategy, ImportSummary, Stamp, WwwForm};
+pub use config::Config;
+pub use db::{create_pool, init_schema, Pool};
+pub use domain::TldFilter;
+
+/// How several `matches`/`regexes` combine (`--filter-logic`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterLogic {
+    /// Every value must match
+    And,
+    /// Any one value may match
+    Or,
+}
+
+/// Which domains `count` and `export` see, as the `--match`, `--regex`,
+/// `--filter-logic`, `--ignore-case` and `--invert` flags select them. The
+/// default matches everything.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// Substrings of the domain
+    pub matches: Vec<String>,
+    /// Regular expressions over the domain
+    pub regexes: Vec<String>,
+    /// `None` requires any match and any regex
+    pub logic: Option<FilterLogic>,
+    pub ignore_case: bool,
+    /// Select the domains that do not match instead
+    pub invert: bool,
+}
+
+impl Filter {
+    fn build(&self) -> Result<filter::DomainFilter> {
+        let logic = self.logic.map(|logic| match logic {
+            FilterLogic::And => "and",
+            FilterLogic::Or => "or",
+        });
+        filter::DomainFilter::from_flags(&self.matches, &self.regexes, logic, self.ignore_case, self.invert)
+    }
+}
+
+/// Output formats of `export`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One domain per line
+    #[default]
+    Text,
+    /// One document with `domain_count`, `exported_at` and `domains`
+    Json,
+    /// One `{"domain": ...}` object per line
+    Jsonl,
+    /// `domain,first_seen,last_seen` with a header
+    Csv,
+    /// A Parquet file with the same columns as `Csv`
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl ExportFormat {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Json => "json",
+            Self::Jsonl => "jsonl",
+            Self::Csv => "csv",
+            #[cfg(feature = "parquet")]
+            Self::Parquet => "parquet",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    pub filter: Filter,
+    /// Order by domain instead of storage order
+    pub sort: bool,
+}
+
+/// Import the domains in `reader`, one per line, as `bountycatch add` would
+pub async fn add_domains<R: BufRead + 'static>(pool: &Pool, reader: R, opts: AddOptions) -> Result<ImportSummary> {
+    commands::add::import(pool, Box::new(reader), opts).await
+}
+
+/// How many stored domains pass `filter`
+pub async fn count(pool: &Pool, filter: &Filter) -> Result<u64> {
+    let filter = filter.build()?;
+    let client = pool.get().await?;
+    db::pin(&client).await?;
+    let count = commands::count::matching(&client, &filter, None).await?;
+    db::unpin(&client).await?;
+    Ok(count as u64)
+}
+
+/// Write the stored domains passing `opts.filter` to `writer`, returning
+/// how many were written
+pub async fn export<W: Write>(pool: &Pool, mut writer: W, opts: &ExportOptions) -> Result<u64> {
+    let filter = opts.filter.build()?;
+    let client = pool.get().await?;
+    db::pin(&client).await?;
+    let count = commands::export::write_matching(&client, &mut writer, opts.format.as_str(), &filter, opts.sort).await?;
+    db::unpin(&client).await?;
+    Ok(count)
+}
@@ -0,0 +1,172 @@
+//! Status messages on stderr through `tracing`: `--verbose`/`--silent` and
+//! `RUST_LOG` pick what is shown, `--log-format json` writes one object per
+//! line for CI.
+//!
+//! Both formats are `tracing_subscriber::fmt` layers. In the text format a
+//! message prints exactly as the bare `eprintln!` it replaces, prefixed with
+//! `Error:` or `Warning:` by level; its fields are for the JSON format. With
+//! `--verbose` both formats also report how long each span was open.
+//! Command output, the progress line and prompts never go through here.
+
+use std::fmt;
+use std::io;
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::fmt::format::{FmtSpan, Writer};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, MakeWriter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::EnvFilter;
+
+/// Install the stderr subscriber. `RUST_LOG` (`debug`, or per target as
+/// `bountycatch::commands::add=debug,info`) replaces the level `verbose`
+/// selects; `silent` keeps only errors either way.
+pub fn init(verbose: bool, silent: bool, format: &str) {
+    let subscriber = subscriber(filter(verbose), verbose, silent, format == "json", io::stderr);
+    // Only fails if a subscriber is already set, which leaves that one
+    let _ = tracing::subscriber::set_global_default(subscriber);
+}
+
+/// `RUST_LOG` when set, otherwise this crate's own messages at the level
+/// `verbose` selects and nothing from dependencies. Unknown directives are
+/// skipped rather than failing the command.
+fn filter(verbose: bool) -> EnvFilter {
+    match std::env::var("RUST_LOG") {
+        Ok(spec) if !spec.trim().is_empty() => EnvFilter::builder().parse_lossy(spec),
+        _ => {
+            let level = if verbose { LevelFilter::DEBUG } else { LevelFilter::INFO };
+            EnvFilter::new(format!("{}={}", env!("CARGO_CRATE_NAME"), level))
+        }
+    }
+}
+
+fn subscriber<W>(filter: EnvFilter, verbose: bool, silent: bool, json: bool, writer: W) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let spans = if verbose { FmtSpan::CLOSE } else { FmtSpan::NONE };
+    let cap = if silent { LevelFilter::ERROR } else { LevelFilter::TRACE };
+    let registry = tracing_subscriber::registry().with(filter).with(cap);
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer).with_span_events(spans);
+    if json {
+        Box::new(registry.with(layer.json().flatten_event(true).with_span_list(false)))
+    } else {
+        Box::new(registry.with(layer.with_ansi(false).event_format(Text)))
+    }
+}
+
+/// The bare message with a prefix for errors and warnings; a span close
+/// event becomes `<span> took <busy>`
+struct Text;
+
+impl<S, N> FormatEvent<S, N> for Text
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        if let (Some(busy), Some(span)) = (fields.busy, ctx.parent_span()) {
+            return writeln!(writer, "{} took {}", span.name(), busy);
+        }
+        match *event.metadata().level() {
+            Level::ERROR => writer.write_str("Error: ")?,
+            Level::WARN => writer.write_str("Warning: ")?,
+            _ => {}
+        }
+        writeln!(writer, "{}", fields.message)
+    }
+}
+
+/// The message of an event, and the busy time of a span close event
+#[derive(Default)]
+struct Fields {
+    message: String,
+    busy: Option<String>,
+}
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{:?}", value),
+            "time.busy" => self.busy = Some(format!("{:?}", value)),
+            _ => {}
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            "time.busy" => self.busy = Some(value.to_string()),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tracing::{debug, error, info, info_span, warn};
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn capture(spec: &str, verbose: bool, silent: bool, json: bool) -> String {
+        let out = Captured::default();
+        let writer = out.clone();
+        let subscriber = subscriber(EnvFilter::new(spec), verbose, silent, json, move || writer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            info_span!("add").in_scope(|| {
+                info!(lines = 3, "Adding 3 domains...");
+                debug!("Batch of 3");
+                warn!("import is in progress");
+                error!("Query failed");
+                info!(target: "tokio_postgres", "connected");
+            });
+        });
+        let bytes = out.0.lock().unwrap().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn test_text_format() {
+        let out = capture("bountycatch=info", false, false, false);
+        assert_eq!(out, "Adding 3 domains...\nWarning: import is in progress\nError: Query failed\n");
+
+        let out = capture("bountycatch=debug", true, false, false);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[1], "Batch of 3");
+        assert!(lines[4].starts_with("add took "), "{}", out);
+
+        let out = capture("warn,bountycatch::logging=debug", true, true, false);
+        assert_eq!(out, "Error: Query failed\n");
+    }
+
+    #[test]
+    fn test_json_format() {
+        let out = capture("bountycatch=info", false, false, true);
+        let lines: Vec<serde_json::Value> = out.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(lines[0]["target"], "bountycatch::logging::tests");
+        assert_eq!(lines[0]["message"], "Adding 3 domains...");
+        assert_eq!(lines[0]["lines"], 3);
+        assert_eq!(lines[0]["span"]["name"], "add");
+        assert!(lines[0]["timestamp"].is_string());
+        assert_eq!(lines[2]["level"], "ERROR");
+    }
+}
@@ -1,18 +1,19 @@
-mod config;
-mod db;
-mod domain;
-mod commands;
-
 use anyhow::Result;
+use bountycatch::{commands, config, db, domain, human, input, logging, output, progress, resolve, webhook};
+use bountycatch::output::OutputFormat;
+use bountycatch::{DedupStrategy, WwwForm};
+use clap::builder::RangedU64ValueParser;
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
+use tracing::{debug, error, info, info_span, Instrument};
 
 #[derive(Parser)]
 #[command(name = "bountycatch")]
 #[command(about = "Ultra-fast bug bounty domain management tool", long_about = None)]
 #[command(version)]
 struct Cli {
-    /// Configuration file path
+    /// Configuration file, or a directory of `*.json`/`*.toml`/`*.yaml` fragments
+    /// (default: $BOUNTYCATCH_CONFIG, then the standard locations)
     #[arg(short, long, global = true)]
     config: Option<PathBuf>,
 
@@ -24,6 +25,40 @@ struct Cli {
     #[arg(short, long, global = true)]
     silent: bool,
 
+    /// Keep the final summaries on stderr but drop step messages such as
+    /// "Deduplicating..."
+    #[arg(long, global = true, conflicts_with_all = ["verbose", "silent"])]
+    summary_only: bool,
+
+    /// Status message format on stderr: `text`, or `json` lines with
+    /// levels, fields and timings for CI
+    #[arg(long, global = true, value_name = "FORMAT", default_value = "text", value_parser = ["text", "json"])]
+    log_format: String,
+
+    /// Use thousands separators in status summaries (stdout stays bare)
+    #[arg(long, global = true)]
+    human: bool,
+
+    /// Connecting through PgBouncer in transaction-pooling mode
+    /// (same as `pooler_mode = "transaction"` in the config)
+    #[arg(long, global = true)]
+    pgbouncer: bool,
+
+    /// Send batch and filter statements unprepared, for poolers that
+    /// mishandle prepared statements (same as `prepared_statements = false`)
+    #[arg(long, global = true)]
+    no_prepare: bool,
+
+    /// Rebuild the domains primary key if it is missing (e.g. after an
+    /// interrupted bulk import); without this it is only reported
+    #[arg(long, global = true)]
+    repair: bool,
+
+    /// Project (bounty program) to work on; each has its own domains
+    /// (same as `project` in the config; default: `default`)
+    #[arg(long, global = true, value_name = "NAME")]
+    project: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -36,77 +71,733 @@ enum Commands {
         #[arg(short, long)]
         file: Option<PathBuf>,
 
-        /// Skip domain validation
+        /// Read domains from stdin (the default when no --file is given)
+        #[arg(long, conflicts_with_all = ["file", "resume"])]
+        stdin: bool,
+
+        /// Input layout: one domain per line, a JSON array, or JSON lines
+        #[arg(long, value_name = "FORMAT", default_value = "text", value_parser = ["text", "json", "jsonl"])]
+        input_format: String,
+
+        /// Object key holding the domain in JSON input (default: `domain`,
+        /// then `host`)
+        #[arg(long, value_name = "KEY")]
+        json_key: Option<String>,
+
+        /// Skip domain validation (lines with control characters are still rejected)
         #[arg(long)]
         no_validate: bool,
+
+        /// Skip `#`/`//` comment lines and strip inline `# note` annotations
+        #[arg(long)]
+        allow_comments: bool,
+
+        /// Store domains in their original case but dedupe case-insensitively
+        /// (first-seen casing wins)
+        #[arg(long)]
+        preserve_input_case: bool,
+
+        /// Reduce each entry to its lowercased host before validation:
+        /// scheme, port, path and a trailing dot are removed
+        /// (`https://Example.com:443/login` -> `example.com`)
+        #[arg(long, conflicts_with = "preserve_input_case")]
+        normalize: bool,
+
+        /// Store internationalized domains in their punycode form
+        /// (`münchen.de` -> `xn--mnchen-3ya.de`)
+        #[arg(long)]
+        idn: bool,
+
+        /// Commit after every N insert batches instead of once at the end
+        /// (forces the batch INSERT path; disables all-or-nothing semantics)
+        #[arg(long, value_name = "N", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+        batch_commit_every: Option<usize>,
+
+        /// Domains per INSERT batch [default: 10000, or `tuning.batch_size`]
+        #[arg(long, value_name = "N")]
+        batch_size: Option<usize>,
+
+        /// Size bulk COPY chunks so each flush takes about this long
+        /// [default: 1500, or `tuning.target_flush_ms`]
+        #[arg(long, value_name = "MS")]
+        target_flush_ms: Option<u64>,
+
+        /// Smallest bulk COPY chunk, in domains [default: 10000, or
+        /// `tuning.min_copy_chunk`]
+        #[arg(long, value_name = "N")]
+        min_copy_chunk: Option<usize>,
+
+        /// Largest bulk COPY chunk, in domains; bounds the memory of one
+        /// flush [default: 5000000, or `tuning.max_copy_chunk`]
+        #[arg(long, value_name = "N")]
+        max_copy_chunk: Option<usize>,
+
+        /// Checkpoint progress to `<file>.checkpoint` after every commit and
+        /// skip already-committed input on rerun (file input only)
+        #[arg(long, requires = "batch_commit_every")]
+        resume: bool,
+
+        /// How duplicates are resolved
+        #[arg(long, value_enum, default_value_t = DedupStrategy::Auto)]
+        dedup_strategy: DedupStrategy,
+
+        /// Validate only a random share of lines (e.g. 0.01 for 1%) to
+        /// estimate the invalid rate; unsampled lines are not validated
+        #[arg(long, value_name = "RATE", conflicts_with_all = ["no_validate", "resume"])]
+        validate_sample: Option<f64>,
+
+        /// Abort before writing anything if the sampled invalid rate
+        /// exceeds this percentage
+        #[arg(long, value_name = "PCT", requires = "validate_sample")]
+        abort_if_invalid_over: Option<f64>,
+
+        /// Stop importing after N valid domains (default: unlimited)
+        #[arg(long, value_name = "N")]
+        max_domains: Option<u64>,
+
+        /// Only accept domains under this public suffix (repeatable)
+        #[arg(long = "allow-tld", value_name = "TLD")]
+        allow_tlds: Vec<String>,
+
+        /// Reject domains under this public suffix (repeatable)
+        #[arg(long = "deny-tld", value_name = "TLD")]
+        deny_tlds: Vec<String>,
+
+        /// Stamp imported rows with this program name
+        #[arg(long)]
+        program: Option<String>,
+
+        /// Stamp imported rows with this source (e.g. the tool that found them)
+        #[arg(long)]
+        source: Option<String>,
+
+        /// Add this tag to imported rows (repeatable; merged with existing tags)
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+
+        /// Print domains from this import that were not already stored
+        #[arg(long, conflicts_with_all = ["resume", "print_duplicates"])]
+        print_new: bool,
+
+        /// Print domains from this import that were already stored
+        #[arg(long, conflicts_with = "resume")]
+        print_duplicates: bool,
+
+        /// Tee stdin to this file while importing, so a crashed import can be
+        /// rerun with `-f FILE`; deleted once the import succeeds
+        #[arg(long, value_name = "FILE", conflicts_with = "file")]
+        spool: Option<PathBuf>,
+
+        /// Record this import's counts and compare new domains with the
+        /// previous recorded run, warning on a sharp drop
+        #[arg(long)]
+        compare_previous: bool,
+
+        /// Rewrite `www.` hosts to their bare form (`www.example.com` ->
+        /// `example.com`)
+        #[arg(long, conflicts_with_all = ["resume", "fold_www"])]
+        strip_www: bool,
+
+        /// When both `www.X` and `X` would be stored, keep only this form
+        #[arg(long, value_enum, value_name = "FORM", conflicts_with = "resume")]
+        fold_www: Option<WwwForm>,
+
+        /// POST a JSON summary of the import to this URL when it completes
+        /// (builds with `--features http`)
+        #[arg(long, value_name = "URL")]
+        webhook: Option<String>,
+
+        /// Also POST to --webhook when the import fails
+        #[arg(long, requires = "webhook")]
+        webhook_on_failure: bool,
     },
 
     /// Print domains (supports filtering)
     Print {
-        /// Filter domains containing this substring
+        /// Only print stored domains listed in this file, one per line
+        /// (e.g. a --retry-file from an earlier run)
+        #[arg(short, long)]
+        file: Option<PathBuf>,
+
+        /// Leave out every domain listed in this file (one per line,
+        /// optionally gzip-compressed); nothing is deleted
+        #[arg(long, value_name = "FILE")]
+        exclude_file: Option<PathBuf>,
+
+        /// Filter domains containing this substring (repeatable)
+        #[arg(long)]
+        r#match: Vec<String>,
+
+        /// Filter domains matching this regex (repeatable)
+        #[arg(long)]
+        regex: Vec<String>,
+
+        /// How repeated --match/--regex values combine: `and` needs every
+        /// one, `or` any one (default: any --match and any --regex)
+        #[arg(long, value_name = "LOGIC", value_parser = ["and", "or"])]
+        filter_logic: Option<String>,
+
+        /// Match --match and --regex case-insensitively
         #[arg(long)]
-        r#match: Option<String>,
+        ignore_case: bool,
 
-        /// Filter domains matching this regex
+        /// Select the domains that fail the --match/--regex test instead
         #[arg(long)]
-        regex: Option<String>,
+        invert: bool,
+
+        /// Only domains with this whole dot-delimited label (repeatable)
+        #[arg(long, value_name = "LABEL")]
+        label: Vec<String>,
+
+        /// Whether domains need all of the --label values or any of them
+        #[arg(long, default_value = "all", value_parser = ["all", "any"], requires = "label")]
+        label_mode: String,
+
+        /// Only domains first seen within this long (e.g. 30m, 24h, 7d, 2w)
+        #[arg(long, value_name = "DURATION")]
+        since: Option<String>,
 
         /// Sort domains before printing
         #[arg(long)]
         sort: bool,
+
+        /// Emit one representative host per apex (eTLD+1)
+        #[arg(long)]
+        one_per_apex: bool,
+
+        /// Which host represents an apex with --one-per-apex
+        #[arg(long, default_value = "shortest", value_parser = ["shortest", "first", "apex"], requires = "one_per_apex")]
+        representative: String,
+
+        /// Print only the first N domains in sorted order
+        #[arg(long, value_name = "N")]
+        first: Option<usize>,
+
+        /// Print only the last N domains (still in ascending order)
+        #[arg(long, value_name = "N")]
+        last: Option<usize>,
+
+        /// Print at most N domains (with --sort, pages are deterministic)
+        #[arg(long, value_name = "N", conflicts_with_all = ["first", "last"])]
+        limit: Option<usize>,
+
+        /// Skip the first N matching domains
+        #[arg(long, value_name = "N", conflicts_with_all = ["first", "last"])]
+        offset: Option<usize>,
+
+        /// Abort when client-side filtering would stream more than N rows
+        /// (overrides `max_rows` in the config file)
+        #[arg(long, value_name = "N")]
+        max_rows: Option<u64>,
+
+        /// Ignore any --max-rows / config row cap
+        #[arg(long)]
+        force: bool,
+
+        /// Wait for an in-progress import to finish instead of warning
+        #[arg(long)]
+        wait: bool,
+
+        /// Only print domains that resolve (A/AAAA via the system resolver)
+        #[arg(long)]
+        resolve: bool,
+
+        /// With --resolve, append a tab and the comma-separated IPs
+        #[arg(long, requires = "resolve")]
+        show_ip: bool,
+
+        /// With --show-ip, also print unresolvable domains with an empty IP column
+        #[arg(long, requires = "show_ip")]
+        keep_unresolved: bool,
+
+        /// Concurrent lookups for --resolve
+        #[arg(long, value_name = "N", default_value_t = 50)]
+        jobs: usize,
+
+        /// With --resolve, write domains whose lookup timed out or errored here
+        /// (one per line, ready for `add`/`remove --file`)
+        #[arg(long, value_name = "FILE", requires = "resolve")]
+        retry_file: Option<PathBuf>,
+
+        /// Comma-separated columns to print: domain, program, source, tags
+        #[arg(long, value_name = "COLS", value_delimiter = ',',
+              conflicts_with_all = ["one_per_apex", "first", "last", "resolve"])]
+        columns: Vec<String>,
+
+        /// Output format: text (tab-separated columns), csv (with header) or
+        /// json (one array, streamed: domains, or objects with --columns)
+        #[arg(long, default_value = "text", value_parser = ["text", "csv", "json"],
+              conflicts_with_all = ["one_per_apex", "first", "last", "resolve"])]
+        format: String,
+
+        /// Text to put before each printed domain (e.g. `https://`)
+        #[arg(long, allow_hyphen_values = true, conflicts_with_all = ["columns", "format", "show_ip"])]
+        prefix: Option<String>,
+
+        /// Text to put after each printed domain (e.g. `/`)
+        #[arg(long, allow_hyphen_values = true, conflicts_with_all = ["columns", "format", "show_ip"])]
+        suffix: Option<String>,
+
+        /// Print only the stored `*.` wildcards covering this host, most
+        /// specific first
+        #[arg(long, value_name = "HOST", conflicts_with_all = ["file", "exclude_file", "match", "regex", "one_per_apex", "first", "last"])]
+        wildcards_covering: Option<String>,
     },
 
     /// Count domains in database
     Count {
-        /// Filter domains containing this substring
+        /// Filter domains containing this substring (repeatable)
+        #[arg(long)]
+        r#match: Vec<String>,
+
+        /// Filter domains matching this regex (repeatable)
         #[arg(long)]
-        r#match: Option<String>,
+        regex: Vec<String>,
+
+        /// How repeated --match/--regex values combine: `and` needs every
+        /// one, `or` any one (default: any --match and any --regex)
+        #[arg(long, value_name = "LOGIC", value_parser = ["and", "or"])]
+        filter_logic: Option<String>,
+
+        /// Match --match and --regex case-insensitively
+        #[arg(long)]
+        ignore_case: bool,
+
+        /// Select the domains that fail the --match/--regex test instead
+        #[arg(long)]
+        invert: bool,
+
+        /// Only domains with this whole dot-delimited label (repeatable)
+        #[arg(long, value_name = "LABEL")]
+        label: Vec<String>,
+
+        /// Whether domains need all of the --label values or any of them
+        #[arg(long, default_value = "all", value_parser = ["all", "any"], requires = "label")]
+        label_mode: String,
+
+        /// Only domains first seen within this long (e.g. 30m, 24h, 7d, 2w)
+        #[arg(long, value_name = "DURATION")]
+        since: Option<String>,
 
-        /// Filter domains matching this regex
+        /// Also print the total corpus size and the matched share
         #[arg(long)]
-        regex: Option<String>,
+        matched_percent: bool,
+
+        /// Print the most frequent subdomain labels (`www: 12000`)
+        /// instead of a count
+        #[arg(long, conflicts_with = "matched_percent")]
+        label_histogram: bool,
+
+        /// Number of labels --label-histogram lists
+        #[arg(long, default_value_t = 20, requires = "label_histogram")]
+        top: usize,
+
+        /// Only list labels seen at least N times (with --label-histogram)
+        #[arg(long, value_name = "N", default_value_t = 1, requires = "label_histogram")]
+        min_count: u64,
+
+        /// Output format: plain (bare number), kv (`count=N`) or json;
+        /// structured forms include the filter
+        #[arg(long, default_value = "plain", value_parser = ["plain", "text", "kv", "json"])]
+        format: String,
+
+        /// Abort when client-side filtering would stream more than N rows
+        /// (overrides `max_rows` in the config file)
+        #[arg(long, value_name = "N")]
+        max_rows: Option<u64>,
+
+        /// Ignore any --max-rows / config row cap
+        #[arg(long)]
+        force: bool,
+
+        /// Wait for an in-progress import to finish instead of warning
+        #[arg(long)]
+        wait: bool,
+    },
+
+    /// Summarize the dataset (totals, apexes, wildcards, top TLDs)
+    Stats {
+        /// Output format (text or json)
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+
+        /// Shorthand for `--format json`
+        #[arg(long, conflicts_with = "format")]
+        json: bool,
+
+        /// Number of TLDs to list
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+
+        /// Only list TLDs with at least N domains
+        #[arg(long, value_name = "N", default_value_t = 1)]
+        min_count: u64,
+
+        /// Append this snapshot as a JSON line to a history file
+        #[arg(long, value_name = "FILE")]
+        history: Option<PathBuf>,
+
+        /// Compare with an earlier `stats --format json` snapshot (or the
+        /// last record of a --history file) and list what changed
+        #[arg(long, value_name = "FILE")]
+        compare: Option<PathBuf>,
+
+        /// With --compare, exit with status 1 when the total or a baseline
+        /// TLD's count fell by more than this percentage (e.g. 10%)
+        #[arg(long, value_name = "PCT", requires = "compare")]
+        fail_if_drop: Option<String>,
     },
 
     /// Export domains to file
     Export {
         /// Output file
-        #[arg(short, long)]
-        file: PathBuf,
+        #[arg(short, long, required_unless_present = "partition_size", conflicts_with = "partition_size")]
+        file: Option<PathBuf>,
+
+        /// Split the export into numbered part files of at most N domains
+        #[arg(long, value_name = "N", requires = "out_dir")]
+        partition_size: Option<usize>,
+
+        /// Directory for `part-0001.txt`, `part-0002.txt`, ... (with --partition-size)
+        #[arg(long, value_name = "DIR", requires = "partition_size")]
+        out_dir: Option<PathBuf>,
 
-        /// Export format
-        #[arg(long, default_value = "text")]
+        /// Export format: text, json, jsonl (one `{"domain":...}` object per
+        /// line, streamed), csv (with a header row and the
+        /// first_seen/last_seen columns) or, in builds with the `parquet`
+        /// feature, parquet (the same columns, streamed in row groups)
+        #[cfg_attr(
+            feature = "parquet",
+            arg(long, default_value = "text", value_parser = ["text", "json", "jsonl", "csv", "parquet"])
+        )]
+        #[cfg_attr(
+            not(feature = "parquet"),
+            arg(long, default_value = "text", value_parser = ["text", "json", "jsonl", "csv"])
+        )]
         format: String,
 
-        /// Filter domains containing this substring
+        /// Filter domains containing this substring (repeatable)
         #[arg(long)]
-        r#match: Option<String>,
+        r#match: Vec<String>,
 
-        /// Filter domains matching this regex
+        /// Filter domains matching this regex (repeatable)
         #[arg(long)]
-        regex: Option<String>,
+        regex: Vec<String>,
+
+        /// How repeated --match/--regex values combine: `and` needs every
+        /// one, `or` any one (default: any --match and any --regex)
+        #[arg(long, value_name = "LOGIC", value_parser = ["and", "or"])]
+        filter_logic: Option<String>,
+
+        /// Match --match and --regex case-insensitively
+        #[arg(long)]
+        ignore_case: bool,
+
+        /// Select the domains that fail the --match/--regex test instead
+        #[arg(long)]
+        invert: bool,
 
         /// Sort domains before exporting
         #[arg(long)]
         sort: bool,
+
+        /// Deterministic text output for version control: lowercased, no
+        /// trailing dots, byte-order sorted, `\n` line endings
+        #[arg(long, conflicts_with_all = ["shuffle", "partition_size"])]
+        canonical: bool,
+
+        /// In JSON exports, write `"domains": null` instead of `[]` when nothing matched
+        #[arg(long)]
+        emit_null_for_empty: bool,
+
+        /// Emit one representative host per apex (eTLD+1)
+        #[arg(long)]
+        one_per_apex: bool,
+
+        /// Which host represents an apex with --one-per-apex
+        #[arg(long, default_value = "shortest", value_parser = ["shortest", "first", "apex"], requires = "one_per_apex")]
+        representative: String,
+
+        /// Drop hosts covered by a `*.apex` wildcard that is also exported
+        #[arg(long, conflicts_with = "one_per_apex")]
+        minimize: bool,
+
+        /// Export in a seeded random order, so each --partition-size part
+        /// is a representative mix instead of a lexicographic block
+        #[arg(long, conflicts_with_all = ["sort", "one_per_apex"])]
+        shuffle: bool,
+
+        /// Seed for --shuffle; the same seed and data give the same order
+        #[arg(long, requires = "shuffle")]
+        seed: Option<u64>,
+
+        /// Text to put before each exported domain (text format)
+        #[arg(long, allow_hyphen_values = true)]
+        prefix: Option<String>,
+
+        /// Text to put after each exported domain (text format)
+        #[arg(long, allow_hyphen_values = true)]
+        suffix: Option<String>,
+
+        /// Only export domains not listed in this earlier export (one domain
+        /// per line, optionally gzip-compressed)
+        #[arg(long, value_name = "FILE")]
+        diff_against: Option<PathBuf>,
+
+        /// Leave out every domain listed in this file (one per line,
+        /// optionally gzip-compressed); nothing is deleted
+        #[arg(long, value_name = "FILE", conflicts_with = "diff_against")]
+        exclude_file: Option<PathBuf>,
     },
 
     /// Remove domains from database
     Remove {
         /// File containing domains to remove
-        #[arg(short, long)]
+        #[arg(short, long, conflicts_with_all = ["domain", "match", "regex", "cidr"])]
         file: Option<PathBuf>,
 
-        /// Single domain to remove
-        #[arg(short, long)]
+        /// Read domains to remove from stdin (the default when no other
+        /// input is given)
+        #[arg(long, conflicts_with_all = ["file", "domain", "match", "regex", "cidr"])]
+        stdin: bool,
+
+        /// Input layout: one domain per line, a JSON array, or JSON lines
+        #[arg(long, value_name = "FORMAT", default_value = "text", value_parser = ["text", "json", "jsonl"])]
+        input_format: String,
+
+        /// Object key holding the domain in JSON input (default: `domain`,
+        /// then `host`)
+        #[arg(long, value_name = "KEY", conflicts_with_all = ["domain", "match", "regex", "cidr"])]
+        json_key: Option<String>,
+
+        /// Single domain to remove (a primary-key lookup; nothing is
+        /// dropped or rebuilt)
+        #[arg(short, long, conflicts_with_all = ["match", "regex", "cidr"])]
         domain: Option<String>,
 
-        /// Remove domains containing this substring
+        /// With --domain, don't report a domain that was not found (it
+        /// exits 0 either way)
+        #[arg(long, requires = "domain", conflicts_with_all = ["file", "stdin", "match", "regex"])]
+        quiet: bool,
+
+        /// Remove domains containing this substring (repeatable)
+        #[arg(long)]
+        r#match: Vec<String>,
+
+        /// Remove domains matching this regex (repeatable)
+        #[arg(long)]
+        regex: Vec<String>,
+
+        /// How repeated --match/--regex values combine: `and` needs every
+        /// one, `or` any one (default: any --match and any --regex)
+        #[arg(long, value_name = "LOGIC", value_parser = ["and", "or"])]
+        filter_logic: Option<String>,
+
+        /// Match --match and --regex case-insensitively
+        #[arg(long)]
+        ignore_case: bool,
+
+        /// Remove the domains that fail the --match/--regex test instead
+        #[arg(long)]
+        invert: bool,
+
+        /// Remove domains first seen before this date or time
+        /// (`2024-01-01`, `2024-01-01T12:00:00Z`)
+        #[arg(long, value_name = "TIME", conflicts_with_all = ["file", "stdin", "domain", "cidr"])]
+        added_before: Option<String>,
+
+        /// Remove domains last seen before this date or time
+        #[arg(long, value_name = "TIME", conflicts_with_all = ["file", "stdin", "domain", "cidr"])]
+        last_seen_before: Option<String>,
+
+        /// Skip the prompt that shows how many domains --match/--regex,
+        /// --added-before or --last-seen-before would remove
+        #[arg(long, conflicts_with_all = ["file", "stdin", "domain", "cidr"])]
+        confirm: bool,
+
+        /// Abort when --match/--regex, a time filter or --cidr would remove
+        /// more than N domains
+        #[arg(long, value_name = "N", conflicts_with_all = ["file", "stdin", "domain"])]
+        max_delete: Option<u64>,
+
+        /// Remove even when more than --max-delete domains match
+        #[arg(long, requires = "max_delete")]
+        force: bool,
+
+        /// Remove stored IP address entries inside this network
+        /// (`10.0.0.0/8`, `2001:db8::/32`)
+        #[arg(long, conflicts_with_all = ["match", "regex"])]
+        cidr: Option<String>,
+
+        /// With --cidr, --match, --regex or a time filter, list the entries
+        /// that would be removed on stdout and remove nothing
+        #[arg(long, conflicts_with_all = ["file", "stdin", "domain", "confirm"])]
+        dry_run: bool,
+
+        /// Skip `#`/`//` comment lines and strip inline `# note` annotations
+        #[arg(long)]
+        allow_comments: bool,
+
+        /// Commit after every N delete batches instead of once at the end
+        /// (forces the batch DELETE path; disables all-or-nothing semantics)
+        #[arg(long, value_name = "N", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+        batch_commit_every: Option<usize>,
+
+        /// Domains per DELETE batch [default: 10000, or
+        /// `tuning.batch_size`]
+        #[arg(long, value_name = "N")]
+        batch_size: Option<usize>,
+    },
+
+    /// Print the unique registrable (apex) domains of the stored domains
+    Apex {
+        /// Public suffix list in publicsuffix.org format (default: the
+        /// bundled copy of the official list)
+        #[arg(long, value_name = "FILE")]
+        psl: Option<PathBuf>,
+
+        /// Follow each apex with a tab and its number of stored domains
+        #[arg(long)]
+        counts: bool,
+    },
+
+    /// Each apex with its number of stored subdomains, most first
+    Tree {
+        /// Public suffix list in publicsuffix.org format (default: the
+        /// bundled copy of the official list)
+        #[arg(long, value_name = "FILE")]
+        psl: Option<PathBuf>,
+
+        /// Only apexes with at least N subdomains
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        min: u64,
+
+        /// List each apex's subdomains indented beneath it
         #[arg(long)]
-        r#match: Option<String>,
+        subdomains: bool,
+    },
+
+    /// Compare a list of domains against the stored ones
+    Diff {
+        /// File containing domains to compare (stdin when omitted)
+        #[arg(short, long)]
+        file: Option<PathBuf>,
 
-        /// Remove domains matching this regex
+        /// Skip `#`/`//` comment lines and strip inline `# note` annotations
         #[arg(long)]
-        regex: Option<String>,
+        allow_comments: bool,
+
+        /// Print only the input domains that are not stored yet, without a
+        /// `+ ` prefix
+        #[arg(long, conflicts_with = "common")]
+        only_new: bool,
+
+        /// Also print domains present in both, prefixed with two spaces
+        #[arg(long)]
+        common: bool,
+
+        /// Exit with status 1 when there are differences
+        #[arg(long)]
+        exit_code: bool,
+    },
+
+    /// Rewrite domains through opt-in normalizations, merging collisions
+    #[command(visible_alias = "dedup")]
+    Dedupe {
+        /// Reduce every entry to its lowercased host, like `add --normalize`
+        /// (scheme, port, path and a trailing dot removed)
+        #[arg(long)]
+        host: bool,
+
+        /// Lowercase every domain
+        #[arg(long)]
+        lowercase: bool,
+
+        /// Strip a trailing root dot (`example.com.`)
+        #[arg(long)]
+        trailing_dot: bool,
+
+        /// Convert Unicode labels to their `xn--` punycode form
+        #[arg(long)]
+        idna: bool,
+
+        /// Report what would be rewritten and removed without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Advanced: count rows matching a validated read-only WHERE fragment
+    Query {
+        /// Boolean expression over the `domain` column, e.g. "domain LIKE '%.gov'"
+        #[arg(long, value_name = "EXPR")]
+        count_where: String,
+    },
+
+    /// Rewrite every domain to its canonical form (URL host, no trailing
+    /// dot, IDNA, lowercase), merging the duplicates this creates
+    Normalize {
+        /// List the planned changes and totals without modifying anything
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Delete rows that are not valid domains even after normalization
+        #[arg(long)]
+        drop_invalid: bool,
+    },
+
+    /// Switch the domains table between logged and unlogged (no WAL)
+    TableMode {
+        /// `unlogged` is faster but the table is emptied after a crash
+        #[arg(value_parser = ["logged", "unlogged"])]
+        mode: String,
+    },
+
+    /// List projects with their domain counts
+    Projects,
+
+    /// Describe the connected database and domains table
+    Info {
+        /// Output format (text or json)
+        #[arg(long, default_value = "text", value_parser = ["text", "json"])]
+        format: String,
+    },
+
+    /// Print version, git commit, schema version and features (no DB needed)
+    Version {
+        /// Output format (text or json)
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+
+    /// Classify hosts as in or out of scope against apex/`*.` rules (no DB needed)
+    VerifyScope {
+        /// Scope rules, one per line: `example.com` (exact) or `*.example.com`
+        #[arg(long, value_name = "FILE")]
+        rules: PathBuf,
+
+        /// Candidate hosts, one per line (default: stdin)
+        #[arg(long, value_name = "FILE")]
+        hosts: Option<PathBuf>,
+
+        /// Print only in-scope hosts, without verdict columns
+        #[arg(long)]
+        in_scope_only: bool,
+    },
+
+    /// Check config, connectivity, privileges and schema, with hints for fixes
+    Doctor {
+        /// Rebuild a missing primary key and a missing or invalid
+        /// idx_domains_domain instead of only reporting them
+        #[arg(long)]
+        fix: bool,
+    },
+
+    /// Store or remove the database password in the OS keyring
+    #[cfg(feature = "keyring")]
+    Keyring {
+        /// `set` prompts for the password (or reads it from stdin)
+        #[arg(value_parser = ["set", "delete"])]
+        action: String,
     },
 
     /// Delete all domains
@@ -114,49 +805,317 @@ enum Commands {
         /// Skip confirmation prompt
         #[arg(long)]
         confirm: bool,
+
+        /// Report how many domains would be deleted and delete nothing
+        #[arg(long, conflicts_with = "confirm")]
+        dry_run: bool,
     },
 }
 
+/// Report a `--max-rows` abort as a one-line message and a non-zero exit
+/// instead of the error chain `main` returns
+fn row_cap_exit<T>(result: Result<T>) -> Result<T> {
+    if let Some(exceeded) = result.as_ref().err().and_then(|e| e.downcast_ref::<db::RowCapExceeded>()) {
+        error!("{}", exceeded);
+        std::process::exit(1);
+    }
+    result
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    let config = config::Config::load(cli.config.as_deref())?;
-    
-    if !cli.silent {
-        if cli.verbose {
-            eprintln!("Connecting to PostgreSQL at {}:{}/{}", 
-                config.postgresql.host, config.postgresql.port, config.postgresql.database);
-        }
+    logging::init(cli.verbose, cli.silent, &cli.log_format);
+    if cli.human {
+        human::enable();
+    }
+    if cli.summary_only {
+        progress::set_summary_only();
     }
 
+    // Answerable without config or a database connection
+    if let Commands::Version { format } = cli.command {
+        return commands::version::run(format);
+    }
+    if let Commands::VerifyScope { rules, hosts, in_scope_only } = cli.command {
+        return commands::verify_scope::run(rules, hosts, in_scope_only, cli.silent);
+    }
+
+    // Manages the password Config::load would otherwise try to fetch
+    #[cfg(feature = "keyring")]
+    if let Commands::Keyring { action } = &cli.command {
+        let config = config::Config::load_unresolved(cli.config.as_deref())?;
+        return commands::keyring::run(&config.postgresql, action, cli.silent);
+    }
+
+    // Reports config and connection problems instead of failing on them
+    if let Commands::Doctor { fix } = cli.command {
+        let project = cli.project.as_deref();
+        return commands::doctor::run(cli.config.as_deref(), cli.pgbouncer, cli.no_prepare, project, fix).await;
+    }
+
+    let mut config = config::Config::load(cli.config.as_deref())?;
+    if cli.pgbouncer {
+        config.postgresql.pooler_mode = "transaction".to_string();
+    }
+    if cli.no_prepare {
+        config.postgresql.prepared_statements = false;
+    }
+    if let Some(project) = cli.project {
+        config.postgresql.project = project;
+    }
+
+    debug!(
+        "Connecting to PostgreSQL at {}:{}/{}",
+        config.postgresql.host, config.postgresql.port, config.postgresql.database
+    );
     let pool = db::create_pool(&config.postgresql).await?;
+    debug!("Connected to PostgreSQL");
 
-    if !cli.silent && cli.verbose {
-        eprintln!("Connected to PostgreSQL");
+    // Only the commands that write create a missing project
+    if !matches!(cli.command, Commands::Add { .. } | Commands::TableMode { .. }) {
+        db::require_project(&pool).await?;
     }
 
     // Initialize schema
-    db::init_schema(&pool).await?;
+    db::init_schema(&pool, config.unlogged, cli.repair, cli.silent).await?;
 
     match cli.command {
-        Commands::Add { file, no_validate } => {
-            commands::add::run(&pool, file, !no_validate, cli.silent).await?;
+        Commands::Add {
+            file, stdin: _, input_format, json_key, no_validate, allow_comments, preserve_input_case,
+            normalize, idn, batch_commit_every, resume,
+            dedup_strategy, validate_sample, abort_if_invalid_over, max_domains, allow_tlds, deny_tlds,
+            program, source, tags, print_new, print_duplicates, spool, compare_previous,
+            batch_size, target_flush_ms, min_copy_chunk, max_copy_chunk, strip_www, fold_www, webhook: webhook_url,
+            webhook_on_failure,
+        } => {
+            let input_format = input::Format::new(&input_format, json_key)?;
+            let tuning = &config.tuning;
+            let tuner = commands::add::ChunkTuner::new(
+                target_flush_ms.or(tuning.target_flush_ms).unwrap_or(commands::add::TARGET_FLUSH_MS),
+                min_copy_chunk.or(tuning.min_copy_chunk).unwrap_or(commands::add::MIN_COPY_CHUNK),
+                max_copy_chunk.or(tuning.max_copy_chunk).unwrap_or(commands::add::MAX_COPY_CHUNK),
+            )?;
+            let batch_size = batch_size.or(tuning.batch_size).unwrap_or(commands::add::BATCH_SIZE);
+            let hook = match webhook_url {
+                Some(url) => Some(webhook::Webhook::start(&pool, url, webhook_on_failure).await?),
+                None => None,
+            };
+            let opts = commands::add::AddOptions {
+                validate: !no_validate,
+                allow_comments,
+                preserve_case: preserve_input_case,
+                normalize,
+                idn,
+                batch_commit_every,
+                dedup_strategy,
+                validate_sample,
+                abort_if_invalid_over,
+                max_domains,
+                tld_filter: domain::TldFilter { allow: allow_tlds, deny: deny_tlds },
+                print_new,
+                print_duplicates,
+                stamp: commands::add::Stamp { program, source, tags },
+                compare_previous,
+                batch_size,
+                tuner,
+                strip_www_prefix: strip_www,
+                fold_www_into: fold_www,
+                silent: cli.silent,
+            };
+            let outcome = commands::add::run(&pool, file, input_format, resume, spool, opts)
+                .instrument(info_span!("add"))
+                .await;
+            let notified = match &hook {
+                Some(hook) => hook.notify(&pool, &outcome, &config.postgresql.project).await,
+                None => Ok(()),
+            };
+            outcome?;
+            notified?;
+        }
+        Commands::Print { wildcards_covering: Some(host), .. } => {
+            output::ignore_closed(commands::print::wildcards_covering(&pool, &host, cli.silent).await)?;
+        }
+        Commands::Print {
+            file, exclude_file, r#match, regex, filter_logic, ignore_case, invert,
+            label, label_mode, since, sort, one_per_apex, representative, first, last, limit, offset,
+            max_rows, force, wait, resolve, show_ip, keep_unresolved, jobs, retry_file, columns, format, prefix, suffix, ..
+        } => {
+            let projection = (!columns.is_empty() || format != "text").then(|| commands::print::Projection {
+                columns: if columns.is_empty() { vec!["domain".to_string()] } else { columns },
+                format,
+            });
+            let opts = commands::print::PrintOptions {
+                file,
+                exclude_file,
+                match_filter: r#match,
+                regex_filter: regex,
+                filter_logic,
+                ignore_case,
+                invert,
+                labels: label,
+                any_label: label_mode == "any",
+                since,
+                sort,
+                one_per_apex_rule: one_per_apex.then_some(representative),
+                first,
+                last,
+                limit,
+                offset,
+                row_cap: if force { None } else { max_rows.or(config.max_rows) },
+                wait,
+                resolve: resolve.then_some(resolve::ResolveOptions { show_ip, keep_unresolved, jobs, retry_file }),
+                projection,
+                wrap: (prefix, suffix),
+                silent: cli.silent,
+            };
+            // `print | head` closing the pipe early is not an error
+            row_cap_exit(output::ignore_closed(commands::print::run(&pool, opts).await))?;
+        }
+        Commands::Count {
+            r#match, regex, filter_logic, ignore_case, invert, label, label_mode, since, matched_percent,
+            label_histogram, top, min_count, format, max_rows, force, wait,
+        } => {
+            let row_cap = if force { None } else { max_rows.or(config.max_rows) };
+            let histogram = label_histogram.then_some((top, min_count));
+            let flags = commands::count::CountFilter {
+                match_filter: r#match,
+                regex_filter: regex,
+                filter_logic,
+                ignore_case,
+                invert,
+                labels: label,
+                any_label: label_mode == "any",
+                since,
+            };
+            let counted = row_cap_exit(
+                commands::count::run(&pool, &flags, matched_percent, histogram, row_cap, wait, cli.silent).await,
+            )?;
+            for line in output::count_report(&counted, &flags, &format)? {
+                println!("{}", line);
+            }
+        }
+        Commands::Stats { format, json, top, min_count, history, compare, fail_if_drop } => {
+            let format = if json { OutputFormat::Json } else { format };
+            let report = commands::stats::run(&pool, top, min_count, history, compare, fail_if_drop, cli.silent).await?;
+            println!("{}", output::stats_report(&report.stats, format)?);
+            if let Some(comparison) = &report.comparison {
+                // Regressions are errors, so they are reported even with -s
+                for (over, line) in output::comparison_report(comparison) {
+                    if over {
+                        error!("{}", line);
+                    } else {
+                        info!("{}", line);
+                    }
+                }
+                if comparison.regressed() > 0 {
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Export {
+            file, partition_size, out_dir, format, r#match, regex, filter_logic, ignore_case, invert, sort, canonical,
+            emit_null_for_empty, one_per_apex, representative, minimize, shuffle, seed, prefix, suffix, diff_against,
+            exclude_file,
+        } => {
+            let partition = partition_size.zip(out_dir);
+            let opts = commands::export::FileExportOptions {
+                format,
+                match_filter: r#match,
+                regex_filter: regex,
+                filter_logic,
+                ignore_case,
+                invert,
+                sort,
+                canonical,
+                one_per_apex_rule: one_per_apex.then_some(representative),
+                emit_null_for_empty,
+                minimize,
+                shuffle,
+                seed,
+                prefix,
+                suffix,
+                diff_against,
+                exclude_file,
+                silent: cli.silent,
+            };
+            commands::export::run(&pool, file, partition, opts).instrument(info_span!("export")).await?;
+        }
+        Commands::Remove {
+            file, stdin: _, input_format, json_key, domain, quiet, r#match, regex, filter_logic, ignore_case, invert,
+            added_before, last_seen_before, confirm, max_delete, force, cidr, dry_run, allow_comments,
+            batch_commit_every, batch_size,
+        } => {
+            let input_format = input::Format::new(&input_format, json_key)?;
+            let batch_size = batch_size.or(config.tuning.batch_size).unwrap_or(commands::remove::BATCH_SIZE);
+            let opts = commands::remove::RemoveOptions {
+                domain,
+                quiet,
+                match_filter: r#match,
+                regex_filter: regex,
+                filter_logic,
+                ignore_case,
+                invert,
+                added_before,
+                last_seen_before,
+                confirm,
+                max_delete,
+                force,
+                cidr,
+                dry_run,
+                allow_comments,
+                batch_commit_every,
+                batch_size,
+                silent: cli.silent,
+            };
+            commands::remove::run(&pool, file, input_format, opts).instrument(info_span!("remove")).await?;
+        }
+        Commands::Apex { psl, counts } => {
+            output::ignore_closed(commands::apex::run(&pool, psl, counts, cli.silent).await)?;
+        }
+        Commands::Tree { psl, min, subdomains } => {
+            output::ignore_closed(commands::tree::run(&pool, psl, min, subdomains, cli.silent).await)?;
+        }
+        Commands::Diff { file, allow_comments, only_new, common, exit_code } => {
+            let differs = commands::diff::run(&pool, file, allow_comments, only_new, common, cli.silent).await?;
+            if exit_code && differs {
+                std::process::exit(1);
+            }
+        }
+        Commands::Dedupe { host, lowercase, trailing_dot, idna, dry_run } => {
+            commands::dedupe::run(&pool, host, lowercase, trailing_dot, idna, dry_run, cli.silent).await?;
+        }
+        Commands::Query { count_where } => {
+            let count = commands::query::run(&pool, &count_where).await?;
+            if !cli.silent {
+                info!("Rows where {}:", count_where);
+            }
+            println!("{}", count);
+        }
+        Commands::Normalize { dry_run, drop_invalid } => {
+            commands::normalize::run(&pool, dry_run, drop_invalid, cli.silent).await?;
         }
-        Commands::Print { r#match, regex, sort } => {
-            commands::print::run(&pool, r#match, regex, sort, cli.silent).await?;
+        Commands::TableMode { mode } => {
+            commands::table_mode::run(&pool, mode, cli.silent).await?;
         }
-        Commands::Count { r#match, regex } => {
-            commands::count::run(&pool, r#match, regex, cli.silent).await?;
+        Commands::Projects => {
+            // The active project is marked with `*`
+            for (project, count) in commands::projects::run(&pool).await? {
+                let marker = if project == config.postgresql.project { "*" } else { " " };
+                println!("{} {}\t{}", marker, project, count);
+            }
         }
-        Commands::Export { file, format, r#match, regex, sort } => {
-            commands::export::run(&pool, file, format, r#match, regex, sort, cli.silent).await?;
+        Commands::Info { format } => {
+            commands::info::run(&pool, &config.postgresql, format, cli.silent).await?;
         }
-        Commands::Remove { file, domain, r#match, regex } => {
-            commands::remove::run(&pool, file, domain, r#match, regex, cli.silent).await?;
+        Commands::DeleteAll { confirm, dry_run } => {
+            commands::delete_all::run(&pool, confirm, dry_run, cli.silent).await?;
         }
-        Commands::DeleteAll { confirm } => {
-            commands::delete_all::run(&pool, confirm, cli.silent).await?;
+        #[cfg(feature = "keyring")]
+        Commands::Keyring { .. } => unreachable!("handled before connecting"),
+        Commands::Version { .. } | Commands::VerifyScope { .. } | Commands::Doctor { .. } => {
+            unreachable!("handled before connecting")
         }
     }
 
@@ -0,0 +1,434 @@
+//! Destinations for `print` and `export` output: stdout, one file, or
+//! numbered part files.
+//!
+//! Commands render lines and hand them to an [`OutputSink`]; buffering,
+//! `--prefix`/`--suffix` wrapping, file rotation and a closed stdout are
+//! handled here, the same way for every command. Commands that return a
+//! result instead (`count`, `stats`) are formatted by the `*_report`
+//! functions at the end.
+
+use anyhow::{bail, Result};
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, StdoutLock, Write};
+use std::path::{Path, PathBuf};
+
+use crate::commands::count::{CountFilter, Counted, LabelCount};
+use crate::commands::stats::{Comparison, Stats};
+use crate::human;
+use crate::wrap::LineWrap;
+
+pub(crate) const FILE_BUFFER: usize = 1024 * 1024;
+
+/// `--format` of the commands that print a report: human-readable text or
+/// one JSON document
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+pub trait OutputSink {
+    /// Write `line` and a newline
+    fn line(&mut self, line: &str) -> Result<()>;
+    /// Write `COPY ... TO STDOUT` data as-is; chunks may end mid-line
+    fn raw(&mut self, data: &[u8]) -> Result<()>;
+    /// Flush everything written. Call once, after the last write: a file is
+    /// only complete once this returns.
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// The reader of stdout went away (`print | head`). Not a failure: the
+/// command just stops, see [`ignore_closed`].
+#[derive(Debug)]
+pub struct Closed;
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("output closed by the reader")
+    }
+}
+
+impl std::error::Error for Closed {}
+
+/// Treat a [`Closed`] stdout as success
+pub fn ignore_closed(result: Result<()>) -> Result<()> {
+    match result {
+        Err(e) if e.is::<Closed>() => Ok(()),
+        other => other,
+    }
+}
+
+fn closed_on_broken_pipe(e: io::Error) -> anyhow::Error {
+    if e.kind() == io::ErrorKind::BrokenPipe {
+        Closed.into()
+    } else {
+        e.into()
+    }
+}
+
+/// Stdout, left line-buffered so a downstream tool sees each domain as it
+/// is selected
+pub struct StdoutSink {
+    out: LineWrap<StdoutLock<'static>>,
+}
+
+impl StdoutSink {
+    pub fn new(wrap: (Option<&str>, Option<&str>)) -> Self {
+        Self { out: LineWrap::new(io::stdout().lock(), wrap.0, wrap.1) }
+    }
+}
+
+impl OutputSink for StdoutSink {
+    fn line(&mut self, line: &str) -> Result<()> {
+        writeln!(self.out, "{}", line).map_err(closed_on_broken_pipe)
+    }
+
+    fn raw(&mut self, data: &[u8]) -> Result<()> {
+        self.out.write_all(data).map_err(closed_on_broken_pipe)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.out.flush().map_err(closed_on_broken_pipe)
+    }
+}
+
+/// One output file, created (or truncated) up front
+pub struct FileSink {
+    out: LineWrap<BufWriter<File>>,
+}
+
+impl FileSink {
+    pub fn create(path: &Path, wrap: (Option<&str>, Option<&str>)) -> Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self { out: LineWrap::new(BufWriter::with_capacity(FILE_BUFFER, file), wrap.0, wrap.1) })
+    }
+}
+
+impl OutputSink for FileSink {
+    fn line(&mut self, line: &str) -> Result<()> {
+        Ok(writeln!(self.out, "{}", line)?)
+    }
+
+    fn raw(&mut self, data: &[u8]) -> Result<()> {
+        Ok(self.out.write_all(data)?)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+}
+
+/// Renders a whole part from its lines
+pub type Document = fn(Vec<String>) -> Result<Vec<u8>>;
+
+/// How [`PartSink`] lays out each part file
+pub struct PartFormat {
+    pub ext: &'static str,
+    /// First line of every part (CSV column names)
+    pub header: Option<&'static str>,
+    /// Render a part as one document from its lines instead of writing the
+    /// lines themselves (JSON)
+    pub document: Option<Document>,
+}
+
+/// Writes `part-0001.<ext>`, `part-0002.<ext>`, ... holding at most `size`
+/// lines each
+pub struct PartSink<'a> {
+    dir: &'a Path,
+    size: usize,
+    format: PartFormat,
+    wrap: (Option<&'a str>, Option<&'a str>),
+    parts: usize,
+    in_part: usize,
+    text: Option<LineWrap<BufWriter<File>>>,
+    pending: Vec<String>,
+}
+
+impl<'a> PartSink<'a> {
+    pub fn new(dir: &'a Path, size: usize, format: PartFormat, wrap: (Option<&'a str>, Option<&'a str>)) -> Self {
+        Self { dir, size, format, wrap, parts: 0, in_part: 0, text: None, pending: Vec::new() }
+    }
+
+    /// Part files started so far
+    pub fn parts(&self) -> usize {
+        self.parts
+    }
+
+    fn part_path(&self) -> PathBuf {
+        self.dir.join(format!("part-{:04}.{}", self.parts, self.format.ext))
+    }
+
+    fn finish_part(&mut self) -> Result<()> {
+        if let Some(mut writer) = self.text.take() {
+            writer.flush()?;
+        }
+        if let (Some(document), true) = (self.format.document, self.in_part > 0) {
+            let data = document(std::mem::take(&mut self.pending))?;
+            File::create(self.part_path())?.write_all(&data)?;
+        }
+        self.in_part = 0;
+        Ok(())
+    }
+}
+
+impl OutputSink for PartSink<'_> {
+    fn line(&mut self, line: &str) -> Result<()> {
+        if self.in_part == 0 {
+            self.parts += 1;
+            if self.format.document.is_none() {
+                let mut file = BufWriter::with_capacity(FILE_BUFFER, File::create(self.part_path())?);
+                if let Some(header) = self.format.header {
+                    writeln!(file, "{}", header)?;
+                }
+                self.text = Some(LineWrap::new(file, self.wrap.0, self.wrap.1));
+            }
+        }
+        match &mut self.text {
+            Some(writer) => writeln!(writer, "{}", line)?,
+            None => self.pending.push(line.to_string()),
+        }
+        self.in_part += 1;
+        if self.in_part == self.size {
+            self.finish_part()?;
+        }
+        Ok(())
+    }
+
+    fn raw(&mut self, _data: &[u8]) -> Result<()> {
+        bail!("part files are split on whole lines; raw COPY output cannot be partitioned")
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finish_part()
+    }
+}
+
+#[derive(Serialize)]
+struct CountOutput<'a> {
+    count: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filter: Option<FilterOutput<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    percent: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct HistogramOutput<'a> {
+    labels: &'a [LabelCount],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filter: Option<FilterOutput<'a>>,
+}
+
+#[derive(Serialize)]
+struct FilterOutput<'a> {
+    #[serde(rename = "match", skip_serializing_if = "<[_]>::is_empty", serialize_with = "one_or_many")]
+    match_filter: &'a [String],
+    #[serde(rename = "regex", skip_serializing_if = "<[_]>::is_empty", serialize_with = "one_or_many")]
+    regex_filter: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filter_logic: Option<&'a str>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    ignore_case: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    invert: bool,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    labels: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label_mode: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    since: Option<&'a str>,
+}
+
+impl<'a> FilterOutput<'a> {
+    fn of(flags: &'a CountFilter) -> Option<Self> {
+        (!flags.is_empty()).then(|| Self {
+            match_filter: &flags.match_filter,
+            regex_filter: &flags.regex_filter,
+            filter_logic: flags.filter_logic.as_deref(),
+            ignore_case: flags.ignore_case,
+            invert: flags.invert,
+            labels: &flags.labels,
+            label_mode: (!flags.labels.is_empty()).then_some(if flags.any_label { "any" } else { "all" }),
+            since: flags.since.as_deref(),
+        })
+    }
+}
+
+/// A single `--match`/`--regex` as a plain string, as before they could
+/// be repeated; several as an array
+fn one_or_many<S: Serializer>(values: &&[String], serializer: S) -> Result<S::Ok, S::Error> {
+    match values {
+        [one] => serializer.serialize_str(one),
+        many => many.serialize(serializer),
+    }
+}
+
+/// A `count` result as `text`, `json` or `kv` lines for stdout
+pub fn count_report(counted: &Counted, flags: &CountFilter, format: &str) -> Result<Vec<String>> {
+    let (count, total) = match counted {
+        Counted::Labels(counts) => {
+            return Ok(match format {
+                "json" => {
+                    let output = HistogramOutput { labels: counts, filter: FilterOutput::of(flags) };
+                    vec![serde_json::to_string(&output)?]
+                }
+                "kv" => counts.iter().map(|c| format!("label={} count={}", c.label, c.count)).collect(),
+                _ => counts.iter().map(|c| format!("{}: {}", c.label, c.count)).collect(),
+            });
+        }
+        Counted::Domains { count, total } => (*count, *total),
+    };
+
+    let percent = total.map(|t| if t > 0 { (count as f64 / t as f64) * 100.0 } else { 0.0 });
+
+    let line = if format == "json" {
+        serde_json::to_string(&CountOutput { count, filter: FilterOutput::of(flags), total, percent })?
+    } else if format == "kv" {
+        let mut fields = vec![format!("count={}", count)];
+        for m in &flags.match_filter {
+            fields.push(format!("match={}", m));
+        }
+        for r in &flags.regex_filter {
+            fields.push(format!("regex={}", r));
+        }
+        if let Some(logic) = &flags.filter_logic {
+            fields.push(format!("filter_logic={}", logic));
+        }
+        if flags.ignore_case && !(flags.match_filter.is_empty() && flags.regex_filter.is_empty()) {
+            fields.push("ignore_case=true".to_string());
+        }
+        if flags.invert {
+            fields.push("invert=true".to_string());
+        }
+        if !flags.labels.is_empty() {
+            fields.push(format!("labels={}", flags.labels.join(",")));
+            fields.push(format!("label_mode={}", if flags.any_label { "any" } else { "all" }));
+        }
+        if let Some(s) = &flags.since {
+            fields.push(format!("since={}", s));
+        }
+        if let (Some(t), Some(pct)) = (total, percent) {
+            fields.push(format!("total={}", t));
+            fields.push(format!("percent={:.2}", pct));
+        }
+        fields.join(" ")
+    } else if let (Some(t), Some(pct)) = (total, percent) {
+        format!("{} / {} ({:.2}%)", count, t, pct)
+    } else {
+        count.to_string()
+    };
+    Ok(vec![line])
+}
+
+/// A `stats` snapshot for stdout: aligned text, or pretty-printed JSON
+pub fn stats_report(stats: &Stats, format: OutputFormat) -> Result<String> {
+    if format == OutputFormat::Json {
+        return Ok(serde_json::to_string_pretty(stats)?);
+    }
+    let mut lines = vec![
+        format!("Total domains:  {}", stats.total),
+        format!("Apex domains:   {}", stats.apex_count),
+        format!("Subdomains:     {}", stats.subdomain_count),
+        format!("Wildcards:      {}", stats.wildcard_count),
+        format!("Avg depth:      {:.2} labels", stats.avg_label_depth),
+        "Top TLDs:".to_string(),
+    ];
+    lines.extend(stats.top_tlds.iter().map(|t| format!("  {:<12} {}", t.tld, t.count)));
+    Ok(lines.join("\n"))
+}
+
+/// The `stats --compare` lines for stderr, each flagged when its metric is
+/// over the `--fail-if-drop` limit and so fails the run
+pub fn comparison_report(comparison: &Comparison) -> Vec<(bool, String)> {
+    let mut lines = vec![(false, format!("Compared with {:?} (snapshot {}):", comparison.path, comparison.timestamp))];
+    for change in comparison.changes.iter().filter(|c| c.after != c.before) {
+        let over = comparison.over_limit(change);
+        let line = format!(
+            "{}: {} -> {} ({:+.1}%)",
+            change.metric,
+            human::count(change.before),
+            human::count(change.after),
+            -change.drop_percent()
+        );
+        lines.push(if over {
+            (true, format!("{}, over the --fail-if-drop limit", line))
+        } else {
+            (false, format!("  {}", line))
+        });
+    }
+    let regressed = comparison.regressed();
+    match comparison.threshold {
+        Some(max) if regressed > 0 => lines.push((false, format!("{} metrics dropped more than {}%", regressed, max))),
+        Some(max) => lines.push((false, format!("No metric dropped more than {}%", max))),
+        None if comparison.changes.iter().all(|c| c.after == c.before) => lines.push((false, "No changes".to_string())),
+        None => {}
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::stats::Change;
+
+    fn flags() -> CountFilter {
+        CountFilter {
+            match_filter: vec!["api".to_string()],
+            regex_filter: Vec::new(),
+            filter_logic: None,
+            ignore_case: false,
+            invert: false,
+            labels: Vec::new(),
+            any_label: false,
+            since: None,
+        }
+    }
+
+    #[test]
+    fn test_count_report() {
+        let counted = Counted::Domains { count: 25, total: Some(200) };
+        assert_eq!(count_report(&counted, &flags(), "text").unwrap(), ["25 / 200 (12.50%)"]);
+        assert_eq!(count_report(&counted, &flags(), "kv").unwrap(), ["count=25 match=api total=200 percent=12.50"]);
+        assert_eq!(
+            count_report(&counted, &flags(), "json").unwrap(),
+            [r#"{"count":25,"filter":{"match":"api"},"total":200,"percent":12.5}"#]
+        );
+
+        let counted = Counted::Labels(vec![LabelCount { label: "api".to_string(), count: 3 }]);
+        assert_eq!(count_report(&counted, &flags(), "text").unwrap(), ["api: 3"]);
+        assert_eq!(count_report(&counted, &flags(), "kv").unwrap(), ["label=api count=3"]);
+    }
+
+    #[test]
+    fn test_comparison_report() {
+        let comparison = Comparison {
+            path: PathBuf::from("base.json"),
+            timestamp: "2026-10-01T00:00:00Z".to_string(),
+            changes: vec![
+                Change { metric: "total".to_string(), before: 1000, after: 950 },
+                Change { metric: "tld io".to_string(), before: 50, after: 50 },
+                Change { metric: "tld com".to_string(), before: 600, after: 480 },
+            ],
+            threshold: Some(10.0),
+        };
+        assert_eq!(comparison.regressed(), 1);
+        let lines = comparison_report(&comparison);
+        let text: Vec<&str> = lines.iter().map(|(_, line)| line.as_str()).collect();
+        assert_eq!(
+            text,
+            [
+                r#"Compared with "base.json" (snapshot 2026-10-01T00:00:00Z):"#,
+                "  total: 1000 -> 950 (-5.0%)",
+                "tld com: 600 -> 480 (-20.0%), over the --fail-if-drop limit",
+                "1 metrics dropped more than 10%",
+            ]
+        );
+        let over: Vec<bool> = lines.iter().map(|(over, _)| *over).collect();
+        assert_eq!(over, [false, false, true, false]);
+    }
+}
@@ -0,0 +1,149 @@
+//! Parquet output for `export --format parquet` (`--features parquet`).
+//!
+//! A required UTF-8 `domain` column and optional `first_seen`/`last_seen`
+//! UTC timestamps in microseconds, written with the arrow and parquet
+//! crates. Rows are buffered into a row group of [`ROW_GROUP_ROWS`] rows,
+//! which is encoded and handed to the output before the next one starts, so
+//! memory stays bounded however large the export is.
+
+use ::parquet::arrow::ArrowWriter;
+use ::parquet::file::properties::WriterProperties;
+use anyhow::Result;
+use arrow_array::builder::{StringBuilder, TimestampMicrosecondBuilder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use std::io::Write;
+use std::sync::Arc;
+
+/// Rows buffered before they are written out as a row group
+const ROW_GROUP_ROWS: usize = 100_000;
+
+/// Streams rows into a Parquet file. Rows go to [`Writer::push`] and the
+/// file is only complete once [`Writer::finish`] has written the footer.
+pub struct Writer {
+    schema: SchemaRef,
+    /// Encodes into memory; drained to the output after every row group
+    file: ArrowWriter<Vec<u8>>,
+    domains: StringBuilder,
+    first_seen: TimestampMicrosecondBuilder,
+    last_seen: TimestampMicrosecondBuilder,
+    rows: usize,
+}
+
+impl Writer {
+    pub fn new() -> Result<Self> {
+        let timestamp = DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()));
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("domain", DataType::Utf8, false),
+            Field::new("first_seen", timestamp.clone(), true),
+            Field::new("last_seen", timestamp, true),
+        ]));
+        let props = WriterProperties::builder().set_max_row_group_size(ROW_GROUP_ROWS).build();
+        let file = ArrowWriter::try_new(Vec::new(), schema.clone(), Some(props))?;
+        Ok(Self {
+            schema,
+            file,
+            domains: StringBuilder::new(),
+            first_seen: TimestampMicrosecondBuilder::new().with_timezone("UTC"),
+            last_seen: TimestampMicrosecondBuilder::new().with_timezone("UTC"),
+            rows: 0,
+        })
+    }
+
+    /// Add one row; sighting times are microseconds since the Unix epoch
+    pub fn push<W: Write>(
+        &mut self,
+        out: &mut W,
+        domain: &str,
+        first_seen: Option<i64>,
+        last_seen: Option<i64>,
+    ) -> Result<()> {
+        self.domains.append_value(domain);
+        self.first_seen.append_option(first_seen);
+        self.last_seen.append_option(last_seen);
+        self.rows += 1;
+        if self.rows >= ROW_GROUP_ROWS {
+            self.flush(out)?;
+        }
+        Ok(())
+    }
+
+    /// Write the last row group and the footer
+    pub fn finish<W: Write>(mut self, out: &mut W) -> Result<()> {
+        self.flush(out)?;
+        out.write_all(&self.file.into_inner()?)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Encode the buffered rows as one row group and pass the bytes on
+    fn flush<W: Write>(&mut self, out: &mut W) -> Result<()> {
+        if self.rows > 0 {
+            let columns: Vec<ArrayRef> = vec![
+                Arc::new(self.domains.finish()),
+                Arc::new(self.first_seen.finish()),
+                Arc::new(self.last_seen.finish()),
+            ];
+            self.file.write(&RecordBatch::try_new(self.schema.clone(), columns)?)?;
+            self.file.flush()?;
+            self.rows = 0;
+        }
+        let encoded = self.file.inner_mut();
+        out.write_all(encoded)?;
+        encoded.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::parquet::file::reader::{FileReader, SerializedFileReader};
+    use ::parquet::record::RowAccessor;
+    use bytes::Bytes;
+
+    fn write(rows: &[(&str, Option<i64>, Option<i64>)]) -> SerializedFileReader<Bytes> {
+        let mut out = Vec::new();
+        let mut writer = Writer::new().unwrap();
+        for (domain, first, last) in rows {
+            writer.push(&mut out, domain, *first, *last).unwrap();
+        }
+        writer.finish(&mut out).unwrap();
+        SerializedFileReader::new(Bytes::from(out)).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let reader = write(&[("a.example.com", Some(1_700_000_000_000_000), None), ("b.example.com", None, Some(-5))]);
+        let meta = reader.metadata();
+        assert_eq!(meta.file_metadata().num_rows(), 2);
+        let fields = meta.file_metadata().schema().get_fields();
+        let names: Vec<&str> = fields.iter().map(|f| f.name()).collect();
+        assert_eq!(names, ["domain", "first_seen", "last_seen"]);
+
+        let rows: Vec<_> = reader.get_row_iter(None).unwrap().map(Result::unwrap).collect();
+        assert_eq!(rows[0].get_string(0).unwrap(), "a.example.com");
+        assert_eq!(rows[0].get_timestamp_micros(1).unwrap(), 1_700_000_000_000_000);
+        assert!(rows[0].get_timestamp_micros(2).is_err());
+        assert_eq!(rows[1].get_string(0).unwrap(), "b.example.com");
+        assert!(rows[1].get_timestamp_micros(1).is_err());
+        assert_eq!(rows[1].get_timestamp_micros(2).unwrap(), -5);
+    }
+
+    #[test]
+    fn test_row_groups_and_empty_file() {
+        let reader = write(&[]);
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 0);
+        assert_eq!(reader.num_row_groups(), 0);
+
+        let rows: Vec<(String, Option<i64>, Option<i64>)> =
+            (0..ROW_GROUP_ROWS + 1).map(|i| (format!("{}.example.com", i), None, None)).collect();
+        let rows: Vec<_> = rows.iter().map(|(d, f, l)| (d.as_str(), *f, *l)).collect();
+        let reader = write(&rows);
+        assert_eq!(reader.num_row_groups(), 2);
+        assert_eq!(reader.metadata().row_group(0).num_rows(), ROW_GROUP_ROWS as i64);
+        assert_eq!(reader.metadata().row_group(1).num_rows(), 1);
+        let last = reader.get_row_iter(None).unwrap().last().unwrap().unwrap();
+        assert_eq!(last.get_string(0).unwrap(), &format!("{}.example.com", ROW_GROUP_ROWS));
+    }
+}
@@ -0,0 +1,139 @@
+//! Process-wide progress counters for long `add`/`remove` runs, dumped to
+//! stderr on `SIGUSR1` and drawn as a live status line on a terminal.
+
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::human;
+
+static LINES: AtomicU64 = AtomicU64::new(0);
+static WRITTEN: AtomicU64 = AtomicU64::new(0);
+static PHASE: Mutex<&'static str> = Mutex::new("starting");
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static SUMMARY_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// How often the live status line is redrawn
+const TICK: Duration = Duration::from_millis(200);
+const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+/// `--summary-only`: drop step messages, keep the final summaries
+pub fn set_summary_only() {
+    SUMMARY_ONLY.store(true, Ordering::Relaxed);
+}
+
+/// Whether to print a step message such as "Deduplicating..."
+pub fn steps(silent: bool) -> bool {
+    !silent && !SUMMARY_ONLY.load(Ordering::Relaxed)
+}
+
+pub fn set_phase(phase: &'static str) {
+    *PHASE.lock().unwrap_or_else(|e| e.into_inner()) = phase;
+}
+
+/// Input lines consumed so far
+pub fn add_lines(n: u64) {
+    LINES.fetch_add(n, Ordering::Relaxed);
+}
+
+/// Domains sent to the database so far
+pub fn add_written(n: u64) {
+    WRITTEN.fetch_add(n, Ordering::Relaxed);
+}
+
+fn dump() {
+    let elapsed = STARTED.get().map(|s| s.elapsed().as_secs_f64()).unwrap_or(0.0);
+    let lines = LINES.load(Ordering::Relaxed);
+    let rate = if elapsed > 0.0 { lines as f64 / elapsed } else { 0.0 };
+    let phase = *PHASE.lock().unwrap_or_else(|e| e.into_inner());
+    eprintln!(
+        "[progress] phase={} lines={} written={} rate={:.0}/s elapsed={:.1}s",
+        phase,
+        lines,
+        WRITTEN.load(Ordering::Relaxed),
+        rate,
+        elapsed
+    );
+}
+
+/// Start the clock and print progress whenever the process receives
+/// `SIGUSR1`. No-op on non-Unix platforms.
+pub fn install_signal_handler() {
+    STARTED.get_or_init(Instant::now);
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        if let Ok(mut usr1) = signal(SignalKind::user_defined1()) {
+            tokio::spawn(async move {
+                while usr1.recv().await.is_some() {
+                    dump();
+                }
+            });
+        }
+    }
+}
+
+/// A live `| reading: 1200000 lines, 0 written (412000/s) 2.9s` line on
+/// stderr, redrawn from a thread (the read loops never yield to tokio) and
+/// cleared when dropped. Nothing else should print while one is alive.
+pub struct Ticker {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+/// Start a [`Ticker`], unless step messages are off or stderr is not a
+/// terminal (a log file would fill up with redraws)
+pub fn ticker(silent: bool) -> Option<Ticker> {
+    if !steps(silent) || !std::io::stderr().is_terminal() {
+        return None;
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&stop);
+    let handle = thread::spawn(move || {
+        let start = Instant::now();
+        let (lines_at, written_at) = (LINES.load(Ordering::Relaxed), WRITTEN.load(Ordering::Relaxed));
+        let mut frame = 0;
+        while !flag.load(Ordering::Relaxed) {
+            thread::sleep(TICK);
+            let (lines, written) = (LINES.load(Ordering::Relaxed), WRITTEN.load(Ordering::Relaxed));
+            let phase = *PHASE.lock().unwrap_or_else(|e| e.into_inner());
+            let elapsed = start.elapsed().as_secs_f64();
+            // Throughput of whichever counter this stage advances
+            let done = if written > written_at { written - written_at } else { lines - lines_at };
+            eprint!(
+                "\r\x1b[K{} {}: {} lines, {} written ({}/s) {:.1}s",
+                SPINNER[frame % SPINNER.len()],
+                phase,
+                human::count(lines),
+                human::count(written),
+                human::count(rate(done, elapsed)),
+                elapsed
+            );
+            let _ = std::io::stderr().flush();
+            frame += 1;
+        }
+        eprint!("\r\x1b[K");
+    });
+    Some(Ticker { stop, handle: Some(handle) })
+}
+
+impl Drop for Ticker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn rate(done: u64, elapsed: f64) -> u64 {
+    if elapsed > 0.0 {
+        (done as f64 / elapsed) as u64
+    } else {
+        0
+    }
+}
@@ -0,0 +1,22 @@
+//! Interactive y/N confirmation before destructive commands
+
+use anyhow::{bail, Result};
+use std::io::{self, IsTerminal, Write};
+
+/// Ask `question` on stderr with a `(y/N)` suffix and read the answer from
+/// stdin; only `y` or `yes`, in any case, confirms. Without a terminal on
+/// stdin (cron, CI, a pipe) nobody can answer, so it fails rather than
+/// quietly treating end of input as "no".
+pub fn confirm(question: &str) -> Result<bool> {
+    if !io::stdin().is_terminal() {
+        bail!("{} Stdin is not a terminal, so it cannot be confirmed interactively; pass --confirm", question);
+    }
+    eprint!("{} (y/N): ", question);
+    io::stderr().flush()?;
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+
+    let answer = input.trim();
+    Ok(answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes"))
+}
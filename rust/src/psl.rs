@@ -0,0 +1,127 @@
+//! Registrable domains from a public suffix list (publicsuffix.org format)
+//!
+//! A copy of the official list is bundled; `apex --psl` loads a newer one
+//! from a file instead.
+
+use anyhow::{anyhow, Context, Result};
+use publicsuffix::{List, Psl};
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::domain::strip_trailing_dot;
+
+/// The bundled list, ICANN and private sections
+const BUILTIN: &str = include_str!("../data/public_suffix_list.dat");
+
+pub struct SuffixList {
+    list: List,
+}
+
+impl SuffixList {
+    pub fn builtin() -> Self {
+        Self::parse(BUILTIN).expect("bundled public suffix list is valid")
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("Cannot read public suffix list {:?}", path))?;
+        Self::parse(&text).with_context(|| format!("Invalid public suffix list {:?}", path))
+    }
+
+    /// Rules are read from the `===BEGIN ICANN DOMAINS===` and
+    /// `===BEGIN PRIVATE DOMAINS===` sections. Unicode rules are also
+    /// stored in their `xn--` form, which is how domains are stored.
+    pub fn parse(text: &str) -> Result<Self> {
+        let list = text.parse::<List>().map_err(|e| anyhow!("{}", e))?;
+        Ok(Self { list })
+    }
+
+    /// Registrable domain of a lowercased `host`: its public suffix plus one
+    /// label (`a.b.example.co.uk` -> `example.co.uk`). `None` when `host` is
+    /// itself a public suffix (`co.uk`, `s3.amazonaws.com`).
+    pub fn registrable<'a>(&self, host: &'a str) -> Option<&'a str> {
+        let len = self.list.domain(host.as_bytes())?.as_bytes().len();
+        Some(&host[host.len() - len..])
+    }
+
+    /// A stored domain as `apex` and `tree` group it: the host (lowercased,
+    /// without a leading `*.` or a trailing root dot) and where its
+    /// registrable domain starts in it. `None` for IP addresses and bare
+    /// public suffixes, which have no apex.
+    pub fn split_apex(&self, domain: &str) -> Option<(String, usize)> {
+        let host = strip_trailing_dot(domain.strip_prefix("*.").unwrap_or(domain)).to_ascii_lowercase();
+        if host.parse::<IpAddr>().is_ok() {
+            return None;
+        }
+        let start = host.len() - self.registrable(&host)?.len();
+        Some((host, start))
+    }
+
+    /// Public suffix of a lowercased `host` (`a.example.co.uk` -> `co.uk`,
+    /// `acme.github.io` -> `github.io`); a single label is its own suffix
+    pub fn public_suffix<'a>(&self, host: &'a str) -> &'a str {
+        let len = self.list.suffix(host.as_bytes()).map_or(0, |s| s.as_bytes().len());
+        &host[host.len() - len..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registrable() {
+        let list = SuffixList::builtin();
+        assert_eq!(list.registrable("a.b.example.co.uk"), Some("example.co.uk"));
+        assert_eq!(list.registrable("api.example.com"), Some("example.com"));
+        assert_eq!(list.registrable("example.com"), Some("example.com"));
+        assert_eq!(list.registrable("com"), None);
+        assert_eq!(list.registrable("co.uk"), None);
+        // Private suffixes: each bucket or app is its own registrable domain
+        assert_eq!(list.registrable("assets.s3.amazonaws.com"), Some("assets.s3.amazonaws.com"));
+        assert_eq!(list.registrable("s3.amazonaws.com"), None);
+        assert_eq!(list.registrable("x.acme.github.io"), Some("acme.github.io"));
+        // Wildcard and exception rules
+        assert_eq!(list.registrable("a.b.foo.ck"), Some("b.foo.ck"));
+        assert_eq!(list.registrable("foo.ck"), None);
+        assert_eq!(list.registrable("a.www.ck"), Some("www.ck"));
+        assert_eq!(list.registrable("ec2-1-2-3-4.compute-1.amazonaws.com"), None);
+    }
+
+    #[test]
+    fn test_split_apex() {
+        let list = SuffixList::builtin();
+        let apex = |d| list.split_apex(d).map(|(host, start)| (host[start..].to_string(), host));
+        assert_eq!(apex("*.API.example.co.uk."), Some(("example.co.uk".into(), "api.example.co.uk".into())));
+        assert_eq!(apex("acme.github.io"), Some(("acme.github.io".into(), "acme.github.io".into())));
+        assert_eq!(apex("10.0.0.1"), None);
+        assert_eq!(apex("::1"), None);
+        assert_eq!(apex("co.uk"), None);
+    }
+
+    #[test]
+    fn test_public_suffix() {
+        let list = SuffixList::builtin();
+        assert_eq!(list.public_suffix("a.example.co.uk"), "co.uk");
+        assert_eq!(list.public_suffix("api.example.com"), "com");
+        assert_eq!(list.public_suffix("x.acme.github.io"), "github.io");
+        assert_eq!(list.public_suffix("github.io"), "github.io");
+        assert_eq!(list.public_suffix("a.www.ck"), "ck");
+        assert_eq!(list.public_suffix("localhost"), "localhost");
+    }
+
+    #[test]
+    fn test_parse() {
+        let list = SuffixList::parse(
+            "// ===BEGIN ICANN DOMAINS===\n\nuk\nco.uk  trailing text\n*.kawasaki.jp\n!city.kawasaki.jp\n公司.cn\n",
+        )
+        .unwrap();
+        assert_eq!(list.registrable("www.example.co.uk"), Some("example.co.uk"));
+        assert_eq!(list.registrable("a.b.kawasaki.jp"), Some("a.b.kawasaki.jp"));
+        assert_eq!(list.registrable("www.city.kawasaki.jp"), Some("city.kawasaki.jp"));
+        assert_eq!(list.registrable("shop.xn--55qx5d.cn"), Some("shop.xn--55qx5d.cn"));
+
+        // Rules outside the marked sections are not read
+        assert!(SuffixList::parse("uk\nco.uk\n").is_err());
+    }
+}
@@ -0,0 +1,91 @@
+//! Concurrent DNS resolution through the system resolver, used by
+//! `print --resolve`.
+
+use futures_util::stream::{self, Stream, StreamExt};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Upper bound for a single lookup, so one dead nameserver can't stall output
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How `print --resolve` filters and annotates its output
+pub struct ResolveOptions {
+    /// Append a tab and the comma-separated IPs to each line
+    pub show_ip: bool,
+    /// Print unresolvable domains too (with an empty IP column)
+    pub keep_unresolved: bool,
+    /// Lookups in flight at once
+    pub jobs: usize,
+    /// Where domains whose lookup timed out or errored are written
+    pub retry_file: Option<PathBuf>,
+}
+
+/// Outcome of one lookup
+pub enum Lookup {
+    /// A/AAAA addresses in resolver order without repeats
+    Resolved(Vec<IpAddr>),
+    /// The resolver answered that the name has no addresses
+    Missing,
+    /// Timed out or failed for another reason; worth retrying later
+    Failed,
+}
+
+impl Lookup {
+    /// Addresses found, empty unless the lookup resolved
+    pub fn ips(&self) -> &[IpAddr] {
+        match self {
+            Lookup::Resolved(ips) => ips,
+            _ => &[],
+        }
+    }
+}
+
+/// Whether a resolver error message means the name definitively has no
+/// addresses (glibc and BSD wording of EAI_NONAME / EAI_NODATA)
+fn is_missing(message: &str) -> bool {
+    message.contains("not known") || message.contains("No address associated")
+}
+
+async fn lookup(domain: &str) -> Lookup {
+    let addrs = match tokio::time::timeout(LOOKUP_TIMEOUT, tokio::net::lookup_host((domain, 0))).await {
+        Ok(Ok(addrs)) => addrs,
+        Ok(Err(e)) if is_missing(&e.to_string()) => return Lookup::Missing,
+        _ => return Lookup::Failed,
+    };
+    let mut ips: Vec<IpAddr> = Vec::new();
+    for addr in addrs {
+        if !ips.contains(&addr.ip()) {
+            ips.push(addr.ip());
+        }
+    }
+    if ips.is_empty() {
+        Lookup::Missing
+    } else {
+        Lookup::Resolved(ips)
+    }
+}
+
+/// Resolve `domains` with up to `jobs` lookups in flight, yielding results
+/// in input order
+pub fn resolve_all(domains: Vec<String>, jobs: usize) -> impl Stream<Item = (String, Lookup)> {
+    stream::iter(domains)
+        .map(|domain| async move {
+            let result = lookup(&domain).await;
+            (domain, result)
+        })
+        .buffered(jobs.max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_missing() {
+        assert!(is_missing("failed to lookup address information: Name or service not known"));
+        assert!(is_missing("failed to lookup address information: nodename nor servname provided, or not known"));
+        assert!(is_missing("failed to lookup address information: No address associated with hostname"));
+        assert!(!is_missing("failed to lookup address information: Temporary failure in name resolution"));
+    }
+}
@@ -0,0 +1,229 @@
+//! TLS for the PostgreSQL connection, following libpq's `sslmode`.
+//!
+//! `disable` connects in plaintext. `allow` and `prefer` use TLS when the
+//! server offers it and fall back to plaintext when it does not; `require`,
+//! `verify-ca` and `verify-full` never fall back. Without `ssl_root_cert`
+//! the first three encrypt without checking the certificate; with it,
+//! `require` checks the chain as libpq does. `verify-ca` checks the chain
+//! against `ssl_root_cert` (the system roots when unset) and `verify-full`
+//! also checks that the certificate names the host.
+
+use anyhow::{bail, Context, Result};
+use deadpool_postgres::SslMode;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, ClientConfig, DigitallySignedStruct, Error, RootCertStore, SignatureScheme};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::config::PostgresConfig;
+
+/// How much of the server certificate is checked
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Check {
+    Nothing,
+    Chain,
+    ChainAndName,
+}
+
+/// The connector and mode for `config.sslmode`, or `None` for `disable`
+pub fn connector(config: &PostgresConfig) -> Result<Option<(SslMode, MakeRustlsConnect)>> {
+    let root_cert = config.ssl_root_cert.as_deref();
+    let (mode, check) = match config.sslmode.as_str() {
+        "disable" => return Ok(None),
+        "allow" | "prefer" => (SslMode::Prefer, Check::Nothing),
+        "require" if root_cert.is_some() => (SslMode::Require, Check::Chain),
+        "require" => (SslMode::Require, Check::Nothing),
+        "verify-ca" => (SslMode::Require, Check::Chain),
+        "verify-full" => (SslMode::Require, Check::ChainAndName),
+        other => bail!(
+            "Unknown sslmode '{}' (expected disable, allow, prefer, require, verify-ca or verify-full)",
+            other
+        ),
+    };
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = verifier(check, root_cert, provider.clone())?;
+    let tls = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .context("Cannot set up TLS")?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    Ok(Some((mode, MakeRustlsConnect::new(tls))))
+}
+
+fn verifier(check: Check, root_cert: Option<&Path>, provider: Arc<CryptoProvider>) -> Result<Arc<dyn ServerCertVerifier>> {
+    if check == Check::Nothing {
+        return Ok(Arc::new(AnyCert(provider)));
+    }
+    let webpki = WebPkiServerVerifier::builder_with_provider(Arc::new(roots(root_cert)?), provider)
+        .build()
+        .context("Cannot set up certificate verification")?;
+    Ok(match check {
+        Check::Chain => Arc::new(AnyName(webpki)),
+        _ => webpki,
+    })
+}
+
+/// The CA certificates in `path`, or the system's trusted roots
+fn roots(path: Option<&Path>) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    match path {
+        Some(path) => {
+            let certs = CertificateDer::pem_file_iter(path)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .with_context(|| format!("Cannot read ssl_root_cert {:?}", path))?;
+            roots.add_parsable_certificates(certs);
+            if roots.is_empty() {
+                bail!("No CA certificates found in ssl_root_cert {:?}", path);
+            }
+        }
+        None => {
+            roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+            if roots.is_empty() {
+                bail!("No system CA certificates found; set ssl_root_cert to the server's CA bundle");
+            }
+        }
+    }
+    Ok(roots)
+}
+
+/// Accepts any certificate but still checks the handshake signatures, so
+/// the connection is encrypted without authenticating the server
+#[derive(Debug)]
+struct AnyCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Checks the chain like `verify-full` but accepts a certificate issued
+/// for another name. The name is only checked once the chain is valid, so
+/// a name error means the chain passed.
+#[derive(Debug)]
+struct AnyName(Arc<WebPkiServerVerifier>);
+
+impl ServerCertVerifier for AnyName {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        match self.0.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now) {
+            Err(Error::InvalidCertificate(
+                CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+            )) => Ok(ServerCertVerified::assertion()),
+            verified => verified,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/tls").join(name)
+    }
+
+    fn verify(check: Check, cert: &str, host: &str) -> Result<ServerCertVerified, Error> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let verifier = verifier(check, Some(&fixture("ca.pem")), provider).unwrap();
+        let cert = CertificateDer::from_pem_file(fixture(cert)).unwrap();
+        let host = ServerName::try_from(host.to_string()).unwrap();
+        verifier.verify_server_cert(&cert, &[], &host, &[], UnixTime::now())
+    }
+
+    #[test]
+    fn test_verification() {
+        assert!(verify(Check::ChainAndName, "server.pem", "db.example.com").is_ok());
+        assert!(verify(Check::ChainAndName, "server.pem", "other.example.com").is_err());
+        assert!(verify(Check::ChainAndName, "rogue.pem", "db.example.com").is_err());
+
+        assert!(verify(Check::Chain, "server.pem", "other.example.com").is_ok());
+        assert!(verify(Check::Chain, "rogue.pem", "db.example.com").is_err());
+
+        assert!(verify(Check::Nothing, "rogue.pem", "other.example.com").is_ok());
+    }
+
+    #[test]
+    fn test_modes() {
+        let mut config = PostgresConfig::default();
+        assert!(connector(&config).unwrap().is_none());
+
+        config.sslmode = "prefer".into();
+        assert!(matches!(connector(&config).unwrap(), Some((SslMode::Prefer, _))));
+        config.sslmode = "verify-full".into();
+        config.ssl_root_cert = Some(fixture("ca.pem"));
+        assert!(matches!(connector(&config).unwrap(), Some((SslMode::Require, _))));
+
+        config.ssl_root_cert = Some(fixture("missing.pem"));
+        assert!(connector(&config).is_err());
+        config.ssl_root_cert = Some(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml"));
+        assert!(connector(&config).err().unwrap().to_string().contains("No CA certificates"));
+
+        config.sslmode = "required".into();
+        assert!(connector(&config).err().unwrap().to_string().contains("Unknown sslmode"));
+    }
+}
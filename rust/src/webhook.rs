@@ -0,0 +1,210 @@
+//! `add --webhook`: POST a JSON summary of the import when it finishes
+//! (`--features http`).
+//!
+//! A failed POST is returned as an error for the caller to report; the
+//! import itself is already committed by then.
+
+use anyhow::{bail, Context, Result};
+use chrono::{SecondsFormat, Utc};
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+use tokio_postgres::types::Type;
+
+use crate::commands::add::ImportSummary;
+use crate::db::{self, Pool};
+
+/// New domains included in a completed-import payload
+const SAMPLE_SIZE: i64 = 20;
+/// Upper bound on one POST, so a hung endpoint can't stall a nightly job
+#[cfg(feature = "http")]
+const POST_TIMEOUT_SECS: u64 = 15;
+
+/// A pending `--webhook` notification for one import
+pub struct Webhook {
+    url: String,
+    on_failure: bool,
+    /// Database clock (epoch seconds) when the import started; rows it adds
+    /// get a later `first_seen`
+    db_start: f64,
+    start: Instant,
+}
+
+impl Webhook {
+    /// Check the URL and note the start time, before the import runs
+    pub async fn start(pool: &Pool, url: String, on_failure: bool) -> Result<Self> {
+        if !cfg!(feature = "http") {
+            bail!("--webhook needs a build with `cargo build --features http`");
+        }
+        if !(url.starts_with("https://") || url.starts_with("http://")) {
+            bail!("--webhook URL must start with https:// or http://");
+        }
+        let client = pool.get().await?;
+        let rows = db::query(&client, "SELECT extract(epoch FROM clock_timestamp())::float8", &[]).await?;
+        Ok(Self { url, on_failure, db_start: rows[0].get(0), start: Instant::now() })
+    }
+
+    /// Report the outcome of the import; failures are only posted with
+    /// `--webhook-on-failure`
+    pub async fn notify(&self, pool: &Pool, outcome: &Result<ImportSummary>, project: &str) -> Result<()> {
+        let elapsed = self.start.elapsed();
+        let payload = match outcome {
+            Ok(summary) => {
+                let sample = self
+                    .new_sample(pool, summary)
+                    .await
+                    .context("Could not sample new domains for the webhook")?;
+                completed_payload(summary, &sample, elapsed, project)
+            }
+            Err(e) if self.on_failure => failed_payload(&format!("{:#}", e), elapsed, project),
+            Err(_) => return Ok(()),
+        };
+        post(&self.url, payload).await.context("Webhook POST failed")
+    }
+
+    /// Some of the domains this import added. A concurrent import's new
+    /// domains can show up here too.
+    async fn new_sample(&self, pool: &Pool, summary: &ImportSummary) -> Result<Vec<String>> {
+        if summary.new == 0 {
+            return Ok(Vec::new());
+        }
+        let client = pool.get().await?;
+        let rows = db::query(
+            &client,
+            "SELECT domain FROM domains WHERE first_seen >= to_timestamp($1) ORDER BY domain LIMIT $2",
+            &[(&self.db_start, Type::FLOAT8), (&SAMPLE_SIZE, Type::INT8)],
+        )
+        .await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+}
+
+fn finished_at() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn completed_payload(summary: &ImportSummary, sample: &[String], elapsed: Duration, project: &str) -> Value {
+    json!({
+        "event": "import.completed",
+        "project": project,
+        "import_id": summary.import_id,
+        "finished_at": finished_at(),
+        "duration_secs": round_secs(elapsed),
+        "processed": summary.processed,
+        "new": summary.new,
+        "duplicates": summary.duplicates,
+        "new_sample": sample,
+        "text": format!(
+            "bountycatch [{}]: +{} new of {} processed in {:.1}s",
+            project, summary.new, summary.processed, elapsed.as_secs_f64()
+        ),
+    })
+}
+
+fn failed_payload(error: &str, elapsed: Duration, project: &str) -> Value {
+    json!({
+        "event": "import.failed",
+        "project": project,
+        "finished_at": finished_at(),
+        "duration_secs": round_secs(elapsed),
+        "error": error,
+        "text": format!("bountycatch [{}]: import failed after {:.1}s: {}", project, elapsed.as_secs_f64(), error),
+    })
+}
+
+fn round_secs(elapsed: Duration) -> f64 {
+    (elapsed.as_secs_f64() * 10.0).round() / 10.0
+}
+
+#[cfg(feature = "http")]
+async fn post(url: &str, payload: Value) -> Result<()> {
+    let client = reqwest::Client::builder().timeout(Duration::from_secs(POST_TIMEOUT_SECS)).build()?;
+    client.post(url).json(&payload).send().await?.error_for_status()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "http"))]
+async fn post(_url: &str, _payload: Value) -> Result<()> {
+    bail!("--webhook needs a build with `cargo build --features http`")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payloads() {
+        let summary = ImportSummary {
+            processed: 1500,
+            new: 2,
+            duplicates: 1498,
+            invalid: 0,
+            elapsed: Duration::from_secs(3),
+            import_id: Some(7),
+        };
+        let sample = vec!["a.example.com".to_string(), "b.example.com".to_string()];
+        let payload = completed_payload(&summary, &sample, Duration::from_millis(3449), "acme");
+        assert_eq!(payload["event"], "import.completed");
+        assert_eq!(payload["import_id"], 7);
+        assert_eq!(payload["duration_secs"], 3.4);
+        assert_eq!(payload["new_sample"][1], "b.example.com");
+        assert_eq!(payload["text"], "bountycatch [acme]: +2 new of 1500 processed in 3.4s");
+
+        let summary = ImportSummary { import_id: None, ..summary };
+        assert!(completed_payload(&summary, &[], Duration::ZERO, "default")["import_id"].is_null());
+
+        let payload = failed_payload("connection refused", Duration::from_secs(1), "default");
+        assert_eq!(payload["event"], "import.failed");
+        assert_eq!(payload["error"], "connection refused");
+        assert!(payload.get("new").is_none());
+    }
+
+    /// Accept one request per status on `listener`, answer it with that
+    /// status and return the raw requests
+    #[cfg(feature = "http")]
+    async fn serve(listener: tokio::net::TcpListener, statuses: &[&str]) -> Vec<String> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut requests = Vec::new();
+        for status in statuses {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                let Some((head, body)) = text.split_once("\r\n\r\n") else {
+                    continue;
+                };
+                let length = head
+                    .lines()
+                    .find_map(|l| l.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().to_string()))
+                    .map_or(0, |v| v.parse().unwrap());
+                if n == 0 || body.len() >= length {
+                    break;
+                }
+            }
+            requests.push(String::from_utf8(request).unwrap());
+            let response = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status);
+            socket.write_all(response.as_bytes()).await.unwrap();
+        }
+        requests
+    }
+
+    #[cfg(feature = "http")]
+    #[tokio::test]
+    async fn test_post() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move { serve(listener, &["204 No Content", "500 Internal Server Error"]).await });
+
+        post(&url, json!({"event": "import.completed"})).await.unwrap();
+        let err = post(&url, json!({})).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("500"));
+
+        let requests = server.await.unwrap();
+        assert!(requests[0].starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(requests[0].to_ascii_lowercase().contains("content-type: application/json"));
+        assert!(requests[0].ends_with(r#"{"event":"import.completed"}"#));
+    }
+}
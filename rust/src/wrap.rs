@@ -0,0 +1,77 @@
+//! `--prefix` / `--suffix` wrapping for line-oriented output.
+//!
+//! Works as a `Write` adapter so it applies equally to formatted lines and
+//! to raw COPY chunks, whose boundaries can fall anywhere inside a line.
+
+use std::io::{self, Write};
+
+pub struct LineWrap<W> {
+    inner: W,
+    prefix: Vec<u8>,
+    suffix: Vec<u8>,
+    line_start: bool,
+}
+
+impl<W: Write> LineWrap<W> {
+    pub fn new(inner: W, prefix: Option<&str>, suffix: Option<&str>) -> Self {
+        Self {
+            inner,
+            prefix: prefix.unwrap_or_default().as_bytes().to_vec(),
+            suffix: suffix.unwrap_or_default().as_bytes().to_vec(),
+            line_start: true,
+        }
+    }
+}
+
+impl<W: Write> Write for LineWrap<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.prefix.is_empty() && self.suffix.is_empty() {
+            return self.inner.write(buf);
+        }
+        let mut rest = buf;
+        while !rest.is_empty() {
+            if self.line_start {
+                self.inner.write_all(&self.prefix)?;
+                self.line_start = false;
+            }
+            match rest.iter().position(|&b| b == b'\n') {
+                Some(end) => {
+                    self.inner.write_all(&rest[..end])?;
+                    self.inner.write_all(&self.suffix)?;
+                    self.inner.write_all(b"\n")?;
+                    self.line_start = true;
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    self.inner.write_all(rest)?;
+                    rest = &[];
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wraps_lines_across_chunk_boundaries() {
+        let mut out = LineWrap::new(Vec::new(), Some("https://"), Some("/"));
+        out.write_all(b"a.example.com\nb.exa").unwrap();
+        out.write_all(b"mple.com\n").unwrap();
+        assert_eq!(out.inner, b"https://a.example.com/\nhttps://b.example.com/\n");
+    }
+
+    #[test]
+    fn test_passthrough_without_affixes() {
+        let mut out = LineWrap::new(Vec::new(), None, None);
+        out.write_all(b"a.example.com\n").unwrap();
+        assert_eq!(out.inner, b"a.example.com\n");
+    }
+}
@@ -0,0 +1,210 @@
+//! End-to-end round trips against a real PostgreSQL server.
+//!
+//! They use the server at `BOUNTYCATCH_TEST_DATABASE_URL` (a `postgres://`
+//! URL), the same variable the unit tests that need a server read, or else
+//! start a PostgreSQL container through Docker. With neither they fail
+//! rather than pass silently. Everything happens in a throwaway project
+//! schema that is dropped afterwards, so any database will do.
+
+use anyhow::Result;
+use bountycatch::commands::export::{self, FileExportOptions};
+use bountycatch::commands::remove::{self, RemoveOptions};
+use bountycatch::commands::{dedupe, delete_all};
+use bountycatch::input::Format;
+use bountycatch::{AddOptions, Config, DedupStrategy, ExportFormat, ExportOptions, Filter, Pool};
+use futures::FutureExt;
+use std::fs;
+use std::io::Cursor;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use testcontainers_modules::postgres::Postgres;
+use testcontainers_modules::testcontainers::runners::SyncRunner;
+use testcontainers_modules::testcontainers::Container;
+
+/// `BOUNTYCATCH_TEST_DATABASE_URL`, or a container started for this run
+fn database_url() -> String {
+    static CONTAINER: OnceLock<(Container<Postgres>, String)> = OnceLock::new();
+    if let Ok(url) = std::env::var("BOUNTYCATCH_TEST_DATABASE_URL") {
+        return url;
+    }
+    // The blocking runner needs a thread outside the test's runtime
+    let (_, url) = CONTAINER.get_or_init(|| {
+        std::thread::spawn(|| {
+            let container = Postgres::default().start().unwrap_or_else(|e| {
+                panic!(
+                    "the round trips need PostgreSQL: set BOUNTYCATCH_TEST_DATABASE_URL to a postgres:// URL \
+                     or make Docker available for a throwaway container ({})",
+                    e
+                )
+            });
+            let host = container.get_host().unwrap();
+            let port = container.get_host_port_ipv4(5432).unwrap();
+            (container, format!("postgres://postgres:postgres@{}:{}/postgres", host, port))
+        })
+        .join()
+        .unwrap_or_else(|e| std::panic::resume_unwind(e))
+    });
+    url.clone()
+}
+
+async fn connect(project: &str) -> Result<Pool> {
+    std::env::set_var("DATABASE_URL", database_url());
+    let mut config = Config::load(None)?;
+    config.postgresql.project = project.to_string();
+    let pool = bountycatch::create_pool(&config.postgresql).await?;
+    bountycatch::init_schema(&pool, false, false, true).await?;
+    Ok(pool)
+}
+
+fn quiet() -> AddOptions {
+    AddOptions { silent: true, ..Default::default() }
+}
+
+async fn add(pool: &Pool, input: &str, opts: AddOptions) -> Result<(u64, u64, u64)> {
+    let summary = bountycatch::add_domains(pool, Cursor::new(input.to_string()), opts).await?;
+    Ok((summary.new, summary.duplicates, summary.invalid))
+}
+
+async fn count(pool: &Pool) -> Result<u64> {
+    bountycatch::count(pool, &Filter::default()).await
+}
+
+async fn export_text(pool: &Pool, filter: Filter) -> Result<String> {
+    let mut out = Vec::new();
+    let options = ExportOptions { format: ExportFormat::Text, filter, sort: true };
+    bountycatch::export(pool, &mut out, &options).await?;
+    Ok(String::from_utf8(out)?)
+}
+
+fn temp_file(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("bountycatch-{}-{}", std::process::id(), name))
+}
+
+#[tokio::test]
+async fn test_round_trip() -> Result<()> {
+    let project = format!("it_{}", std::process::id());
+    let pool = connect(&project).await?;
+    // Drop the schema even when an assertion fails
+    let outcome = AssertUnwindSafe(round_trip(&pool)).catch_unwind().await;
+    let client = pool.get().await?;
+    client.batch_execute(&format!("DROP SCHEMA IF EXISTS project_{} CASCADE", project)).await?;
+    outcome.unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+/// Two projects open side by side in one process each see only their own
+/// domains
+#[tokio::test]
+async fn test_projects_are_isolated() -> Result<()> {
+    let (first, second) = (format!("iso_a_{}", std::process::id()), format!("iso_b_{}", std::process::id()));
+    let (a, b) = (connect(&first).await?, connect(&second).await?);
+    let outcome = AssertUnwindSafe(async {
+        assert_eq!(add(&a, "a.example.com\nb.example.com\n", quiet()).await?, (2, 0, 0));
+        assert_eq!(add(&b, "c.example.com\n", quiet()).await?, (1, 0, 0));
+        assert_eq!(count(&a).await?, 2);
+        assert_eq!(count(&b).await?, 1);
+        assert_eq!(export_text(&b, Filter::default()).await?, "c.example.com\n");
+        Ok::<_, anyhow::Error>(())
+    })
+    .catch_unwind()
+    .await;
+    let client = a.get().await?;
+    client.batch_execute(&format!("DROP SCHEMA IF EXISTS project_{} CASCADE", first)).await?;
+    client.batch_execute(&format!("DROP SCHEMA IF EXISTS project_{} CASCADE", second)).await?;
+    outcome.unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+async fn round_trip(pool: &Pool) -> Result<()> {
+    // INSERT path: repeats within the input and invalid lines
+    let input = "a.example.com\nb.example.com\na.example.com\nnot a domain\napi.test.org\n";
+    assert_eq!(add(pool, input, quiet()).await?, (3, 1, 1));
+    assert_eq!(count(pool).await?, 3);
+
+    // Duplicates of stored rows
+    assert_eq!(add(pool, "b.example.com\nc.example.com\n", quiet()).await?, (1, 1, 0));
+    assert_eq!(count(pool).await?, 4);
+
+    // Bulk COPY with the index drop and rebuild
+    let bulk: String = (0..500).map(|i| format!("host{}.bulk.example.net\n", i)).collect();
+    let bulk = format!("{}a.example.com\nhost7.bulk.example.net\n", bulk);
+    let opts = AddOptions { dedup_strategy: DedupStrategy::SqlRebuild, ..quiet() };
+    assert_eq!(add(pool, &bulk, opts).await?, (500, 2, 0));
+    assert_eq!(count(pool).await?, 504);
+
+    // The rebuilt primary key still rejects repeats
+    assert_eq!(add(pool, "host1.bulk.example.net\n", quiet()).await?, (0, 1, 0));
+    let client = pool.get().await?;
+    let row = client.query_one("SELECT COUNT(*) FROM domains WHERE domain = 'host1.bulk.example.net'", &[]).await?;
+    assert_eq!(row.get::<_, i64>(0), 1);
+
+    // Filtered count and export
+    let filter = Filter { matches: vec!["example.com".to_string()], ..Default::default() };
+    assert_eq!(bountycatch::count(pool, &filter).await?, 3);
+    assert_eq!(export_text(pool, filter).await?, "a.example.com\nb.example.com\nc.example.com\n");
+    let filter = Filter { regexes: vec![r"\.org$".to_string()], ..Default::default() };
+    let mut jsonl = Vec::new();
+    let options = ExportOptions { format: ExportFormat::Jsonl, filter, sort: false };
+    assert_eq!(bountycatch::export(pool, &mut jsonl, &options).await?, 1);
+    assert_eq!(String::from_utf8(jsonl)?, "{\"domain\":\"api.test.org\"}\n");
+
+    // Unfiltered export to a file takes the COPY fast path
+    let path = temp_file("export.txt");
+    export::run(pool, Some(path.clone()), None, FileExportOptions { silent: true, ..Default::default() }).await?;
+    let exported = fs::read_to_string(&path)?;
+    fs::remove_file(&path)?;
+    let mut lines: Vec<&str> = exported.lines().collect();
+    lines.sort();
+    assert_eq!(lines.len(), 504);
+    assert_eq!(&lines[..3], ["a.example.com", "api.test.org", "b.example.com"]);
+
+    // Remove a list of domains, including one that is not stored
+    let path = temp_file("remove.txt");
+    fs::write(&path, "a.example.com\napi.test.org\nmissing.example.com\n")?;
+    remove::run(pool, Some(path.clone()), Format::Text, RemoveOptions { silent: true, ..Default::default() }).await?;
+    fs::remove_file(&path)?;
+    assert_eq!(count(pool).await?, 502);
+    let filter = Filter { matches: vec!["example.com".to_string()], ..Default::default() };
+    assert_eq!(export_text(pool, filter).await?, "b.example.com\nc.example.com\n");
+
+    // A single domain, stored or not, exits successfully
+    let single = |domain: &str| RemoveOptions { domain: Some(domain.to_string()), quiet: true, ..Default::default() };
+    remove::run(pool, None, Format::Text, single("c.example.com")).await?;
+    remove::run(pool, None, Format::Text, single("missing.example.com")).await?;
+    assert_eq!(count(pool).await?, 501);
+    assert_eq!(add(pool, "c.example.com\n", quiet()).await?, (1, 0, 0));
+
+    // A dry run removes nothing
+    let bulk_pattern = || RemoveOptions { match_filter: vec![".bulk.".to_string()], silent: true, ..Default::default() };
+    remove::run(pool, None, Format::Text, RemoveOptions { dry_run: true, ..bulk_pattern() }).await?;
+    assert_eq!(count(pool).await?, 502);
+
+    // More matches than --max-delete allows removes nothing, unless forced
+    let capped = || RemoveOptions { confirm: true, max_delete: Some(100), ..bulk_pattern() };
+    let err = remove::run(pool, None, Format::Text, capped()).await.unwrap_err();
+    assert!(err.to_string().contains("--max-delete"), "{}", err);
+    assert_eq!(count(pool).await?, 502);
+    remove::run(pool, None, Format::Text, RemoveOptions { force: true, ..capped() }).await?;
+    assert_eq!(count(pool).await?, 2);
+    let hosts: String = (0..500).map(|i| format!("host{}.bulk.example.net\n", i)).collect();
+    assert_eq!(add(pool, &hosts, quiet()).await?, (500, 0, 0));
+
+    // Remove by pattern, without the prompt
+    remove::run(pool, None, Format::Text, RemoveOptions { confirm: true, ..bulk_pattern() }).await?;
+    assert_eq!(count(pool).await?, 2);
+
+    // Dedupe rewrites a case variant without losing when it was first seen
+    let opts = AddOptions { preserve_case: true, ..quiet() };
+    assert_eq!(add(pool, "Old.Example.com\n", opts).await?, (1, 0, 0));
+    let client = pool.get().await?;
+    client.execute("UPDATE domains SET first_seen = '2020-01-01' WHERE domain = 'Old.Example.com'", &[]).await?;
+    dedupe::run(pool, false, true, false, false, false, true).await?;
+    let row = client.query_one("SELECT first_seen::date::text FROM domains WHERE domain = 'old.example.com'", &[]).await?;
+    assert_eq!(row.get::<_, String>(0), "2020-01-01");
+    assert_eq!(count(pool).await?, 3);
+
+    delete_all::run(pool, false, true, true).await?;
+    assert_eq!(count(pool).await?, 3);
+    delete_all::run(pool, true, false, true).await?;
+    assert_eq!(count(pool).await?, 0);
+    Ok(())
+}